@@ -0,0 +1,372 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use bandwidth::BandwidthFile;
+use bridge::{BridgeDescriptor, BridgeExtraInfo, BridgeNetworkStatus};
+use certificate::KeyCertificate;
+use consensus::ConsensusDocument;
+use descriptor::ServerDescriptor;
+use detached::DetachedSignatures;
+use document::{parse_complete_with, split_documents, strip_annotations, Document};
+use error::Error;
+use extrainfo::ExtraInfo;
+use microdescriptor::Microdescriptor;
+use onion::HsDescriptor;
+use options::ParseOptions;
+use rules::{
+    Violation, DETACHED_SIGNATURES, EXTRA_INFO, KEY_CERTIFICATE, MICRODESCRIPTOR, NETWORK_STATUS,
+    SERVER_DESCRIPTOR,
+};
+
+/// A line before a document that begins with "@", such as the
+/// "@type server-descriptor 1.0" lines CollecTor adds or the
+/// "@last-listed" lines tor adds to cached descriptors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Annotation {
+    /// "@type" SP name SP major "." minor
+    Type(DocumentType),
+    /// Any other annotation, with the "@" removed from the keyword
+    Other { keyword: String, arguments: String },
+}
+/// The type and format version of a CollecTor document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentType {
+    /// Such as "network-status-consensus-3"
+    pub name: String,
+    pub major: u32,
+    pub minor: u32,
+}
+/// A document of any type, as returned by `parse_any`
+#[derive(Debug)]
+pub enum AnyDocument {
+    /// "network-status-consensus-3", "network-status-microdesc-consensus-3"
+    /// or "network-status-vote-3"
+    Consensus(Box<ConsensusDocument>),
+    /// "server-descriptor"
+    ServerDescriptors(Vec<ServerDescriptor>),
+    /// "extra-info"
+    ExtraInfos(Vec<ExtraInfo>),
+    /// "microdescriptor"
+    Microdescriptors(Vec<Microdescriptor>),
+    /// "dir-key-certificate-3"
+    KeyCertificate(KeyCertificate),
+    /// "detached-signature-3"
+    DetachedSignatures(DetachedSignatures),
+    /// "bridge-network-status"
+    BridgeNetworkStatus(BridgeNetworkStatus),
+    /// "bridge-server-descriptor"
+    BridgeDescriptors(Vec<BridgeDescriptor>),
+    /// "bridge-extra-info"
+    BridgeExtraInfos(Vec<BridgeExtraInfo>),
+    /// "bandwidth-file"
+    BandwidthFile(BandwidthFile),
+    /// "hidden-service-descriptor-3", which CollecTor doesn't archive, is
+    /// only recognized by its first keyword
+    HsDescriptor(HsDescriptor),
+    /// A document of a type without a typed parser
+    Generic(Document),
+}
+
+impl Annotation {
+    /// Parses a single annotation line, with or without its newline
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let line = line.trim_end();
+        if !line.starts_with('@') {
+            return Err(Error::Syntax(0));
+        }
+        let mut parts = line[1..].splitn(2, [' ', '\t']);
+        let keyword = parts.next().unwrap_or("");
+        let arguments = parts.next().unwrap_or("").trim();
+        if keyword != "type" {
+            return Ok(Annotation::Other {
+                keyword: keyword.to_string(),
+                arguments: arguments.to_string(),
+            });
+        }
+        let malformed = || {
+            Error::invalid(
+                "@type",
+                format!("expected name and version, found \"{}\"", arguments),
+            )
+        };
+        let mut parts = arguments.split_whitespace();
+        let name = parts.next().ok_or_else(malformed)?;
+        let mut version = parts.next().ok_or_else(malformed)?.splitn(2, '.');
+        let major = version.next().and_then(|major| major.parse().ok());
+        let minor = version.next().and_then(|minor| minor.parse().ok());
+        match (major, minor, parts.next()) {
+            (Some(major), Some(minor), None) => Ok(Annotation::Type(DocumentType {
+                name: name.to_string(),
+                major,
+                minor,
+            })),
+            _ => Err(malformed()),
+        }
+    }
+    /// Parses the annotation lines at the start of the input, returning them
+    /// along with the document that follows
+    pub fn parse_all(input: &[u8]) -> Result<(Vec<Annotation>, &[u8]), Error> {
+        let (lines, rest) = strip_annotations(input);
+        let annotations = lines
+            .into_iter()
+            .map(Annotation::parse)
+            .collect::<Result<_, _>>()?;
+        Ok((annotations, rest))
+    }
+}
+
+/// Parses a document of any type, using its "@type" annotation to choose a
+/// typed parser. Without an annotation, the first keyword decides. Types
+/// without a typed parser are returned as a generic `Document`.
+pub fn parse_any(input: &[u8]) -> Result<AnyDocument, Error> {
+    parse_any_with(input, &ParseOptions::default())
+}
+/// Parses a document of any type with the given options
+pub fn parse_any_with(input: &[u8], options: &ParseOptions) -> Result<AnyDocument, Error> {
+    let input = &*options.line_endings(input);
+    let (annotations, document) = Annotation::parse_all(input)?;
+    let offset = input.len() - document.len();
+    let name = type_name(&annotations, document);
+    let parsed =
+        match name {
+            Some("network-status-consensus-3")
+            | Some("network-status-microdesc-consensus-3")
+            | Some("network-status-vote-3") => ConsensusDocument::parse_with(document, options)
+                .map(|consensus| AnyDocument::Consensus(Box::new(consensus))),
+            // Streams of several documents carry annotations between them,
+            // which parse_all skips
+            Some("server-descriptor") => {
+                ServerDescriptor::parse_all_with(input, options).map(AnyDocument::ServerDescriptors)
+            }
+            Some("extra-info") => {
+                ExtraInfo::parse_all_with(input, options).map(AnyDocument::ExtraInfos)
+            }
+            Some("microdescriptor") => {
+                Microdescriptor::parse_all_with(input, options).map(AnyDocument::Microdescriptors)
+            }
+            Some("dir-key-certificate-3") => {
+                KeyCertificate::parse_with(document, options).map(AnyDocument::KeyCertificate)
+            }
+            Some("detached-signature-3") => DetachedSignatures::parse_with(document, options)
+                .map(AnyDocument::DetachedSignatures),
+            Some("bridge-network-status") => BridgeNetworkStatus::parse_with(input, options)
+                .map(AnyDocument::BridgeNetworkStatus),
+            Some("bridge-server-descriptor") => {
+                BridgeDescriptor::parse_all_with(input, options).map(AnyDocument::BridgeDescriptors)
+            }
+            Some("bridge-extra-info") => {
+                BridgeExtraInfo::parse_all_with(input, options).map(AnyDocument::BridgeExtraInfos)
+            }
+            Some("bandwidth-file") => {
+                BandwidthFile::parse_with(document, options).map(AnyDocument::BandwidthFile)
+            }
+            Some("hidden-service-descriptor-3") => {
+                HsDescriptor::parse_with(document, options).map(AnyDocument::HsDescriptor)
+            }
+            _ => parse_complete_with(document, options).map(AnyDocument::Generic),
+        };
+    // Offsets into the document are reported as offsets into the input.
+    // Parsers that were given the whole input already count the
+    // annotations.
+    let whole_input = matches!(
+        name,
+        Some("server-descriptor")
+            | Some("extra-info")
+            | Some("microdescriptor")
+            | Some("bridge-network-status")
+            | Some("bridge-server-descriptor")
+            | Some("bridge-extra-info")
+    );
+    parsed.map_err(|error| {
+        if whole_input {
+            error
+        } else {
+            error.shift(offset)
+        }
+    })
+}
+/// Checks every document in the input against the item rules for its type,
+/// returning each violation with the line it is on. Lines are numbered from
+/// 1 at the start of the input, annotations included, and violations for
+/// missing items are reported at the first line of their document. Types
+/// without rules only have their syntax checked.
+pub fn validate_any(input: &[u8]) -> Result<Vec<(usize, Violation)>, Error> {
+    validate_any_with(input, &ParseOptions::default())
+}
+/// Checks every document in the input with the given options
+pub fn validate_any_with(
+    input: &[u8],
+    options: &ParseOptions,
+) -> Result<Vec<(usize, Violation)>, Error> {
+    let input = &*options.line_endings(input);
+    let (annotations, document) = Annotation::parse_all(input)?;
+    let (rules, first_keyword) = match type_name(&annotations, document) {
+        Some("network-status-consensus-3")
+        | Some("network-status-microdesc-consensus-3")
+        | Some("network-status-vote-3") => (Some(&NETWORK_STATUS), None),
+        Some("server-descriptor") => (Some(&SERVER_DESCRIPTOR), Some("router")),
+        Some("extra-info") => (Some(&EXTRA_INFO), Some("extra-info")),
+        Some("microdescriptor") => (Some(&MICRODESCRIPTOR), Some("onion-key")),
+        Some("dir-key-certificate-3") => (Some(&KEY_CERTIFICATE), None),
+        Some("detached-signature-3") => (Some(&DETACHED_SIGNATURES), None),
+        _ => (None, None),
+    };
+    let documents = match first_keyword {
+        Some(keyword) => split_documents(input, keyword),
+        None => vec![(input.len() - document.len(), document)],
+    };
+    let line_of = |bytes: &[u8]| bytes.iter().filter(|byte| **byte == b'\n').count();
+    let mut violations = Vec::new();
+    for (offset, bytes) in documents {
+        let parsed = parse_complete_with(bytes, options).map_err(|error| error.shift(offset))?;
+        let rules = match rules {
+            Some(rules) => rules,
+            None => continue,
+        };
+        // Item offsets point into the document after preprocessing, which
+        // keeps every line break where it was
+        let preprocessed = options.preprocess(bytes)?;
+        let first_line = line_of(&input[..offset]) + 1;
+        for violation in rules.validate(&parsed) {
+            let line = match violation.index {
+                Some(index) => {
+                    first_line + line_of(&preprocessed[..parsed.items()[index].offset()])
+                }
+                None => first_line,
+            };
+            violations.push((line, violation));
+        }
+    }
+    Ok(violations)
+}
+/// The name from the first "@type" annotation, or else the type guessed
+/// from the first keyword
+fn type_name<'a>(annotations: &'a [Annotation], document: &[u8]) -> Option<&'a str> {
+    annotations
+        .iter()
+        .filter_map(|annotation| match *annotation {
+            Annotation::Type(ref document_type) => Some(document_type.name.as_str()),
+            Annotation::Other { .. } => None,
+        })
+        .next()
+        .or_else(|| sniff_type(document))
+}
+/// Guesses the CollecTor type of an unannotated document from its first
+/// keyword. Sanitized bridge descriptors are told apart from relay ones by
+/// their "router-digest" line, and bandwidth files start with a bare unix
+/// time.
+fn sniff_type(document: &[u8]) -> Option<&'static str> {
+    let first_line = document.split(|byte| *byte == b'\n').next()?;
+    let first_line = ::std::str::from_utf8(first_line).ok()?;
+    let mut words = first_line.split_whitespace();
+    let first_word = words.next()?;
+    let sanitized = document
+        .split(|byte| *byte == b'\n')
+        .any(|line| line.starts_with(b"router-digest "));
+    Some(match first_word {
+        "network-status-version" => match words.nth(1) {
+            Some("microdesc") => "network-status-microdesc-consensus-3",
+            _ => "network-status-consensus-3",
+        },
+        "router" if sanitized => "bridge-server-descriptor",
+        "router" => "server-descriptor",
+        "extra-info" if sanitized => "bridge-extra-info",
+        "extra-info" => "extra-info",
+        "onion-key" => "microdescriptor",
+        "dir-key-certificate-version" => "dir-key-certificate-3",
+        "consensus-digest" => "detached-signature-3",
+        "hs-descriptor" => "hidden-service-descriptor-3",
+        "published" => "bridge-network-status",
+        timestamp
+            if words.next().is_none() && timestamp.bytes().all(|byte| byte.is_ascii_digit()) =>
+        {
+            "bandwidth-file"
+        }
+        _ => return None,
+    })
+}
+
+/// Test
+#[test]
+fn test_parse_any() {
+    assert_eq!(
+        Annotation::parse("@type network-status-consensus-3 1.0\n"),
+        Ok(Annotation::Type(DocumentType {
+            name: "network-status-consensus-3".to_string(),
+            major: 1,
+            minor: 0,
+        }))
+    );
+    assert!(Annotation::parse("@type server-descriptor").is_err());
+    match parse_any(include_bytes!("../test/server-descriptors")) {
+        Ok(AnyDocument::ServerDescriptors(descriptors)) => assert_eq!(descriptors.len(), 2),
+        other => panic!("expected server descriptors, found {:?}", other),
+    }
+    match parse_any(include_bytes!("../test/bridge-network-status")) {
+        Ok(AnyDocument::BridgeNetworkStatus(status)) => assert_eq!(status.routers.len(), 2),
+        other => panic!("expected a bridge network status, found {:?}", other),
+    }
+    let mut consensus = b"@type network-status-consensus-3 1.0\n".to_vec();
+    consensus.extend_from_slice(include_bytes!("../test/barebones.consensus"));
+    match parse_any(&consensus) {
+        Ok(AnyDocument::Consensus(consensus)) => assert_eq!(consensus.routers.len(), 3),
+        other => panic!("expected a consensus, found {:?}", other),
+    }
+    // Without annotations, the first lines decide
+    let unannotated = |input: &[u8]| Annotation::parse_all(input).unwrap().1.to_vec();
+    match parse_any(include_bytes!("../test/bandwidth-file")) {
+        Ok(AnyDocument::BandwidthFile(file)) => assert_eq!(file.relays.len(), 3),
+        other => panic!("expected a bandwidth file, found {:?}", other),
+    }
+    match parse_any(&unannotated(include_bytes!(
+        "../test/bridge-network-status"
+    ))) {
+        Ok(AnyDocument::BridgeNetworkStatus(status)) => assert_eq!(status.routers.len(), 2),
+        other => panic!("expected a bridge network status, found {:?}", other),
+    }
+    match parse_any(&unannotated(include_bytes!("../test/bridge-descriptors"))) {
+        Ok(AnyDocument::BridgeDescriptors(descriptors)) => assert!(!descriptors.is_empty()),
+        other => panic!("expected bridge descriptors, found {:?}", other),
+    }
+    match parse_any(&unannotated(include_bytes!("../test/bridge-extra-infos"))) {
+        Ok(AnyDocument::BridgeExtraInfos(extra_infos)) => assert!(!extra_infos.is_empty()),
+        other => panic!("expected bridge extra-infos, found {:?}", other),
+    }
+    match parse_any(b"@type torperf 1.1\nsome-item argument\n") {
+        Ok(AnyDocument::Generic(document)) => assert_eq!(document.items().len(), 1),
+        other => panic!("expected a generic document, found {:?}", other),
+    }
+    assert_eq!(validate_any(&consensus), Ok(Vec::new()));
+    let mut descriptors = include_bytes!("../test/server-descriptors").to_vec();
+    let text = String::from_utf8_lossy(&descriptors).into_owned();
+    let second = text
+        .find("\nidentity-ed25519\n-----BEGIN ED25519 CERT-----\nyw/g")
+        .unwrap()
+        + 1;
+    descriptors.splice(second..second, b"uptime 5\n".iter().cloned());
+    // The extra item pushes "identity-ed25519" away from "router", and the
+    // second "uptime" is the one that is too many
+    let line_of = |position: usize| text[..position].matches('\n').count() + 1;
+    let violations = validate_any(&descriptors).unwrap();
+    assert_eq!(violations.len(), 2);
+    assert_eq!(violations[0].0, line_of(second) + 1);
+    assert_eq!(violations[0].1.keyword, "identity-ed25519");
+    assert_eq!(
+        violations[1].0,
+        line_of(text.find("uptime 86123").unwrap()) + 1
+    );
+    assert_eq!(violations[1].1.keyword, "uptime");
+}
@@ -0,0 +1,200 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use annotation::{parse_any_with, AnyDocument};
+use error::Error;
+use flate2::read::GzDecoder;
+use options::ParseOptions;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tar;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// How a tarball is compressed, detected from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Gzip,
+    Zstd,
+}
+/// A tarball of documents, such as CollecTor's monthly
+/// consensuses-2018-02.tar.xz
+pub struct Archive {
+    archive: tar::Archive<Box<dyn Read>>,
+    pub compression: Compression,
+    /// How files are parsed, the defaults unless changed
+    pub options: ParseOptions,
+}
+/// One file of an archive and the result of parsing it
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// The path of the file within the archive
+    pub path: PathBuf,
+    pub document: Result<AnyDocument, Error>,
+}
+/// The outcome of parsing every file of an archive
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    /// How many files were parsed successfully
+    pub parsed: usize,
+    /// The files that failed to parse and why
+    pub failures: Vec<(PathBuf, Error)>,
+}
+/// Iterates over the files of an archive, parsing each one
+pub struct Documents<'a> {
+    entries: tar::Entries<'a, Box<dyn Read>>,
+    options: ParseOptions,
+}
+
+impl Compression {
+    /// Recognizes the magic bytes at the start of a compressed stream
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+impl Archive {
+    /// Opens a tarball on disk, decompressing it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+    /// Reads a tarball from any reader, decompressing it if needed
+    pub fn new<R: Read + 'static>(reader: R) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let compression = Compression::detect(reader.fill_buf()?);
+        let reader: Box<dyn Read> = match compression {
+            Compression::None => Box::new(reader),
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::with_buffer(reader)?),
+        };
+        Ok(Archive {
+            archive: tar::Archive::new(reader),
+            compression,
+            options: ParseOptions::default(),
+        })
+    }
+    /// The files of the archive in order, each parsed with `parse_any_with`
+    /// and the archive's options.
+    /// Directories and other special entries are skipped. Parse errors are
+    /// reported per entry; I/O errors end the iteration.
+    pub fn documents(&mut self) -> io::Result<Documents<'_>> {
+        Ok(Documents {
+            entries: self.archive.entries()?,
+            options: self.options,
+        })
+    }
+    /// Parses every file, passing each document to `handle` and carrying on
+    /// past files that fail to parse
+    pub fn parse_all<F>(&mut self, mut handle: F) -> io::Result<ArchiveReport>
+    where
+        F: FnMut(&Path, AnyDocument),
+    {
+        let mut report = ArchiveReport::default();
+        for entry in self.documents()? {
+            let entry = entry?;
+            match entry.document {
+                Ok(document) => {
+                    report.parsed += 1;
+                    handle(&entry.path, document);
+                }
+                Err(error) => report.failures.push((entry.path, error)),
+            }
+        }
+        Ok(report)
+    }
+}
+impl<'a> Iterator for Documents<'a> {
+    type Item = io::Result<ArchiveEntry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = match entry.path() {
+                Ok(path) => path.into_owned(),
+                Err(error) => return Some(Err(error)),
+            };
+            let mut contents = Vec::new();
+            if let Err(error) = entry.read_to_end(&mut contents) {
+                return Some(Err(error));
+            }
+            return Some(Ok(ArchiveEntry {
+                path,
+                document: parse_any_with(&contents, &self.options),
+            }));
+        }
+    }
+}
+
+/// Test
+#[test]
+fn test_read_archive() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let files: &[(&str, &[u8])] = &[
+        (
+            "consensuses-2018-02/08/2018-02-08-07-00-00-consensus",
+            include_bytes!("../test/barebones.consensus"),
+        ),
+        (
+            "consensuses-2018-02/08/broken",
+            b"network-status-version 3\n",
+        ),
+        (
+            "server-descriptors-2018-02/descriptors",
+            include_bytes!("../test/server-descriptors"),
+        ),
+    ];
+    for &(path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, contents).unwrap();
+    }
+    let tarball = builder.into_inner().unwrap();
+    let compressed = zstd::stream::encode_all(&tarball[..], 3).unwrap();
+    let mut archive = Archive::new(io::Cursor::new(compressed)).unwrap();
+    assert_eq!(archive.compression, Compression::Zstd);
+    let mut consensuses = 0;
+    let report = archive
+        .parse_all(|_, document| {
+            if let AnyDocument::Consensus(_) = document {
+                consensuses += 1;
+            }
+        })
+        .unwrap();
+    assert_eq!(consensuses, 1);
+    assert_eq!(report.parsed, 2);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(
+        report.failures[0].0,
+        PathBuf::from("consensuses-2018-02/08/broken")
+    );
+}
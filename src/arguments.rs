@@ -0,0 +1,251 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use document::{decode_base64, Item};
+use error::Error;
+use identity::RsaIdentity;
+use std::net::IpAddr;
+use std::str::{self, FromStr};
+use time::Timestamp;
+
+/// The arguments of an item split into tokens at each run of whitespace,
+/// with helpers that convert a token and report where it was if it is
+/// missing or malformed
+#[derive(Debug, Clone)]
+pub struct Arguments<'a> {
+    /// Keyword of the item, for errors
+    keyword: &'a str,
+    /// Each token along with its offset in the document
+    tokens: Vec<(usize, &'a [u8])>,
+    /// Offset of the end of the keyword line, for missing arguments
+    end: usize,
+}
+impl<'a> Arguments<'a> {
+    /// Splits the arguments of an item
+    pub(crate) fn new(item: &'a Item) -> Self {
+        let line = item.keyword_line();
+        let start = item.offset() + line.arguments_offset();
+        let bytes = line.arguments().unwrap_or_default();
+        let mut tokens = Vec::new();
+        let mut token_start = None;
+        for (index, byte) in bytes.iter().enumerate() {
+            let is_space = *byte == b' ' || *byte == b'\t';
+            match token_start {
+                Some(begin) if is_space => {
+                    tokens.push((start + begin, &bytes[begin..index]));
+                    token_start = None;
+                }
+                None if !is_space => token_start = Some(index),
+                _ => (),
+            }
+        }
+        if let Some(begin) = token_start {
+            tokens.push((start + begin, &bytes[begin..]));
+        }
+        Arguments {
+            keyword: item.keyword(),
+            tokens,
+            end: if bytes.is_empty() {
+                item.offset() + item.keyword().len()
+            } else {
+                start + bytes.len()
+            },
+        }
+    }
+    /// Number of tokens
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    /// Whether there were no arguments
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+    /// The raw bytes of the token at `index`
+    pub fn get(&self, index: usize) -> Option<&'a [u8]> {
+        self.tokens.get(index).map(|&(_, token)| token)
+    }
+    /// Byte offset of the token at `index` in the document
+    pub fn offset(&self, index: usize) -> Option<usize> {
+        self.tokens.get(index).map(|&(offset, _)| offset)
+    }
+    /// Every token as raw bytes, in order
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.tokens.iter().map(|&(_, token)| token)
+    }
+    /// An error about the argument at `index`
+    pub fn error<R: Into<String>>(&self, index: usize, reason: R) -> Error {
+        Error::InvalidArgument {
+            keyword: self.keyword.to_string(),
+            index,
+            offset: self.offset(index).unwrap_or(self.end),
+            reason: reason.into(),
+        }
+    }
+    /// The token at `index` as a string
+    pub fn str(&self, index: usize) -> Result<&'a str, Error> {
+        let token = self
+            .get(index)
+            .ok_or_else(|| self.error(index, "missing"))?;
+        str::from_utf8(token).map_err(|_| self.error(index, "not valid UTF-8"))
+    }
+    /// Parses the token at `index`
+    pub fn arg<T: FromStr>(&self, index: usize) -> Result<T, Error> {
+        let token = self.str(index)?;
+        token
+            .parse()
+            .map_err(|_| self.error(index, format!("malformed \"{}\"", token)))
+    }
+    /// Parses every token as the same type
+    pub fn all<T: FromStr>(&self) -> Result<Vec<T>, Error> {
+        (0..self.len()).map(|index| self.arg(index)).collect()
+    }
+    /// Parses the token at `index` as an IPv4 or IPv6 address, which may be
+    /// in brackets
+    pub fn arg_ip(&self, index: usize) -> Result<IpAddr, Error> {
+        let token = self.str(index)?;
+        let address = token
+            .strip_prefix('[')
+            .and_then(|address| address.strip_suffix(']'))
+            .unwrap_or(token);
+        address
+            .parse()
+            .map_err(|_| self.error(index, format!("malformed address \"{}\"", token)))
+    }
+    /// Decodes the token at `index` as base64, with or without padding
+    pub fn arg_base64(&self, index: usize) -> Result<Vec<u8>, Error> {
+        let token = self.str(index)?;
+        decode_base64(token)
+            .ok_or_else(|| self.error(index, format!("malformed base64 \"{}\"", token)))
+    }
+    /// Decodes the token at `index` as a base64 digest of `length` bytes,
+    /// with or without padding
+    pub fn arg_base64_digest(&self, index: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let token = self.str(index)?;
+        decode_base64(token)
+            .filter(|digest| digest.len() == length)
+            .ok_or_else(|| {
+                self.error(
+                    index,
+                    format!(
+                        "expected a {}-byte base64 digest, found \"{}\"",
+                        length, token
+                    ),
+                )
+            })
+    }
+    /// Decodes the token at `index` as a 40-digit hex fingerprint, which may
+    /// have a '$' prefix
+    pub fn arg_hex_fingerprint(&self, index: usize) -> Result<RsaIdentity, Error> {
+        let token = self.str(index)?;
+        RsaIdentity::from_hex(token.strip_prefix('$').unwrap_or(token))
+            .ok_or_else(|| self.error(index, format!("malformed fingerprint \"{}\"", token)))
+    }
+    /// Parses the tokens at `date` and `time` as a
+    /// "YYYY-MM-DD HH:MM:SS" timestamp
+    pub fn arg_datetime(&self, date: usize, time: usize) -> Result<Timestamp, Error> {
+        let timestamp = format!("{} {}", self.str(date)?, self.str(time)?);
+        Timestamp::parse(&timestamp)
+            .ok_or_else(|| self.error(date, format!("malformed timestamp \"{}\"", timestamp)))
+    }
+    /// Splits the token at `index` as a key=value pair
+    pub fn arg_pair(&self, index: usize) -> Result<(&'a str, &'a str), Error> {
+        let token = self.str(index)?;
+        match token.find('=') {
+            Some(equals) if equals > 0 => Ok((&token[..equals], &token[equals + 1..])),
+            _ => Err(self.error(index, format!("expected key=value, found \"{}\"", token))),
+        }
+    }
+    /// Splits every token as a key=value pair, as on "params",
+    /// "bandwidth-weights" and "pr" lines. The position of each pair is the
+    /// index of its token.
+    pub fn kv_pairs(&self) -> Result<Vec<(&'a str, &'a str)>, Error> {
+        (0..self.len()).map(|index| self.arg_pair(index)).collect()
+    }
+}
+/// Test
+#[test]
+fn test_arguments() {
+    use document::parse_complete;
+    let document = parse_complete(
+        b"published 2018-08-03 14:08:02\n\
+          r seele AAoQ1DAR6kkoo19hBAX5K0QztNw 2018-08-03 14:08:02 67.161.31.147 9001 0\n\
+          family $0011BD2485AD45D984EC4159C88FC066E5E3300E\n\
+          params  CircuitPriorityHalflifeMsec=30000\tNumDirectoryGuards=x\n\
+          contact\n",
+    )
+    .unwrap();
+    let items = document.items();
+    assert_eq!(
+        items[0].args().arg_datetime(0, 1).unwrap(),
+        Timestamp::parse("2018-08-03 14:08:02").unwrap()
+    );
+    let r = items[1].args();
+    assert_eq!(r.len(), 7);
+    assert_eq!(r.str(0).unwrap(), "seele");
+    assert_eq!(r.arg_base64_digest(1, 20).unwrap().len(), 20);
+    assert_eq!(
+        r.arg_base64_digest(1, 32),
+        Err(Error::InvalidArgument {
+            keyword: "r".to_string(),
+            index: 1,
+            offset: 38,
+            reason: "expected a 32-byte base64 digest, found \"AAoQ1DAR6kkoo19hBAX5K0QztNw\""
+                .to_string(),
+        })
+    );
+    assert_eq!(
+        r.arg_datetime(2, 3).unwrap(),
+        items[0].args().arg_datetime(0, 1).unwrap()
+    );
+    assert_eq!(
+        r.arg_ip(4).unwrap(),
+        "67.161.31.147".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(r.arg::<u16>(5).unwrap(), 9001);
+    assert_eq!(
+        r.arg::<u16>(0).err(),
+        Some(Error::InvalidArgument {
+            keyword: "r".to_string(),
+            index: 0,
+            offset: 32,
+            reason: "malformed \"seele\"".to_string(),
+        })
+    );
+    assert_eq!(
+        items[2].args().arg_hex_fingerprint(0).unwrap().0[..2],
+        [0x00, 0x11]
+    );
+    assert_eq!(
+        r.all::<String>().unwrap()[..2],
+        ["seele", "AAoQ1DAR6kkoo19hBAX5K0QztNw"]
+    );
+    assert!(r.all::<u16>().is_err());
+    assert_eq!(
+        r.arg_base64(1).unwrap(),
+        r.arg_base64_digest(1, 20).unwrap()
+    );
+    assert!(r.arg_pair(0).is_err());
+    let params = items[3].args();
+    let pairs = params.kv_pairs().unwrap();
+    assert_eq!(pairs[1], ("NumDirectoryGuards", "x"));
+    assert_eq!(params.arg_pair(1).unwrap(), pairs[1]);
+    assert_eq!(params.offset(1), Some(198));
+    // Missing arguments point at the end of the line
+    match items[4].args().str(0) {
+        Err(Error::InvalidArgument { offset, .. }) => assert_eq!(offset, 226),
+        other => panic!("unexpected {:?}", other),
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{ConsensusDocument, Router};
+use error::Error;
+use options::{NonAscii, ParseOptions};
+use std::collections::HashMap;
+use std::fmt;
+use time::Timestamp;
+
+/// Bandwidth files are written by bandwidth scanners such as sbws and
+/// torflow, and read by authorities to set the "Measured=" value of each
+/// relay's "w" line. They are not in the directory metaformat.
+///
+/// Spec defined in bandwidth-file-spec.txt, versions 1.0.0 through 1.4.0
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BandwidthFile {
+    /// Timestamp NL
+    /// When the scanner last measured a relay, as a unix timestamp.
+    /// [At start, exactly once.]
+    pub timestamp: Timestamp,
+    /// "version=" version NL, "1.0.0" when not given.
+    /// [Exactly once, first, since version 1.1.0]
+    pub version: String,
+    /// The other header lines, such as "software" and "file_created", in
+    /// order.
+    /// [Any number, since version 1.1.0]
+    pub headers: Vec<(String, String)>,
+    /// One entry per measured relay
+    pub relays: Vec<BandwidthEntry>,
+}
+/// RelayLine ::= KeyValue (SP KeyValue)* NL
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BandwidthEntry {
+    /// "node_id=" "$" hex fingerprint, stored without the "$"
+    pub node_id: String,
+    /// The base64 Ed25519 master key, the alternative relay identity since
+    /// version 1.1.0
+    pub master_key_ed25519: Option<String>,
+    /// The measured bandwidth in kilobytes per second
+    pub bw: u64,
+    pub nick: Option<String>,
+    /// "vote=0" tells authorities not to vote on the relay's bandwidth.
+    /// [Since version 1.4.0]
+    pub vote: bool,
+    /// Whether the relay could not be measured and `bw` is a placeholder.
+    /// [Since version 1.4.0]
+    pub unmeasured: bool,
+    /// Whether too few relays were measured for the file to be used.
+    /// [Since version 1.4.0]
+    pub under_min_report: bool,
+    /// Every other pair, such as "bw_mean" or "success", in order
+    pub extra: Vec<(String, String)>,
+    /// The keys of the line in the order they were read, which writing the
+    /// entry follows. Values without a key here are written after the
+    /// others, leaving out flags that have their default value.
+    pub keys: Vec<String>,
+}
+/// A consensus relay and the bandwidth file entry measuring it, if any
+#[derive(Debug, Clone, Copy)]
+pub struct MeasuredRelay<'a> {
+    pub router: &'a Router,
+    pub entry: Option<&'a BandwidthEntry>,
+}
+
+/// Header lines end with a terminator, five "=" since version 1.2.0 and
+/// four before
+const TERMINATORS: &[&str] = &["=====", "===="];
+
+impl BandwidthFile {
+    /// Parses a bandwidth file of any version from 1.0.0 through 1.4.0
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a bandwidth file with the given options. As the file is not
+    /// in the metaformat, only the options about line endings, line length
+    /// and non-ASCII bytes apply.
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let text = match options.non_ascii {
+            NonAscii::Reject => match input.iter().position(|byte| !byte.is_ascii()) {
+                Some(offset) => return Err(Error::Syntax(offset)),
+                None => String::from_utf8_lossy(&input),
+            },
+            NonAscii::Replace | NonAscii::PassThrough => String::from_utf8_lossy(&input),
+        };
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for line in text.split_terminator('\n') {
+            if options
+                .max_line_length
+                .is_some_and(|maximum| line.len() > maximum)
+            {
+                return Err(Error::Syntax(offset));
+            }
+            lines.push((offset, line));
+            offset += line.len() + 1;
+        }
+        let (timestamp, mut rest) = match lines.split_first() {
+            Some((&(_, line), rest)) => (
+                line.trim().parse().map(Timestamp::from_unix).map_err(|_| {
+                    Error::invalid(
+                        "timestamp",
+                        format!("expected a unix time, found \"{}\"", line),
+                    )
+                })?,
+                rest,
+            ),
+            None => return Err(Error::MissingItem("timestamp")),
+        };
+        // Version 1.0.0 has no headers or terminator, later versions start
+        // with the version header
+        let mut version = "1.0.0".to_string();
+        let mut headers = Vec::new();
+        if let Some(terminator) = rest
+            .iter()
+            .position(|&(_, line)| TERMINATORS.contains(&line))
+        {
+            for &(_, line) in &rest[..terminator] {
+                let (key, value) = split_pair(line)?;
+                if key == "version" {
+                    version = value.to_string();
+                } else {
+                    headers.push((key.to_string(), value.to_string()));
+                }
+            }
+            rest = &rest[terminator + 1..];
+        }
+        let relays = rest
+            .iter()
+            .filter(|&&(_, line)| !line.trim().is_empty())
+            .map(|&(_, line)| BandwidthEntry::parse_line(line))
+            .collect::<Result<_, _>>()?;
+        Ok(BandwidthFile {
+            timestamp,
+            version,
+            headers,
+            relays,
+        })
+    }
+    /// The value of a header line
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&(header, _)| header == key)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Pairs every relay in a consensus with the entry measuring it, so the
+    /// measured bandwidth can be compared with the consensus weight
+    pub fn relays<'a>(&'a self, consensus: &'a ConsensusDocument) -> Vec<MeasuredRelay<'a>> {
+        let entries: HashMap<String, &BandwidthEntry> = self
+            .relays
+            .iter()
+            .map(|entry| (entry.node_id.to_uppercase(), entry))
+            .collect();
+        consensus
+            .routers
+            .iter()
+            .map(|router| MeasuredRelay {
+                router,
+                entry: router
+                    .rsa_identity()
+                    .and_then(|identity| entries.get(&identity.to_hex()).cloned()),
+            })
+            .collect()
+    }
+}
+impl fmt::Display for BandwidthFile {
+    /// Writes the file in the format of its version
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.timestamp.unix())?;
+        if self.version != "1.0.0" {
+            writeln!(f, "version={}", self.version)?;
+            for (key, value) in &self.headers {
+                writeln!(f, "{}={}", key, value)?;
+            }
+            let terminator = if self.version.starts_with("1.1.") {
+                TERMINATORS[1]
+            } else {
+                TERMINATORS[0]
+            };
+            writeln!(f, "{}", terminator)?;
+        }
+        for entry in &self.relays {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+impl BandwidthEntry {
+    /// Parses a relay line
+    fn parse_line(line: &str) -> Result<Self, Error> {
+        let mut node_id = None;
+        let mut bw = None;
+        let mut entry = BandwidthEntry {
+            node_id: String::new(),
+            master_key_ed25519: None,
+            bw: 0,
+            nick: None,
+            vote: true,
+            unmeasured: false,
+            under_min_report: false,
+            extra: Vec::new(),
+            keys: Vec::new(),
+        };
+        for pair in line.split_whitespace() {
+            let (key, value) = split_pair(pair)?;
+            entry.keys.push(key.to_string());
+            match key {
+                "node_id" => {
+                    let fingerprint = value.trim_start_matches('$');
+                    if fingerprint.len() != 40
+                        || !fingerprint.chars().all(|c| c.is_ascii_hexdigit())
+                    {
+                        return Err(Error::invalid(key, "malformed fingerprint"));
+                    }
+                    node_id = Some(fingerprint.to_string());
+                }
+                "bw" => {
+                    bw = Some(value.parse().map_err(|_| {
+                        Error::invalid(key, format!("malformed bandwidth \"{}\"", value))
+                    })?)
+                }
+                "master_key_ed25519" => entry.master_key_ed25519 = Some(value.to_string()),
+                "nick" => entry.nick = Some(value.to_string()),
+                "vote" => entry.vote = parse_flag(key, value)?,
+                "unmeasured" => entry.unmeasured = parse_flag(key, value)?,
+                "under_min_report" => entry.under_min_report = parse_flag(key, value)?,
+                _ => entry.extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        entry.node_id = node_id.ok_or(Error::MissingItem("node_id"))?;
+        entry.bw = bw.ok_or(Error::MissingItem("bw"))?;
+        Ok(entry)
+    }
+    /// The value of a pair without a typed field
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.extra
+            .iter()
+            .find(|&(extra, _)| extra == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+impl fmt::Display for BandwidthEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |value: bool| if value { "1" } else { "0" };
+        let mut pairs: Vec<(&str, String)> = Vec::new();
+        let mut extra = self.extra.iter();
+        let mut written = Vec::new();
+        for key in &self.keys {
+            let value = match key.as_str() {
+                "node_id" => Some(format!("${}", self.node_id)),
+                "bw" => Some(self.bw.to_string()),
+                "nick" => self.nick.clone(),
+                "master_key_ed25519" => self.master_key_ed25519.clone(),
+                "vote" => Some(flag(self.vote).to_string()),
+                "unmeasured" => Some(flag(self.unmeasured).to_string()),
+                "under_min_report" => Some(flag(self.under_min_report).to_string()),
+                _ => match extra.next() {
+                    Some((key, value)) => {
+                        pairs.push((key, value.clone()));
+                        continue;
+                    }
+                    None => None,
+                },
+            };
+            if let Some(value) = value {
+                pairs.push((key, value));
+                written.push(key.as_str());
+            }
+        }
+        let missing = |key: &str| !written.contains(&key);
+        if missing("node_id") {
+            pairs.push(("node_id", format!("${}", self.node_id)));
+        }
+        if missing("bw") {
+            pairs.push(("bw", self.bw.to_string()));
+        }
+        if let (Some(nick), true) = (&self.nick, missing("nick")) {
+            pairs.push(("nick", nick.clone()));
+        }
+        if let (Some(key), true) = (&self.master_key_ed25519, missing("master_key_ed25519")) {
+            pairs.push(("master_key_ed25519", key.clone()));
+        }
+        pairs.extend(extra.map(|(key, value)| (key.as_str(), value.clone())));
+        // Flags are only added when they differ from the defaults, since
+        // older versions don't have them
+        if !self.vote && missing("vote") {
+            pairs.push(("vote", "0".to_string()));
+        }
+        if self.unmeasured && missing("unmeasured") {
+            pairs.push(("unmeasured", "1".to_string()));
+        }
+        if self.under_min_report && missing("under_min_report") {
+            pairs.push(("under_min_report", "1".to_string()));
+        }
+        for (index, (key, value)) in pairs.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+impl<'a> MeasuredRelay<'a> {
+    /// The bandwidth the scanner measured
+    pub fn measured(&self) -> Option<u64> {
+        self.entry.map(|entry| entry.bw)
+    }
+    /// The bandwidth from the consensus "w" line
+    pub fn consensus_bandwidth(&self) -> Option<u32> {
+        self.router
+            .bandwidth
+            .as_ref()
+            .map(|bandwidth| bandwidth.bandwidth)
+    }
+}
+
+/// KeyValue ::= Key "=" Value
+fn split_pair(pair: &str) -> Result<(&str, &str), Error> {
+    let mut parts = pair.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key, value)),
+        _ => Err(Error::invalid(pair, "expected a key=value pair")),
+    }
+}
+/// "0" or "1"
+fn parse_flag(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Error::invalid(key, "expected 0 or 1")),
+    }
+}
+
+/// Test
+#[test]
+fn test_bandwidth_file() {
+    let file = BandwidthFile::parse(include_bytes!("../test/bandwidth-file"))
+        .expect("failed to parse bandwidth file");
+    assert_eq!(file.version, "1.4.0");
+    assert_eq!(file.header("software"), Some("sbws"));
+    assert_eq!(file.relays.len(), 3);
+    assert!(!file.relays[1].vote && file.relays[1].unmeasured);
+    assert_eq!(file.relays[2].get("success"), Some("7"));
+    // Writing the file back gives the same bytes, explicit defaults and
+    // the order of keys included
+    let written = file.to_string();
+    assert_eq!(
+        written.as_bytes(),
+        &include_bytes!("../test/bandwidth-file")[..]
+    );
+    assert_eq!(BandwidthFile::parse(written.as_bytes()).unwrap(), file);
+    let mut built = file.relays[2].clone();
+    built.keys.clear();
+    built.vote = false;
+    assert!(built
+        .to_string()
+        .starts_with("node_id=$0011BD2485AD45D984EC4159C88FC066E5E3300E bw=17000 nick="));
+    assert!(built.to_string().ends_with(" vote=0"));
+    assert_eq!(
+        BandwidthFile::parse(b"1518073200\nnode_id bw=5\n"),
+        Err(Error::invalid("node_id", "expected a key=value pair"))
+    );
+    let utf8 = "1518073200\nnode_id=$000A10D43011EA4928A35F610405F92B4433B4DC bw=52 nick=séele\n";
+    assert!(BandwidthFile::parse_with(utf8.as_bytes(), &ParseOptions::strict()).is_err());
+    assert_eq!(
+        BandwidthFile::parse(utf8.as_bytes()).unwrap().relays[0].nick,
+        Some("séele".to_string())
+    );
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/barebones.consensus"))
+        .expect("failed to parse consensus");
+    let relays = file.relays(&consensus);
+    assert_eq!(relays[2].router.nickname, "CalyxInstitute14");
+    assert_eq!(relays[2].measured(), Some(17000));
+    assert_eq!(relays[2].consensus_bandwidth(), Some(15800));
+    let legacy = BandwidthFile::parse(
+        b"1518073200\nnode_id=$000A10D43011EA4928A35F610405F92B4433B4DC bw=52 nick=seele\n",
+    )
+    .expect("failed to parse a version 1.0.0 file");
+    assert_eq!(legacy.version, "1.0.0");
+    assert_eq!(legacy.relays[0].bw, 52);
+}
@@ -0,0 +1,363 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{parse_threshold, Flavor, Router, Threshold};
+use descriptor::ServerDescriptor;
+use document::{
+    check_type_annotation, decode_hex, encode_base64, encode_hex, parse_complete_with,
+    split_documents, strip_annotations, Item,
+};
+use error::Error;
+use extrainfo::ExtraInfo;
+use options::ParseOptions;
+use rules::{EXTRA_INFO, NETWORK_STATUS, SERVER_DESCRIPTOR};
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+use time::Timestamp;
+
+/// The bridge authority's list of running bridges, as archived by CollecTor.
+/// Entries use the same "r", "a", "s", "w" and "p" lines as an ns-flavored
+/// consensus, but identities are hashed and addresses are scrubbed.
+///
+/// Format defined in CollecTor's bridge descriptor documentation
+#[derive(Debug, Clone)]
+pub struct BridgeNetworkStatus {
+    /// "published" YYYY-MM-DD HH:MM:SS NL
+    /// [At start, exactly once.]
+    pub published: Timestamp,
+    /// The thresholds the bridge authority used to assign flags.
+    /// [At most once]
+    pub flag_thresholds: Vec<Threshold>,
+    /// Hex fingerprint of the bridge authority, since format version 1.2.
+    /// [At most once]
+    pub fingerprint: Option<String>,
+    /// One entry per bridge
+    pub routers: Vec<BridgeRouter>,
+}
+/// An entry of a bridge network status, with the hashed identity in place
+/// of the identity and the digest of the original descriptor
+#[derive(Debug, Clone)]
+pub struct BridgeRouter {
+    pub router: Router,
+    /// The bridge's fingerprint hashed as by `hash_fingerprint`, which the
+    /// entry gives as its identity
+    pub hashed_fingerprint: String,
+    /// Whether the entry's addresses were all replaced by CollecTor
+    pub scrubbed_addresses: bool,
+}
+/// A bridge's server descriptor after CollecTor's sanitization, which
+/// removes keys and signatures, replaces the nickname's fingerprint with its
+/// sha1 hash, and scrubs addresses.
+/// The descriptor's `onion_key`, `signing_key` and `router_signature` are
+/// empty, and its `digest` is taken from `router_digest` so that it matches
+/// the bridge network status.
+#[derive(Debug, Clone)]
+pub struct BridgeDescriptor {
+    pub descriptor: ServerDescriptor,
+    /// "router-digest" of the original descriptor, in uppercase hex.
+    /// [Exactly once]
+    pub router_digest: String,
+    /// "router-digest-sha256" of the original descriptor, base64 with
+    /// trailing =s omitted.
+    /// [At most once]
+    pub router_digest_sha256: Option<String>,
+    /// The bridge's fingerprint hashed as by `hash_fingerprint`, from the
+    /// "fingerprint" line
+    pub hashed_fingerprint: Option<String>,
+    /// Whether the descriptor's addresses were all replaced by CollecTor
+    pub scrubbed_addresses: bool,
+}
+/// A bridge's extra-info document after CollecTor's sanitization.
+/// The document's `router_signature` is empty, and its `digest` is
+/// `router_digest` so that it matches the sanitized server descriptor's
+/// "extra-info-digest". Extra-info documents hold no addresses to scrub.
+#[derive(Debug, Clone)]
+pub struct BridgeExtraInfo {
+    pub extra_info: ExtraInfo,
+    /// "router-digest" of the original document, in uppercase hex.
+    /// [Exactly once]
+    pub router_digest: String,
+    /// "router-digest-sha256" of the original document.
+    /// [At most once]
+    pub router_digest_sha256: Option<String>,
+    /// The bridge's fingerprint hashed as by `hash_fingerprint`, from the
+    /// "extra-info" line
+    pub hashed_fingerprint: String,
+}
+
+impl BridgeNetworkStatus {
+    /// Parses a bridge network status, which may begin with an
+    /// "@type bridge-network-status" annotation
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a bridge network status with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let (annotations, document) = strip_annotations(&input);
+        check_type_annotation(&annotations, "bridge-network-status")?;
+        let document = parse_complete_with(document, options)
+            .map_err(|error| error.shift(input.len() - document.len()))?;
+        let items = document.items();
+        options.check_keywords(items, |keyword| {
+            keyword == "published"
+                || keyword == "flag-thresholds"
+                || keyword == "fingerprint"
+                || NETWORK_STATUS.knows(keyword)
+        })?;
+        let header_end = items
+            .iter()
+            .position(|item| item.keyword() == "r")
+            .unwrap_or(items.len());
+        let mut published = None;
+        let mut status = BridgeNetworkStatus {
+            published: Timestamp::from_unix(0),
+            flag_thresholds: Vec::new(),
+            fingerprint: None,
+            routers: Vec::new(),
+        };
+        for item in &items[..header_end] {
+            let arguments = item.args();
+            match item.keyword() {
+                "published" if published.is_some() => {
+                    return Err(Error::DuplicateItem("published".to_string()))
+                }
+                "published" => published = Some(arguments.arg_datetime(0, 1)?),
+                "flag-thresholds" => {
+                    status.flag_thresholds = (0..arguments.len())
+                        .map(|index| parse_threshold(&arguments, index))
+                        .collect::<Result<_, _>>()?
+                }
+                "fingerprint" => status.fingerprint = Some(arguments.arg(0)?),
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        status.published = published.ok_or(Error::MissingItem("published"))?;
+        let routers = &items[header_end..];
+        let starts: Vec<usize> = routers
+            .iter()
+            .enumerate()
+            .filter(|&(_, item)| item.keyword() == "r")
+            .map(|(index, _)| index)
+            .chain(Some(routers.len()))
+            .collect();
+        for bounds in starts.windows(2) {
+            let router = Router::from_items(&routers[bounds[0]..bounds[1]], false, Flavor::Ns)?;
+            let hashed_fingerprint = match router.rsa_identity() {
+                Some(identity) => identity.to_hex(),
+                None => {
+                    return Err(routers[bounds[0]]
+                        .args()
+                        .error(1, format!("malformed identity \"{}\"", router.identity)))
+                }
+            };
+            let scrubbed_addresses = is_scrubbed_address(&IpAddr::V4(router.ip))
+                && router
+                    .addresses
+                    .iter()
+                    .all(|address| is_scrubbed_address(&address.ip()));
+            status.routers.push(BridgeRouter {
+                router,
+                hashed_fingerprint,
+                scrubbed_addresses,
+            });
+        }
+        Ok(status)
+    }
+}
+impl BridgeDescriptor {
+    /// Parses a single sanitized bridge server descriptor, which may begin
+    /// with an "@type bridge-server-descriptor" annotation
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single sanitized bridge server descriptor with the given
+    /// options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let (annotations, document) = strip_annotations(&input);
+        check_type_annotation(&annotations, "bridge-server-descriptor")?;
+        let items = parse_complete_with(document, options)
+            .map_err(|error| error.shift(input.len() - document.len()))?;
+        let items = items.items();
+        options.check_keywords(items, |keyword| {
+            is_router_digest(keyword) || SERVER_DESCRIPTOR.knows(keyword)
+        })?;
+        let (router_digest, router_digest_sha256) = parse_router_digests(items)?;
+        let mut descriptor = ServerDescriptor::from_items(items, true)?;
+        descriptor.digest = encode_base64(&decode_hex(&router_digest).unwrap_or_default());
+        let scrubbed_addresses = is_scrubbed_address(&IpAddr::V4(descriptor.address))
+            && descriptor
+                .or_addresses
+                .iter()
+                .all(|address| is_scrubbed_address(&address.ip()));
+        Ok(BridgeDescriptor {
+            hashed_fingerprint: descriptor.fingerprint.clone(),
+            scrubbed_addresses,
+            descriptor,
+            router_digest,
+            router_digest_sha256,
+        })
+    }
+    /// Parses every sanitized descriptor in a concatenated stream
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every sanitized descriptor in a concatenated stream with the given options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "router")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+}
+impl BridgeExtraInfo {
+    /// Parses a single sanitized bridge extra-info document, which may begin
+    /// with an "@type bridge-extra-info" annotation
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single sanitized bridge extra-info document with the given
+    /// options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let (annotations, document) = strip_annotations(&input);
+        check_type_annotation(&annotations, "bridge-extra-info")?;
+        let items = parse_complete_with(document, options)
+            .map_err(|error| error.shift(input.len() - document.len()))?;
+        let items = items.items();
+        options.check_keywords(items, |keyword| {
+            is_router_digest(keyword) || EXTRA_INFO.knows(keyword)
+        })?;
+        let (router_digest, router_digest_sha256) = parse_router_digests(items)?;
+        let mut extra_info = ExtraInfo::from_items(items, true)?;
+        extra_info.digest = router_digest.clone();
+        extra_info.digest_sha256 = router_digest_sha256.clone().unwrap_or_default();
+        Ok(BridgeExtraInfo {
+            hashed_fingerprint: extra_info.fingerprint.clone(),
+            extra_info,
+            router_digest,
+            router_digest_sha256,
+        })
+    }
+    /// Parses every sanitized extra-info document in a concatenated stream
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every sanitized extra-info document in a concatenated stream with the given options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "extra-info")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+}
+
+/// Whether the keyword is one of the digests CollecTor adds to sanitized
+/// documents
+fn is_router_digest(keyword: &str) -> bool {
+    keyword == "router-digest" || keyword == "router-digest-sha256"
+}
+/// Hashes a bridge's hex fingerprint the way CollecTor does, giving the
+/// fingerprint that appears in its sanitized descriptors
+pub fn hash_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint: String = fingerprint.split_whitespace().collect();
+    match decode_hex(&fingerprint) {
+        Some(ref identity) if identity.len() == 20 => Some(encode_hex(&Sha1::digest(identity))),
+        _ => None,
+    }
+}
+/// Whether an address is one CollecTor substitutes for a bridge's real
+/// address: 10.x.x.x for IPv4 and fd9f:2e19:3bcf::/48 for IPv6
+pub fn is_scrubbed_address(address: &IpAddr) -> bool {
+    match *address {
+        IpAddr::V4(address) => address.octets()[0] == 10,
+        IpAddr::V6(address) => address.segments()[..3] == [0xfd9f, 0x2e19, 0x3bcf],
+    }
+}
+
+/// "router-digest" and "router-digest-sha256" of a sanitized document
+fn parse_router_digests(items: &[Item]) -> Result<(String, Option<String>), Error> {
+    let mut router_digest = None;
+    let mut router_digest_sha256 = None;
+    for item in items {
+        let arguments = item.args();
+        match item.keyword() {
+            "router-digest" => {
+                let digest: String = arguments.arg(0)?;
+                if digest.len() != 40 || decode_hex(&digest).is_none() {
+                    return Err(arguments.error(0, format!("malformed digest \"{}\"", digest)));
+                }
+                router_digest = Some(digest.to_uppercase());
+            }
+            "router-digest-sha256" => router_digest_sha256 = Some(arguments.arg(0)?),
+            _ => (),
+        }
+    }
+    Ok((
+        router_digest.ok_or(Error::MissingItem("router-digest"))?,
+        router_digest_sha256,
+    ))
+}
+
+/// Test
+#[test]
+fn test_parse_bridge_descriptors() {
+    let status = BridgeNetworkStatus::parse(include_bytes!("../test/bridge-network-status"))
+        .expect("failed to parse bridge network status");
+    assert_eq!(status.routers.len(), 2);
+    assert_eq!(status.published.to_string(), "2018-02-08 07:37:04");
+    let descriptors = BridgeDescriptor::parse_all(include_bytes!("../test/bridge-descriptors"))
+        .expect("failed to parse bridge descriptors");
+    let descriptor = &descriptors[0];
+    assert!(descriptor.scrubbed_addresses);
+    assert!(status.routers.iter().all(|entry| entry.scrubbed_addresses));
+    assert_eq!(
+        status.routers[0].router.digest.as_ref().unwrap(),
+        &descriptor.descriptor.digest
+    );
+    assert_eq!(
+        descriptor.hashed_fingerprint,
+        hash_fingerprint("0011 BD24 85AD 45D9 84EC 4159 C88F C066 E5E3 300E")
+    );
+    let extra_infos = BridgeExtraInfo::parse_all(include_bytes!("../test/bridge-extra-infos"))
+        .expect("failed to parse bridge extra-info documents");
+    assert!(extra_infos[0].extra_info.matches(&descriptor.descriptor));
+    assert_eq!(extra_infos[0].extra_info.transports, vec!["obfs4"]);
+    assert_eq!(
+        Some(&extra_infos[0].hashed_fingerprint),
+        descriptor.hashed_fingerprint.as_ref()
+    );
+    // Syntax errors count the annotation that was stripped
+    let broken = String::from_utf8_lossy(include_bytes!("../test/bridge-descriptors")).replacen(
+        "\nbandwidth ",
+        "\n bandwidth ",
+        1,
+    );
+    let position = broken.find("\n bandwidth ").unwrap() + 1;
+    assert_eq!(
+        BridgeDescriptor::parse(broken.as_bytes()).err(),
+        Some(Error::Syntax(position))
+    );
+    assert!(BridgeDescriptor::parse(include_bytes!("../test/bridge-network-status")).is_err());
+}
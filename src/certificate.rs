@@ -0,0 +1,255 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use descriptor::signed_portion;
+use document::{parse_complete_with, split_documents, Item};
+use error::Error;
+use options::ParseOptions;
+use rules::KEY_CERTIFICATE;
+#[cfg(feature = "serde")]
+use serialization;
+use sha1::{Digest, Sha1};
+use std::net::SocketAddrV4;
+use time::Timestamp;
+
+/// Directory authorities use a long-term identity key to certify the
+/// medium-term signing keys that they sign votes and consensuses with.
+/// Key certificates are published on their own and are also embedded in
+/// the authority section of every vote.
+///
+/// Spec defined in section 3.1 of dir-spec.txt
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct KeyCertificate {
+    /// Determines the version of the key certificate. MUST be "3".
+    /// [At start, exactly once.]
+    pub version: u32,
+    /// An IP:Port for this authority's directory port.
+    /// [At most once.]
+    pub address: Option<SocketAddrV4>,
+    /// Hexadecimal encoding without spaces based on the authority's
+    /// identity key.
+    /// [Exactly once.]
+    pub fingerprint: String,
+    /// The time (in UTC) when this document and corresponding key were
+    /// last generated.
+    /// [Exactly once.]
+    pub published: Timestamp,
+    /// A time (in UTC) after which this key is no longer valid.
+    /// [Exactly once.]
+    pub expires: Timestamp,
+    /// The long-term authority identity key, DER-encoded.
+    /// [Exactly once.]
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub identity_key: Vec<u8>,
+    /// The medium-term authority signing key, DER-encoded.
+    /// [Exactly once.]
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub signing_key: Vec<u8>,
+    /// The signature of the identity key digest made with the signing key.
+    /// [Exactly once.]
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub crosscert: Vec<u8>,
+    /// The signature of the whole certificate made with the identity key.
+    /// [At end, exactly once.]
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub certification: Vec<u8>,
+    /// SHA-1 digest of the certificate from its start through the
+    /// "dir-key-certification" line, which is what the certification
+    /// signs. It is only known for certificates parsed from their bytes,
+    /// and certificates without it never verify.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) digest: Option<[u8; 20]>,
+}
+/// Ed25519 certificates bind one key to another, such as an onion
+/// service's descriptor signing key to its blinded key, or a relay's
+/// signing key to its master identity key.
+///
+/// Spec defined in section 2.1 of cert-spec.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ed25519Certificate {
+    /// Always 1
+    pub version: u8,
+    /// What the certificate is for, such as 4 for a relay's signing key or 8
+    /// for an onion service's descriptor signing key
+    pub cert_type: u8,
+    /// Hours since the epoch after which the certificate is invalid
+    pub expiration: u32,
+    /// The type of the certified key, 1 for an Ed25519 key
+    pub key_type: u8,
+    pub certified_key: [u8; 32],
+    pub extensions: Vec<CertificateExtension>,
+    /// Ed25519 signature of everything before it
+    pub signature: Vec<u8>,
+}
+/// An extension of an Ed25519 certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateExtension {
+    /// 4 for the key that signed the certificate
+    pub ext_type: u8,
+    /// 1 if the certificate must be rejected when the extension is not
+    /// understood
+    pub flags: u8,
+    pub data: Vec<u8>,
+}
+impl Ed25519Certificate {
+    /// Parses the decoded bytes of an "ED25519 CERT" object
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        let malformed = || Error::invalid("ED25519 CERT", "certificate is truncated");
+        if input.len() < 40 {
+            return Err(malformed());
+        }
+        let mut certified_key = [0; 32];
+        certified_key.copy_from_slice(&input[7..39]);
+        let mut extensions = Vec::new();
+        let mut position = 40;
+        for _ in 0..input[39] {
+            let header = input.get(position..position + 4).ok_or_else(malformed)?;
+            let length = (usize::from(header[0]) << 8) | usize::from(header[1]);
+            let data = input
+                .get(position + 4..position + 4 + length)
+                .ok_or_else(malformed)?;
+            extensions.push(CertificateExtension {
+                ext_type: header[2],
+                flags: header[3],
+                data: data.to_vec(),
+            });
+            position += 4 + length;
+        }
+        if input.len() != position + 64 {
+            return Err(Error::invalid(
+                "ED25519 CERT",
+                "expected a 64-byte signature at the end",
+            ));
+        }
+        Ok(Ed25519Certificate {
+            version: input[0],
+            cert_type: input[1],
+            expiration: (u32::from(input[2]) << 24)
+                | (u32::from(input[3]) << 16)
+                | (u32::from(input[4]) << 8)
+                | u32::from(input[5]),
+            key_type: input[6],
+            certified_key,
+            extensions,
+            signature: input[position..].to_vec(),
+        })
+    }
+    /// The key that signed the certificate, from the extension of type 4
+    pub fn signing_key(&self) -> Option<[u8; 32]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.ext_type == 4 && extension.data.len() == 32)
+            .map(|extension| {
+                let mut key = [0; 32];
+                key.copy_from_slice(&extension.data);
+                key
+            })
+    }
+}
+impl KeyCertificate {
+    /// Parses a single key certificate from its raw bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single key certificate with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let document = parse_complete_with(&input, options)?;
+        options.check_keywords(document.items(), |keyword| KEY_CERTIFICATE.knows(keyword))?;
+        let mut certificate = Self::from_items(document.items())?;
+        certificate.digest = Some(certification_digest(&input)?);
+        Ok(certificate)
+    }
+    /// Parses every key certificate in a concatenated stream, such as the
+    /// response to a request for all authority certificates or a
+    /// cached-certs file
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every key certificate in a concatenated stream with the given
+    /// options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "dir-key-certificate-version")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+    /// Builds a key certificate from the items between
+    /// "dir-key-certificate-version" and "dir-key-certification"
+    pub fn from_items(items: &[Item]) -> Result<Self, Error> {
+        match items.first() {
+            Some(item) if item.keyword() == "dir-key-certificate-version" => (),
+            _ => return Err(Error::MissingItem("dir-key-certificate-version")),
+        }
+        let mut version = None;
+        let mut address = None;
+        let mut fingerprint = None;
+        let mut published = None;
+        let mut expires = None;
+        let mut identity_key = None;
+        let mut signing_key = None;
+        let mut crosscert = None;
+        let mut certification = None;
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "dir-key-certificate-version" => version = Some(arguments.arg(0)?),
+                "dir-address" => address = Some(arguments.arg(0)?),
+                "fingerprint" => fingerprint = Some(arguments.arg(0)?),
+                "dir-key-published" => published = Some(arguments.arg_datetime(0, 1)?),
+                "dir-key-expires" => expires = Some(arguments.arg_datetime(0, 1)?),
+                "dir-identity-key" => identity_key = Some(item.object("RSA PUBLIC KEY")?),
+                "dir-signing-key" => signing_key = Some(item.object("RSA PUBLIC KEY")?),
+                "dir-key-crosscert" => crosscert = Some(cross_signature(item)?),
+                "dir-key-certification" => certification = Some(item.object("SIGNATURE")?),
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        match items.last() {
+            Some(item) if item.keyword() == "dir-key-certification" => (),
+            _ => return Err(Error::MissingItem("dir-key-certification")),
+        }
+        KEY_CERTIFICATE.check_counts(items, &[])?;
+        Ok(KeyCertificate {
+            version: version.ok_or(Error::MissingItem("dir-key-certificate-version"))?,
+            address,
+            fingerprint: fingerprint.ok_or(Error::MissingItem("fingerprint"))?,
+            published: published.ok_or(Error::MissingItem("dir-key-published"))?,
+            expires: expires.ok_or(Error::MissingItem("dir-key-expires"))?,
+            identity_key: identity_key.ok_or(Error::MissingItem("dir-identity-key"))?,
+            signing_key: signing_key.ok_or(Error::MissingItem("dir-signing-key"))?,
+            crosscert: crosscert.ok_or(Error::MissingItem("dir-key-crosscert"))?,
+            certification: certification.ok_or(Error::MissingItem("dir-key-certification"))?,
+            digest: None,
+        })
+    }
+}
+/// The digest that "dir-key-certification" signs, of the bytes from the
+/// start of the certificate through that line
+pub(crate) fn certification_digest(certificate: &[u8]) -> Result<[u8; 20], Error> {
+    Ok(Sha1::digest(signed_portion(certificate, "dir-key-certification")?).into())
+}
+/// CrossSignature = "-----BEGIN ID SIGNATURE-----" NL Base64 "-----END ID SIGNATURE-----" NL
+/// Older certificates used a plain SIGNATURE object instead.
+fn cross_signature(item: &Item) -> Result<Vec<u8>, Error> {
+    item.object("ID SIGNATURE")
+        .or_else(|_| item.object("SIGNATURE"))
+}
@@ -0,0 +1,549 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{Authority, ConsensusDocument, Entry, PortPolicy, Router};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use time::Timestamp;
+use version::TorVersion;
+
+/// The relative change in a relay's bandwidth weight that `compare` reports
+pub const DEFAULT_BANDWIDTH_THRESHOLD: f64 = 0.5;
+
+/// What changed between two consensuses, in terms of relays, parameters
+/// and authorities rather than lines.
+/// The `Display` implementation gives a readable report.
+#[derive(Debug, Clone)]
+pub struct ConsensusComparison {
+    /// The valid-after times of the old and new consensus
+    pub valid_after: (Timestamp, Timestamp),
+    /// Relays only in the new consensus
+    pub added: Vec<RelaySummary>,
+    /// Relays only in the old consensus
+    pub removed: Vec<RelaySummary>,
+    /// Relays in both whose status entries differ in a reported way
+    pub changed: Vec<RelayChanges>,
+    /// Consensus parameters that were added, removed or changed
+    pub params: Vec<Change<i32>>,
+    /// The "*-protocols" lines that changed, keyed by their keyword
+    pub protocols: Vec<Change<Vec<Entry>>>,
+    /// The "client-versions" and "server-versions" lines that changed
+    pub recommended_versions: Vec<Change<Vec<TorVersion>>>,
+    /// Flags that only the new consensus knows
+    pub flags_added: Vec<String>,
+    /// Flags that only the old consensus knows
+    pub flags_removed: Vec<String>,
+    /// Authorities only in the new consensus
+    pub authorities_added: Vec<Authority>,
+    /// Authorities only in the old consensus
+    pub authorities_removed: Vec<Authority>,
+    /// Identities of authorities that only signed the new consensus
+    pub signatures_added: Vec<String>,
+    /// Identities of authorities that only signed the old consensus
+    pub signatures_removed: Vec<String>,
+    /// Identities of authorities that signed both with different signing keys
+    pub signing_keys_changed: Vec<String>,
+}
+/// A relay, by the names it is known by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelaySummary {
+    pub nickname: String,
+    /// The base64 identity as in the "r" line
+    pub identity: String,
+}
+/// The reported differences of a relay listed in both consensuses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayChanges {
+    pub relay: RelaySummary,
+    /// Flags in the new status entry only
+    pub flags_gained: Vec<String>,
+    /// Flags in the old status entry only
+    pub flags_lost: Vec<String>,
+    /// The old and new bandwidth weights, if they differ by more than the
+    /// threshold
+    pub bandwidth: Option<(u32, u32)>,
+    /// The old and new "v" lines, if they differ
+    pub version: Option<(Option<String>, Option<String>)>,
+    /// The old and new "p" lines, if they differ
+    pub policy: Option<(Option<PortPolicy>, Option<PortPolicy>)>,
+}
+/// A named value that was added, removed or changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<T> {
+    pub key: String,
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+impl ConsensusComparison {
+    /// Compares two consensuses, reporting bandwidth changes larger than the
+    /// given fraction of the old bandwidth
+    pub fn new(old: &ConsensusDocument, new: &ConsensusDocument, bandwidth_threshold: f64) -> Self {
+        let old_routers = by_identity(&old.routers);
+        let new_routers = by_identity(&new.routers);
+        let added = new_routers
+            .iter()
+            .filter(|&(identity, _)| !old_routers.contains_key(identity))
+            .map(|(_, router)| RelaySummary::new(router))
+            .collect();
+        let removed = old_routers
+            .iter()
+            .filter(|&(identity, _)| !new_routers.contains_key(identity))
+            .map(|(_, router)| RelaySummary::new(router))
+            .collect();
+        let changed = old_routers
+            .iter()
+            .filter_map(|(identity, old)| {
+                new_routers
+                    .get(identity)
+                    .and_then(|new| RelayChanges::new(old, new, bandwidth_threshold))
+            })
+            .collect();
+        let params = changes(
+            old.params
+                .iter()
+                .map(|param| (param.key.clone(), param.value)),
+            new.params
+                .iter()
+                .map(|param| (param.key.clone(), param.value)),
+        );
+        let protocol_lines = |consensus: &ConsensusDocument| {
+            vec![
+                (
+                    "recommended-client-protocols".to_string(),
+                    consensus.recommended_client_protocols.clone(),
+                ),
+                (
+                    "recommended-relay-protocols".to_string(),
+                    consensus.recommended_relay_protocols.clone(),
+                ),
+                (
+                    "required-client-protocols".to_string(),
+                    consensus.required_client_protocols.clone(),
+                ),
+                (
+                    "required-relay-protocols".to_string(),
+                    consensus.required_relay_protocols.clone(),
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, entries)| !entries.is_empty())
+        };
+        let version_lines = |consensus: &ConsensusDocument| {
+            vec![
+                (
+                    "client-versions".to_string(),
+                    consensus.client_versions.clone(),
+                ),
+                (
+                    "server-versions".to_string(),
+                    consensus.server_versions.clone(),
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(key, versions)| versions.map(|versions| (key, versions)))
+        };
+        let old_flags: BTreeSet<&String> = old.known_flags.iter().collect();
+        let new_flags: BTreeSet<&String> = new.known_flags.iter().collect();
+        let authorities =
+            |from: &ConsensusDocument, without: &ConsensusDocument| -> Vec<Authority> {
+                from.authorities
+                    .iter()
+                    .filter(|authority| {
+                        !without
+                            .authorities
+                            .iter()
+                            .any(|other| other.identity == authority.identity)
+                    })
+                    .cloned()
+                    .collect()
+            };
+        let signers = |consensus: &ConsensusDocument| -> BTreeMap<String, String> {
+            consensus
+                .footer
+                .signatures
+                .iter()
+                .map(|signature| {
+                    (
+                        signature.identity.clone(),
+                        signature.signing_key_digest.clone(),
+                    )
+                })
+                .collect()
+        };
+        let old_signers = signers(old);
+        let new_signers = signers(new);
+        ConsensusComparison {
+            valid_after: (old.valid_after, new.valid_after),
+            added,
+            removed,
+            changed,
+            params,
+            protocols: changes(protocol_lines(old), protocol_lines(new)),
+            recommended_versions: changes(version_lines(old), version_lines(new)),
+            flags_added: new_flags
+                .difference(&old_flags)
+                .map(|flag| flag.to_string())
+                .collect(),
+            flags_removed: old_flags
+                .difference(&new_flags)
+                .map(|flag| flag.to_string())
+                .collect(),
+            authorities_added: authorities(new, old),
+            authorities_removed: authorities(old, new),
+            signatures_added: new_signers
+                .keys()
+                .filter(|identity| !old_signers.contains_key(*identity))
+                .cloned()
+                .collect(),
+            signatures_removed: old_signers
+                .keys()
+                .filter(|identity| !new_signers.contains_key(*identity))
+                .cloned()
+                .collect(),
+            signing_keys_changed: old_signers
+                .iter()
+                .filter(|&(identity, key)| new_signers.get(identity).is_some_and(|new| new != key))
+                .map(|(identity, _)| identity.clone())
+                .collect(),
+        }
+    }
+    /// Whether nothing reportable changed
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.params.is_empty()
+            && self.protocols.is_empty()
+            && self.recommended_versions.is_empty()
+            && self.flags_added.is_empty()
+            && self.flags_removed.is_empty()
+            && self.authorities_added.is_empty()
+            && self.authorities_removed.is_empty()
+            && self.signatures_added.is_empty()
+            && self.signatures_removed.is_empty()
+            && self.signing_keys_changed.is_empty()
+    }
+}
+impl RelaySummary {
+    fn new(router: &Router) -> Self {
+        RelaySummary {
+            nickname: router.nickname.clone(),
+            identity: router.identity.clone(),
+        }
+    }
+}
+impl RelayChanges {
+    /// Compares two status entries of the same relay, or None if nothing
+    /// reportable changed
+    fn new(old: &Router, new: &Router, bandwidth_threshold: f64) -> Option<Self> {
+        let old_flags: BTreeSet<&String> = old.flags.iter().collect();
+        let new_flags: BTreeSet<&String> = new.flags.iter().collect();
+        let weight = |router: &Router| {
+            router
+                .bandwidth
+                .as_ref()
+                .map(|bandwidth| bandwidth.bandwidth)
+        };
+        let bandwidth = match (weight(old), weight(new)) {
+            (Some(old), Some(new))
+                if (f64::from(new) - f64::from(old)).abs()
+                    > bandwidth_threshold * f64::from(old) =>
+            {
+                Some((old, new))
+            }
+            _ => None,
+        };
+        let changes = RelayChanges {
+            relay: RelaySummary::new(new),
+            flags_gained: new_flags
+                .difference(&old_flags)
+                .map(|flag| flag.to_string())
+                .collect(),
+            flags_lost: old_flags
+                .difference(&new_flags)
+                .map(|flag| flag.to_string())
+                .collect(),
+            bandwidth,
+            version: if old.version != new.version {
+                Some((old.version.clone(), new.version.clone()))
+            } else {
+                None
+            },
+            policy: if old.policy != new.policy {
+                Some((old.policy.clone(), new.policy.clone()))
+            } else {
+                None
+            },
+        };
+        if changes.flags_gained.is_empty()
+            && changes.flags_lost.is_empty()
+            && changes.bandwidth.is_none()
+            && changes.version.is_none()
+            && changes.policy.is_none()
+        {
+            None
+        } else {
+            Some(changes)
+        }
+    }
+}
+
+impl ConsensusDocument {
+    /// Compares this consensus with a later one, reporting bandwidth changes
+    /// of more than `DEFAULT_BANDWIDTH_THRESHOLD`
+    pub fn compare(&self, other: &ConsensusDocument) -> ConsensusComparison {
+        ConsensusComparison::new(self, other, DEFAULT_BANDWIDTH_THRESHOLD)
+    }
+}
+
+/// Indexes status entries by their identity
+fn by_identity(routers: &[Router]) -> BTreeMap<&str, &Router> {
+    routers
+        .iter()
+        .map(|router| (router.identity.as_str(), router))
+        .collect()
+}
+/// Pairs up named values, keeping those that differ
+fn changes<T, I, J>(old: I, new: J) -> Vec<Change<T>>
+where
+    T: PartialEq,
+    I: IntoIterator<Item = (String, T)>,
+    J: IntoIterator<Item = (String, T)>,
+{
+    let mut paired: BTreeMap<String, (Option<T>, Option<T>)> = BTreeMap::new();
+    for (key, value) in old {
+        paired.entry(key).or_insert((None, None)).0 = Some(value);
+    }
+    for (key, value) in new {
+        paired.entry(key).or_insert((None, None)).1 = Some(value);
+    }
+    paired
+        .into_iter()
+        .filter(|&(_, (ref old, ref new))| old != new)
+        .map(|(key, (old, new))| Change { key, old, new })
+        .collect()
+}
+
+/// Writes an optional value, or "none"
+fn write_optional<T, F>(f: &mut fmt::Formatter, value: &Option<T>, write: F) -> fmt::Result
+where
+    F: Fn(&mut fmt::Formatter, &T) -> fmt::Result,
+{
+    match *value {
+        Some(ref value) => write(f, value),
+        None => write!(f, "none"),
+    }
+}
+/// Writes a change as "key: old -> new"
+fn write_change<T, F>(f: &mut fmt::Formatter, change: &Change<T>, write: F) -> fmt::Result
+where
+    F: Fn(&mut fmt::Formatter, &T) -> fmt::Result,
+{
+    write!(f, "{}: ", change.key)?;
+    write_optional(f, &change.old, &write)?;
+    write!(f, " -> ")?;
+    write_optional(f, &change.new, &write)?;
+    writeln!(f)
+}
+/// Writes protocol entries as they appear in documents
+fn write_entries(f: &mut fmt::Formatter, entries: &[Entry]) -> fmt::Result {
+    for (index, entry) in entries.iter().enumerate() {
+        if index > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}=", entry.name)?;
+        write_ranges(f, &entry.versions)?;
+    }
+    Ok(())
+}
+/// Writes inclusive ranges as "1-2,4"
+fn write_ranges<T: PartialEq + fmt::Display>(
+    f: &mut fmt::Formatter,
+    ranges: &[(T, T)],
+) -> fmt::Result {
+    for (index, (low, high)) in ranges.iter().enumerate() {
+        if index > 0 {
+            write!(f, ",")?;
+        }
+        if low == high {
+            write!(f, "{}", low)?;
+        } else {
+            write!(f, "{}-{}", low, high)?;
+        }
+    }
+    Ok(())
+}
+/// Writes a port policy as it appears in a "p" line
+fn write_policy(f: &mut fmt::Formatter, policy: &PortPolicy) -> fmt::Result {
+    write!(f, "{} ", if policy.accept { "accept" } else { "reject" })?;
+    write_ranges(f, &policy.ports)
+}
+
+impl fmt::Display for RelaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.nickname, self.identity)
+    }
+}
+impl fmt::Display for RelayChanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.relay)?;
+        for flag in &self.flags_gained {
+            write!(f, " +{}", flag)?;
+        }
+        for flag in &self.flags_lost {
+            write!(f, " -{}", flag)?;
+        }
+        if let Some((old, new)) = self.bandwidth {
+            write!(f, " bandwidth {} -> {}", old, new)?;
+        }
+        if let Some((ref old, ref new)) = self.version {
+            write!(f, " version ")?;
+            write_optional(f, old, |f, version| write!(f, "{}", version))?;
+            write!(f, " -> ")?;
+            write_optional(f, new, |f, version| write!(f, "{}", version))?;
+        }
+        if let Some((ref old, ref new)) = self.policy {
+            write!(f, " policy ")?;
+            write_optional(f, old, write_policy)?;
+            write!(f, " -> ")?;
+            write_optional(f, new, write_policy)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for ConsensusComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "consensus {} -> {}",
+            self.valid_after.0, self.valid_after.1
+        )?;
+        writeln!(
+            f,
+            "relays: {} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )?;
+        for relay in &self.added {
+            writeln!(f, "+ {}", relay)?;
+        }
+        for relay in &self.removed {
+            writeln!(f, "- {}", relay)?;
+        }
+        for relay in &self.changed {
+            writeln!(f, "~ {}", relay)?;
+        }
+        for param in &self.params {
+            write!(f, "param ")?;
+            write_change(f, param, |f, value| write!(f, "{}", value))?;
+        }
+        for protocols in &self.protocols {
+            write_change(f, protocols, |f, entries| write_entries(f, entries))?;
+        }
+        for versions in &self.recommended_versions {
+            write_change(f, versions, |f, versions| {
+                let versions: Vec<String> = versions.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", versions.join(","))
+            })?;
+        }
+        for flag in &self.flags_added {
+            writeln!(f, "known flag added: {}", flag)?;
+        }
+        for flag in &self.flags_removed {
+            writeln!(f, "known flag removed: {}", flag)?;
+        }
+        for authority in &self.authorities_added {
+            writeln!(
+                f,
+                "authority added: {} {}",
+                authority.nickname, authority.identity
+            )?;
+        }
+        for authority in &self.authorities_removed {
+            writeln!(
+                f,
+                "authority removed: {} {}",
+                authority.nickname, authority.identity
+            )?;
+        }
+        for identity in &self.signatures_added {
+            writeln!(f, "signature added: {}", identity)?;
+        }
+        for identity in &self.signatures_removed {
+            writeln!(f, "signature removed: {}", identity)?;
+        }
+        for identity in &self.signing_keys_changed {
+            writeln!(f, "signing key changed: {}", identity)?;
+        }
+        Ok(())
+    }
+}
+
+/// Test
+#[test]
+fn test_compare_consensuses() {
+    let old = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus")).into_owned();
+    let consensus = ConsensusDocument::parse(old.as_bytes()).unwrap();
+    assert!(consensus.compare(&consensus).is_empty());
+    let new = old
+        .replace("Bandwidth=997", "Bandwidth=2000")
+        .replace(
+            "s Fast Running V2Dir Valid",
+            "s Fast Running Stable V2Dir Valid",
+        )
+        .replace("v Tor 0.3.1.9", "v Tor 0.3.2.9")
+        .replace("NumEntryGuards=1 ", "")
+        .replace("usecreatefast=0", "usecreatefast=0 wombat=3")
+        .replace("BadExit Exit", "Exit")
+        .replace("NoEdConsensus Running", "NoEdConsensus Running StaleDesc");
+    let comparison = consensus.compare(&ConsensusDocument::parse(new.as_bytes()).unwrap());
+    assert!(comparison.added.is_empty() && comparison.removed.is_empty());
+    assert_eq!(comparison.changed.len(), 2);
+    assert_eq!(
+        comparison.changed[1].flags_gained,
+        vec!["Stable".to_string()]
+    );
+    assert_eq!(comparison.changed[1].bandwidth, Some((997, 2000)));
+    assert_eq!(
+        comparison.params,
+        vec![
+            Change {
+                key: "NumEntryGuards".to_string(),
+                old: Some(1),
+                new: None,
+            },
+            Change {
+                key: "wombat".to_string(),
+                old: None,
+                new: Some(3),
+            },
+        ]
+    );
+    let report = comparison.to_string();
+    assert!(report
+        .contains("~ seele AAoQ1DAR6kkoo19hBAX5K0QztNw: version Tor 0.3.1.9 -> Tor 0.3.2.9\n"));
+    assert!(report.contains("param NumEntryGuards: 1 -> none\n"));
+    assert_eq!(comparison.flags_added, vec!["StaleDesc".to_string()]);
+    assert_eq!(comparison.flags_removed, vec!["BadExit".to_string()]);
+    assert!(report.contains("known flag added: StaleDesc\n"));
+    assert!(report.contains("known flag removed: BadExit\n"));
+    // A whole day later, many relays came and went
+    let later = ConsensusDocument::parse(include_bytes!("../test/consensus")).unwrap();
+    let comparison = consensus.compare(&later);
+    assert_eq!(comparison.added.len() + 3 - comparison.removed.len(), 6218);
+}
@@ -0,0 +1,563 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use detached::signed_consensus;
+use document::{decode_base64, decode_hex, encode_hex};
+use error::Error;
+use sha3::{Digest, Sha3_256};
+use std::cmp::Ordering;
+
+/// Sections larger than this many line pairs are replaced wholesale rather
+/// than compared line by line, to bound the memory used by the LCS table
+const MAX_LCS_CELLS: usize = 1 << 24;
+
+/// A diff between two consensuses of the same flavor, which lets clients
+/// fetch only what changed since the consensus they have. It is an ed
+/// script that edits the lines of the base consensus into the target.
+///
+/// Spec defined in proposal 140 and section 4.3 of dir-spec.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusDiff {
+    /// "hash" SP FromDigest SP ToDigest NL
+    /// The uppercase hex sha3-256 digest of the signed portion of the base
+    /// consensus, up to and including the space after the first
+    /// "directory-signature".
+    pub base_digest: String,
+    /// The uppercase hex sha3-256 digest of the whole target consensus
+    pub target_digest: String,
+    /// Ed commands, in decreasing order of the line numbers they touch
+    pub commands: Vec<EdCommand>,
+}
+/// One ed command. Line numbers are those of the base consensus, counting
+/// from 1, and ranges are inclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdCommand {
+    /// "N" ["," M] "d": delete lines N through M
+    Delete { start: usize, end: usize },
+    /// "N" ["," M] "c": replace lines N through M with new lines
+    Change {
+        start: usize,
+        end: usize,
+        lines: Vec<Vec<u8>>,
+    },
+    /// "N" "a": add new lines after line N, where 0 means at the start
+    Append { after: usize, lines: Vec<Vec<u8>> },
+}
+/// Lines of the base from `old.0` up to `old.1` are replaced by lines of
+/// the target from `new.0` up to `new.1`, counting from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Hunk {
+    old: (usize, usize),
+    new: (usize, usize),
+}
+
+impl ConsensusDiff {
+    /// Parses a diff document
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        let mut lines = split_lines(input)
+            .ok_or_else(|| Error::invalid("network-status-diff-version", "missing final newline"))?
+            .into_iter();
+        if lines.next() != Some(&b"network-status-diff-version 1"[..]) {
+            return Err(Error::MissingItem("network-status-diff-version"));
+        }
+        let hash = lines
+            .next()
+            .and_then(|line| ::std::str::from_utf8(line).ok())
+            .ok_or(Error::MissingItem("hash"))?;
+        let hashes: Vec<&str> = hash.split(' ').collect();
+        let is_digest = |digest: &str| digest.len() == 64 && decode_hex(digest).is_some();
+        if hashes.len() != 3
+            || hashes[0] != "hash"
+            || !is_digest(hashes[1])
+            || !is_digest(hashes[2])
+        {
+            return Err(Error::invalid("hash", "expected two hex sha3-256 digests"));
+        }
+        let mut commands: Vec<EdCommand> = Vec::new();
+        while let Some(line) = lines.next() {
+            let command = ::std::str::from_utf8(line)
+                .map_err(|_| Error::invalid("ed command", "not ASCII"))?;
+            let invalid =
+                |reason: &str| Error::invalid(format!("ed command \"{}\"", command), reason);
+            let (range, action) = command.split_at(command.len().saturating_sub(1));
+            let mut bounds = range.splitn(2, ',');
+            let start: usize = bounds
+                .next()
+                .and_then(|start| start.parse().ok())
+                .ok_or_else(|| invalid("malformed line number"))?;
+            let end = match bounds.next() {
+                None => None,
+                Some("$") => Some(usize::MAX),
+                Some(end) => Some(end.parse().map_err(|_| invalid("malformed line number"))?),
+            };
+            let mut take_lines = || -> Result<Vec<Vec<u8>>, Error> {
+                let mut added = Vec::new();
+                loop {
+                    match lines.next() {
+                        Some(b".") => return Ok(added),
+                        Some(line) => added.push(line.to_vec()),
+                        None => return Err(invalid("missing \".\" after the new lines")),
+                    }
+                }
+            };
+            let parsed = match (action, end) {
+                ("d", _) | ("c", _) if start == 0 || end.is_some_and(|end| end < start) => {
+                    return Err(invalid("empty range"))
+                }
+                ("d", end) => EdCommand::Delete {
+                    start,
+                    end: end.unwrap_or(start),
+                },
+                ("c", end) => EdCommand::Change {
+                    start,
+                    end: end.unwrap_or(start),
+                    lines: take_lines()?,
+                },
+                ("a", None) => EdCommand::Append {
+                    after: start,
+                    lines: take_lines()?,
+                },
+                _ => return Err(invalid("unknown command")),
+            };
+            // Each command must come before the previous one so that line
+            // numbers still refer to the base
+            if let Some(previous) = commands.last() {
+                if parsed.last_line() >= previous.first_line() {
+                    return Err(invalid("commands out of order"));
+                }
+            }
+            commands.push(parsed);
+        }
+        Ok(ConsensusDiff {
+            base_digest: hashes[1].to_uppercase(),
+            target_digest: hashes[2].to_uppercase(),
+            commands,
+        })
+    }
+    /// Applies a diff document to the base consensus it was made from,
+    /// checking the digests of both the base and the result
+    pub fn apply(base: &[u8], diff: &[u8]) -> Result<Vec<u8>, Error> {
+        Self::parse(diff)?.apply_to(base)
+    }
+    /// Applies this diff to the base consensus it was made from, checking
+    /// the digests of both the base and the result
+    pub fn apply_to(&self, base: &[u8]) -> Result<Vec<u8>, Error> {
+        if base_digest(base)? != self.base_digest {
+            return Err(Error::invalid(
+                "hash",
+                "the base consensus does not match the diff",
+            ));
+        }
+        let mut lines: Vec<Vec<u8>> = split_lines(base)
+            .ok_or_else(|| Error::invalid("hash", "the base consensus lacks a final newline"))?
+            .into_iter()
+            .map(|line| line.to_vec())
+            .collect();
+        for command in &self.commands {
+            let out_of_range = || {
+                Error::invalid(
+                    "ed command",
+                    format!(
+                        "line {} is past the end of the base consensus",
+                        command.last_line()
+                    ),
+                )
+            };
+            match *command {
+                EdCommand::Delete { start, end } | EdCommand::Change { start, end, .. } => {
+                    let end = if end == usize::MAX { lines.len() } else { end };
+                    if end > lines.len() {
+                        return Err(out_of_range());
+                    }
+                    if start == 0 || start > end {
+                        return Err(Error::invalid(
+                            "ed command",
+                            format!("lines {} to {} are an empty range", start, end),
+                        ));
+                    }
+                    let added = match *command {
+                        EdCommand::Change { ref lines, .. } => lines.clone(),
+                        _ => Vec::new(),
+                    };
+                    lines.splice(start - 1..end, added);
+                }
+                EdCommand::Append {
+                    after,
+                    lines: ref added,
+                } => {
+                    if after > lines.len() {
+                        return Err(out_of_range());
+                    }
+                    lines.splice(after..after, added.iter().cloned());
+                }
+            }
+        }
+        let mut result = lines.join(&b'\n');
+        result.push(b'\n');
+        if encode_hex(&Sha3_256::digest(&result)) != self.target_digest {
+            return Err(Error::invalid(
+                "hash",
+                "the result does not match the target digest",
+            ));
+        }
+        Ok(result)
+    }
+    /// Generates a diff from one consensus to another. Router status entries
+    /// are matched up by identity and only compared line by line with the
+    /// entry of the same relay, as tor does, which keeps diffs small and
+    /// fast to compute.
+    pub fn generate(old: &[u8], new: &[u8]) -> Result<Self, Error> {
+        let old_lines = split_lines(old)
+            .ok_or_else(|| Error::invalid("hash", "the base consensus lacks a final newline"))?;
+        let new_lines = split_lines(new)
+            .ok_or_else(|| Error::invalid("hash", "the target consensus lacks a final newline"))?;
+        // A line holding a single "." would end the new lines early
+        if new_lines.iter().any(|line| *line == b".") {
+            return Err(Error::invalid(
+                "ed command",
+                "the target consensus has a line that ed can't represent",
+            ));
+        }
+        let mut hunks = Vec::new();
+        match (router_section(&old_lines), router_section(&new_lines)) {
+            (Some(old_routers), Some(new_routers)) => {
+                diff_lines(
+                    &old_lines,
+                    &new_lines,
+                    (0, old_routers.0),
+                    (0, new_routers.0),
+                    &mut hunks,
+                );
+                diff_routers(&old_lines, &new_lines, old_routers, new_routers, &mut hunks);
+                diff_lines(
+                    &old_lines,
+                    &new_lines,
+                    (old_routers.1, old_lines.len()),
+                    (new_routers.1, new_lines.len()),
+                    &mut hunks,
+                );
+            }
+            _ => diff_lines(
+                &old_lines,
+                &new_lines,
+                (0, old_lines.len()),
+                (0, new_lines.len()),
+                &mut hunks,
+            ),
+        }
+        let commands = merge_hunks(hunks)
+            .into_iter()
+            .rev()
+            .map(|hunk| {
+                let added: Vec<Vec<u8>> = new_lines[hunk.new.0..hunk.new.1]
+                    .iter()
+                    .map(|line| line.to_vec())
+                    .collect();
+                if hunk.old.0 == hunk.old.1 {
+                    EdCommand::Append {
+                        after: hunk.old.0,
+                        lines: added,
+                    }
+                } else if added.is_empty() {
+                    EdCommand::Delete {
+                        start: hunk.old.0 + 1,
+                        end: hunk.old.1,
+                    }
+                } else {
+                    EdCommand::Change {
+                        start: hunk.old.0 + 1,
+                        end: hunk.old.1,
+                        lines: added,
+                    }
+                }
+            })
+            .collect();
+        Ok(ConsensusDiff {
+            base_digest: base_digest(old)?,
+            target_digest: encode_hex(&Sha3_256::digest(new)),
+            commands,
+        })
+    }
+    /// Writes the diff document
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = format!(
+            "network-status-diff-version 1\nhash {} {}\n",
+            self.base_digest, self.target_digest
+        )
+        .into_bytes();
+        for command in &self.commands {
+            let (header, lines) = match *command {
+                EdCommand::Delete { start, end } => (format!("{}d", range(start, end)), None),
+                EdCommand::Change {
+                    start,
+                    end,
+                    ref lines,
+                } => (format!("{}c", range(start, end)), Some(lines)),
+                EdCommand::Append { after, ref lines } => (format!("{}a", after), Some(lines)),
+            };
+            output.extend_from_slice(header.as_bytes());
+            output.push(b'\n');
+            if let Some(lines) = lines {
+                for line in lines {
+                    output.extend_from_slice(line);
+                    output.push(b'\n');
+                }
+                output.extend_from_slice(b".\n");
+            }
+        }
+        output
+    }
+}
+impl EdCommand {
+    /// The first base line the command touches, or the line it appends after
+    fn first_line(&self) -> usize {
+        match *self {
+            EdCommand::Delete { start, .. } | EdCommand::Change { start, .. } => start,
+            EdCommand::Append { after, .. } => after,
+        }
+    }
+    /// The last base line the command touches, or the line it appends after
+    fn last_line(&self) -> usize {
+        match *self {
+            EdCommand::Delete { end, .. } | EdCommand::Change { end, .. } => end,
+            EdCommand::Append { after, .. } => after,
+        }
+    }
+}
+
+/// The hex sha3-256 digest of the signed portion of a consensus
+fn base_digest(consensus: &[u8]) -> Result<String, Error> {
+    Ok(encode_hex(&Sha3_256::digest(signed_consensus(consensus)?)))
+}
+/// "N" or "N,M"
+fn range(start: usize, end: usize) -> String {
+    if start == end {
+        start.to_string()
+    } else if end == usize::MAX {
+        format!("{},$", start)
+    } else {
+        format!("{},{}", start, end)
+    }
+}
+/// Splits a document into lines without their newlines, or None if the
+/// document doesn't end with one
+fn split_lines(input: &[u8]) -> Option<Vec<&[u8]>> {
+    if input.is_empty() {
+        return Some(Vec::new());
+    }
+    let (last, body) = input.split_last()?;
+    if *last != b'\n' {
+        return None;
+    }
+    Some(body.split(|byte| *byte == b'\n').collect())
+}
+/// The lines from the first "r" line up to the footer
+fn router_section(lines: &[&[u8]]) -> Option<(usize, usize)> {
+    let start = lines.iter().position(|line| line.starts_with(b"r "))?;
+    let end = lines[start..]
+        .iter()
+        .position(|line| *line == b"directory-footer" || line.starts_with(b"directory-signature "))
+        .map(|position| start + position)
+        .unwrap_or(lines.len());
+    Some((start, end))
+}
+/// The decoded identity from an "r" line, which entries are sorted by
+fn router_identity(line: &[u8]) -> Vec<u8> {
+    ::std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.split(' ').nth(2))
+        .and_then(decode_base64)
+        .unwrap_or_default()
+}
+/// The start of every router status entry in a section, plus its end
+fn entry_bounds(lines: &[&[u8]], section: (usize, usize)) -> Vec<usize> {
+    (section.0..section.1)
+        .filter(|&index| lines[index].starts_with(b"r "))
+        .chain(Some(section.1))
+        .collect()
+}
+/// Diffs the router status entries, pairing entries of the same relay
+fn diff_routers(
+    old: &[&[u8]],
+    new: &[&[u8]],
+    old_section: (usize, usize),
+    new_section: (usize, usize),
+    hunks: &mut Vec<Hunk>,
+) {
+    let old_entries = entry_bounds(old, old_section);
+    let new_entries = entry_bounds(new, new_section);
+    let (mut i, mut j) = (0, 0);
+    while i + 1 < old_entries.len() || j + 1 < new_entries.len() {
+        let old_entry = (
+            old_entries[i],
+            old_entries[(i + 1).min(old_entries.len() - 1)],
+        );
+        let new_entry = (
+            new_entries[j],
+            new_entries[(j + 1).min(new_entries.len() - 1)],
+        );
+        let order = if i + 1 == old_entries.len() {
+            Ordering::Greater
+        } else if j + 1 == new_entries.len() {
+            Ordering::Less
+        } else {
+            router_identity(old[old_entry.0]).cmp(&router_identity(new[new_entry.0]))
+        };
+        match order {
+            Ordering::Equal => {
+                diff_lines(old, new, old_entry, new_entry, hunks);
+                i += 1;
+                j += 1;
+            }
+            // The relay left the consensus
+            Ordering::Less => {
+                hunks.push(Hunk {
+                    old: old_entry,
+                    new: (new_entry.0, new_entry.0),
+                });
+                i += 1;
+            }
+            // The relay joined the consensus
+            Ordering::Greater => {
+                hunks.push(Hunk {
+                    old: (old_entry.0, old_entry.0),
+                    new: new_entry,
+                });
+                j += 1;
+            }
+        }
+    }
+}
+/// Diffs two ranges of lines with a longest common subsequence, after
+/// trimming their common prefix and suffix
+fn diff_lines(
+    old: &[&[u8]],
+    new: &[&[u8]],
+    mut old_range: (usize, usize),
+    mut new_range: (usize, usize),
+    hunks: &mut Vec<Hunk>,
+) {
+    while old_range.0 < old_range.1
+        && new_range.0 < new_range.1
+        && old[old_range.0] == new[new_range.0]
+    {
+        old_range.0 += 1;
+        new_range.0 += 1;
+    }
+    while old_range.0 < old_range.1
+        && new_range.0 < new_range.1
+        && old[old_range.1 - 1] == new[new_range.1 - 1]
+    {
+        old_range.1 -= 1;
+        new_range.1 -= 1;
+    }
+    let (n, m) = (old_range.1 - old_range.0, new_range.1 - new_range.0);
+    if n == 0 && m == 0 {
+        return;
+    }
+    if n == 0 || m == 0 || n * m > MAX_LCS_CELLS {
+        hunks.push(Hunk {
+            old: old_range,
+            new: new_range,
+        });
+        return;
+    }
+    // lengths[i][j] is the LCS length of old[i..] and new[j..]
+    let width = m + 1;
+    let mut lengths = vec![0u32; (n + 1) * width];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i * width + j] = if old[old_range.0 + i] == new[new_range.0 + j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut pending: Option<Hunk> = None;
+    while i < n || j < m {
+        if i < n && j < m && old[old_range.0 + i] == new[new_range.0 + j] {
+            hunks.extend(pending.take());
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let hunk = pending.get_or_insert(Hunk {
+            old: (old_range.0 + i, old_range.0 + i),
+            new: (new_range.0 + j, new_range.0 + j),
+        });
+        if j == m || (i < n && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1]) {
+            i += 1;
+            hunk.old.1 = old_range.0 + i;
+        } else {
+            j += 1;
+            hunk.new.1 = new_range.0 + j;
+        }
+    }
+    hunks.extend(pending);
+}
+/// Joins hunks that touch, so that each becomes a single command
+fn merge_hunks(hunks: Vec<Hunk>) -> Vec<Hunk> {
+    let mut merged: Vec<Hunk> = Vec::new();
+    for hunk in hunks {
+        match merged.last_mut() {
+            Some(last) if last.old.1 == hunk.old.0 && last.new.1 == hunk.new.0 => {
+                last.old.1 = hunk.old.1;
+                last.new.1 = hunk.new.1;
+            }
+            _ => merged.push(hunk),
+        }
+    }
+    merged
+}
+
+/// Test
+#[test]
+fn test_consensus_diff() {
+    let old = &include_bytes!("../test/barebones.consensus")[..];
+    let new = &include_bytes!("../test/consensus")[..];
+    let diff = ConsensusDiff::generate(old, new).expect("failed to generate diff");
+    let document = diff.to_bytes();
+    assert!(document.starts_with(b"network-status-diff-version 1\nhash "));
+    assert_eq!(ConsensusDiff::parse(&document), Ok(diff.clone()));
+    assert_eq!(ConsensusDiff::apply(old, &document).as_deref(), Ok(new));
+    // Relays present in both keep their unchanged lines
+    let edited = String::from_utf8(old.to_vec())
+        .unwrap()
+        .replace("w Bandwidth=997\n", "w Bandwidth=1020\n");
+    let diff = ConsensusDiff::generate(old, edited.as_bytes()).unwrap();
+    assert_eq!(
+        diff.commands,
+        vec![EdCommand::Change {
+            start: 37,
+            end: 37,
+            lines: vec![b"w Bandwidth=1020".to_vec()],
+        }]
+    );
+    assert!(ConsensusDiff::apply(new, &diff.to_bytes()).is_err());
+    // Ranges built by hand are checked as parsed ones are
+    let mut empty = diff.clone();
+    empty.commands = vec![EdCommand::Delete { start: 0, end: 3 }];
+    assert!(empty.apply_to(old).is_err());
+    empty.commands = vec![EdCommand::Delete { start: 5, end: 4 }];
+    assert!(empty.apply_to(old).is_err());
+    empty.commands = vec![EdCommand::Delete {
+        start: 1000,
+        end: usize::MAX,
+    }];
+    assert!(empty.apply_to(old).is_err());
+}
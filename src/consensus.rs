@@ -14,7 +14,29 @@
     You should have received a copy of the GNU General Public License
     along with onyan.  If not, see <http://www.gnu.org/licenses/>.
 */
-use std::io::BufRead;
+use arguments::Arguments;
+use certificate::{certification_digest, KeyCertificate};
+use detached::signed_consensus;
+use document::{decode_base64, parse_complete_with, Document, Item};
+use error::Error;
+use identity::{Ed25519Identity, RsaIdentity};
+use options::ParseOptions;
+use rules::{
+    NETWORK_STATUS, NETWORK_STATUS_AUTHORITY, NETWORK_STATUS_FOOTER, NETWORK_STATUS_PREAMBLE,
+    NETWORK_STATUS_ROUTER,
+};
+#[cfg(feature = "serde")]
+use serialization;
+use sha1::{Digest as _, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use time::Timestamp;
+use version::{TorVersion, VersionStatus};
+
+/// How long before valid-after and after valid-until a consensus is still
+/// good enough to use, in seconds
+pub const REASONABLY_LIVE_TIME: u64 = 24 * 60 * 60;
 
 /// Spec defined at https://gitweb.torproject.org/torspec.git/tree/dir-spec.txt
 /// Votes and consensuses are more strictly formatted than other documents
@@ -30,69 +52,62 @@ use std::io::BufRead;
 /// consensuses.  Unless specified, items occur in both.
 /// The preamble contains the following items.  They SHOULD occur in the
 /// order given here:
-struct ConsensusDocument {
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ConsensusDocument {
     /// A document format version.  For this specification, the version is "3".
     /// [At start, exactly once.]
-    network_status_version: i32,
+    pub network_status_version: i32,
+    /// The flavor named after the version, such as "microdesc" in
+    /// "network-status-version 3 microdesc". Absent means "ns".
+    /// Votes are always "ns".
+    pub flavor: Flavor,
     /// The status MUST be "vote" or "consensus", depending on the type of the document.
     /// [Exactly once.]
-    vote_status: String,
-    /// A space-separated list of supported methods for generating
-    /// consensuses from votes.  See section 3.8.1 for details.  Absence of
-    /// the line means that only method "1" is supported.
-    /// [At most once for votes; does not occur in consensuses.]
-    consensus_methods: Option<Vec<i32>>,
-    /// See section 3.8.1 for details.
-    /// (Only included when the vote is generated with consensus-method 2 or later.)
-    /// [At most once for consensuses; does not occur in votes.]
-    /// [No extra arguments]
-    consensus_method: Option<i32>,
-    /// The publication time for this status document (if a vote).
-    /// [Exactly once for votes; does not occur in consensuses.]
-    /// YYYY-MM-DD SP HH:MM:SS
-    published: String,
+    /// Holds the items that only occur in one of the two.
+    pub vote_status: NetworkStatus,
     /// The start of the Interval for this vote.  Before this time, the
     /// consensus document produced from this vote should not be used.
     /// See section 1.4 for voting timeline information.
     /// [Exactly once.]
     /// YYYY-MM-DD SP HH:MM:SS
-    valid_after: String,
+    pub valid_after: Timestamp,
     /// The time at which the next consensus should be produced; before this
     /// time, there is no point in downloading another consensus, since there
     /// won't be a new one.  See section 1.4 for voting timeline information.
     /// [Exactly once.]
     /// YYYY-MM-DD SP HH:MM:SS
-    fresh_until: String,
+    pub fresh_until: Timestamp,
     /// The end of the Interval for this vote.  After this time, the
     /// consensus produced by this vote should not be used.  See section 1.4
     /// for voting timeline information.
     /// [Exactly once.]
     /// YYYY-MM-DD SP HH:MM:SS
-    valid_until: String,
+    pub valid_until: Timestamp,
     /// VoteSeconds is the number of seconds that we will allow to collect
     /// votes from all authorities
     /// See section 1.4 for voting timeline information.
     /// [Exactly once.]
     /// Part of the voting-delay field
     /// VoteSeconds DistSeconds
-    vote_seconds: i32,
+    pub vote_seconds: i32,
     /// DistSeconds is the number of seconds
     /// we'll allow to collect signatures from all authorities.
     /// [Exactly once.]
     /// Part of the voting-delay field
-    dist_seconds: i32,
+    pub dist_seconds: i32,
     /// A comma-separated list of recommended Tor versions for client
     /// usage, in ascending order. The versions are given as defined by
     /// version-spec.txt. If absent, no opinion is held about client
     /// versions.
     /// [At most once.]
-    client_versions: Option<Vec<String>>,
+    pub client_versions: Option<Vec<TorVersion>>,
     /// A comma-separated list of recommended Tor versions for relay
     /// usage, in ascending order. The versions are given as defined by
     /// version-spec.txt. If absent, no opinion is held about server
     /// versions.
     /// [At most once.]
-    server_versions: Option<Vec<String>>,
+    pub server_versions: Option<Vec<TorVersion>>,
     /// Indicates that a package called "package" of version VERSION may be
     /// found at URL, and its digest as computed with DIGESTTYPE is equal to
     /// DIGESTVAL.  In consensuses, these lines are sorted lexically by
@@ -111,55 +126,14 @@ struct ConsensusDocument {
     /// other than "=".
     /// Included in consensuses only for method 19 and later.
     /// [Any number of times.]
-    package: Vec<Digest>,
+    pub package: Vec<Package>,
     /// A space-separated list of all of the flags that this document
     /// might contain.  A flag is "known" either because the authority
     /// knows about them and might set them (if in a vote), or because
     /// enough votes were counted for the consensus for an authoritative
     /// opinion to have been formed about their status.
     /// [Exactly once.]
-    known_flags: Vec<String>,
-    /// A space-separated list of the internal performance thresholds
-    /// that the directory authority had at the moment it was forming
-    /// a vote.
-    /// [At most once for votes; does not occur in consensuses.]
-    ///
-    /// The metaformat is:
-    ///    Thresholds = Threshold | Threshold SP Thresholds
-    ///    Threshold = ThresholdKey '=' ThresholdVal
-    ///    ThresholdKey = (KeywordChar | "_") +
-    ///    ThresholdVal = [0-9]+("."[0-9]+)? "%"?
-    ///
-    /// Commonly used Thresholds at this point include:
-    ///
-    /// "stable-uptime" -- Uptime (in seconds) required for a relay
-    ///                    to be marked as stable.
-    ///
-    /// "stable-mtbf" -- MTBF (in seconds) required for a relay to be
-    ///                  marked as stable.
-    ///
-    /// "enough-mtbf" -- Whether we have measured enough MTBF to look
-    ///                  at stable-mtbf instead of stable-uptime.
-    ///
-    /// "fast-speed" -- Bandwidth (in bytes per second) required for
-    ///                 a relay to be marked as fast.
-    ///
-    /// "guard-wfu" -- WFU (in seconds) required for a relay to be
-    ///                marked as guard.
-    ///
-    /// "guard-tk" -- Weighted Time Known (in seconds) required for a
-    ///               relay to be marked as guard.
-    ///
-    /// "guard-bw-inc-exits" -- If exits can be guards, then all guards
-    ///                         must have a bandwidth this high.
-    ///
-    /// "guard-bw-exc-exits" -- If exits can't be guards, then all guards
-    ///                         must have a bandwidth this high.
-    ///
-    /// "ignoring-advertised-bws" -- 1 if we have enough measured bandwidths
-    ///                         that we'll ignore the advertised bandwidth
-    ///                         claims of routers without measured bandwidth.
-    flag_thresholds: Vec<Threshold>,
+    pub known_flags: Vec<String>,
     /// The "proto" element as specified in section 2.1.1.
     ///
     /// To vote on these entries, a protocol/version combination is included
@@ -174,10 +148,10 @@ struct ConsensusDocument {
     /// The tor-spec.txt section 9 details how a relay and a client should
     /// behave when they encounter these lines in the consensus.
     /// [At most once for each.]
-    recommended_client_protocols: Vec<Entry>,
-    recommended_relay_protocols: Vec<Entry>,
-    required_client_protocols: Vec<Entry>,
-    required_relay_protocols: Vec<Entry>,
+    pub recommended_client_protocols: Vec<Entry>,
+    pub recommended_relay_protocols: Vec<Entry>,
+    pub required_client_protocols: Vec<Entry>,
+    pub required_relay_protocols: Vec<Entry>,
 
     /// The parameters list, if present, contains a space-separated list of
     /// case-sensitive key-value pairs, sorted in lexical order by their
@@ -354,7 +328,7 @@ struct ConsensusDocument {
     /// number of rendezvous attempt an HS service can make per introduction.
     /// Min 1. Max 10. Default 2.
     /// First-appeared: 0.3.3.0-alpha.
-    params: Vec<Param>,
+    pub params: Vec<Param>,
     /// The shared random value that was generated during the second-to-last
     /// shared randomness protocol run. For example, if this document was
     /// created on the 5th of November, this field carries the shared random
@@ -368,7 +342,8 @@ struct ConsensusDocument {
     /// values in votes and consensus.
     /// Value is the actual shared random value encoded in base64. NumReveals
     /// is the number of commits used to generate this SRV.
-    shared_rand_previous_value: (i32, Vec<u8>),
+    #[cfg_attr(feature = "serde", serde(with = "serialization::shared_rand"))]
+    pub shared_rand_previous_value: Option<(i32, Vec<u8>)>,
     /// The shared random value that was generated during the latest shared
     /// randomness protocol run. For example, if this document was created on
     /// the 5th of November, this field carries the shared random value
@@ -381,13 +356,16 @@ struct ConsensusDocument {
     /// this value given the active commits.
     /// Value is the actual shared random value encoded in base64. NumReveals
     /// is the number of commits used to generate this SRV.
-    shared_rand_current_value: (i32, Vec<u8>),
+    #[cfg_attr(feature = "serde", serde(with = "serialization::shared_rand"))]
+    pub shared_rand_current_value: Option<(i32, Vec<u8>)>,
+    /// Authorities that contributed to this document.
+    ///
+    /// ```text
     /// The authority section of a vote contains the following items, followed
     /// in turn by the authority's current key certificate:
     /// "dir-source" SP nickname SP identity SP address SP IP SP dirport SP
     ///    orport NL
     ///     [Exactly once, at start]
-
     ///     Describes this authority.  The nickname is a convenient identifier
     ///     for the authority.  The identity is an uppercase hex fingerprint of
     ///     the authority's current (v3 authority) identity key.  The address is
@@ -458,7 +436,11 @@ struct ConsensusDocument {
     /// with "-legacy" appended, and all other fields as in the main "dir-source"
     /// line for that authority.  These "dir-source" lines do not have
     /// corresponding "contact" or "vote-digest" entries.
-    authorities: Vec<Authority>,
+    /// ```
+    pub authorities: Vec<Authority>,
+    /// Router status entries, in the order they appear.
+    ///
+    /// ```text
     ///    Each router status entry contains the following items.  Router status
     ///    entries are sorted in ascending order by identity digest.
     ///
@@ -607,7 +589,11 @@ struct ConsensusDocument {
     ///      "id" SP "ed25519" SP ed25519-identity NL
     ///      "id" SP "ed25519" SP "none" NL
     ///         [vote only, at most once]
-    routers: Vec<Router>,
+    /// ```
+    pub routers: Vec<Router>,
+    /// The bandwidth weights and signatures at the end of the document.
+    ///
+    /// ```text
     /// The footer section is delineated in all votes and consensuses supporting
     /// consensus method 9 and above with the following:
     ///
@@ -686,8 +672,132 @@ struct ConsensusDocument {
     ///         "counted" as meaning that the authority has signed the consensus.
     ///
     ///         (Tor clients before 0.2.3.x did not understand the 'algorithm'
-    ///         field.)*/
-    _void: (),
+    ///         field.)
+    /// ```
+    pub footer: Footer,
+    /// SHA-1 and SHA-256 digests of the document from its start through
+    /// the space after the first "directory-signature" keyword, which is
+    /// what the signatures sign. They are only known for documents parsed
+    /// from their bytes, and signatures can't be checked or attached
+    /// without them.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) sha1_digest: Option<[u8; 20]>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) sha256_digest: Option<[u8; 32]>,
+}
+
+/// Consensus flavors differ in how router status entries refer to the
+/// router's descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Flavor {
+    /// The original flavor, where "r" lines carry the digest of the router's
+    /// server descriptor
+    Ns,
+    /// The flavor most clients fetch, where "r" lines have no descriptor
+    /// digest and an "m" line carries the digest of the router's
+    /// microdescriptor
+    Microdesc,
+}
+impl Flavor {
+    /// The name of the flavor as it appears in documents
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Flavor::Ns => "ns",
+            Flavor::Microdesc => "microdesc",
+        }
+    }
+    /// Detects the flavor from the arguments of "network-status-version"
+    fn from_version_item(item: &Item) -> Result<Self, Error> {
+        let arguments = item.args();
+        if arguments.len() < 2 {
+            return Ok(Flavor::Ns);
+        }
+        match arguments.str(1)? {
+            "ns" => Ok(Flavor::Ns),
+            "microdesc" => Ok(Flavor::Microdesc),
+            flavor => Err(arguments.error(1, format!("unknown flavor \"{}\"", flavor))),
+        }
+    }
+}
+
+/// Whether a status document is a vote or a consensus, along with the items
+/// that only occur in one of the two
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum NetworkStatus {
+    /// A vote published by a single authority
+    Vote {
+        /// A space-separated list of supported methods for generating
+        /// consensuses from votes.  See section 3.8.1 for details.  Absence of
+        /// the line means that only method "1" is supported.
+        /// [At most once for votes; does not occur in consensuses.]
+        consensus_methods: Vec<i32>,
+        /// The publication time for this status document (if a vote).
+        /// [Exactly once for votes; does not occur in consensuses.]
+        /// YYYY-MM-DD SP HH:MM:SS
+        published: Timestamp,
+        /// A space-separated list of the internal performance thresholds
+        /// that the directory authority had at the moment it was forming
+        /// a vote.
+        /// [At most once for votes; does not occur in consensuses.]
+        ///
+        /// The metaformat is:
+        ///    Thresholds = Threshold | Threshold SP Thresholds
+        ///    Threshold = ThresholdKey '=' ThresholdVal
+        ///    ThresholdKey = (KeywordChar | "_") +
+        ///    ThresholdVal = [0-9]+("."[0-9]+)? "%"?
+        ///
+        /// Commonly used Thresholds at this point include:
+        ///
+        /// "stable-uptime" -- Uptime (in seconds) required for a relay
+        ///                    to be marked as stable.
+        ///
+        /// "stable-mtbf" -- MTBF (in seconds) required for a relay to be
+        ///                  marked as stable.
+        ///
+        /// "enough-mtbf" -- Whether we have measured enough MTBF to look
+        ///                  at stable-mtbf instead of stable-uptime.
+        ///
+        /// "fast-speed" -- Bandwidth (in bytes per second) required for
+        ///                 a relay to be marked as fast.
+        ///
+        /// "guard-wfu" -- WFU (in seconds) required for a relay to be
+        ///                marked as guard.
+        ///
+        /// "guard-tk" -- Weighted Time Known (in seconds) required for a
+        ///               relay to be marked as guard.
+        ///
+        /// "guard-bw-inc-exits" -- If exits can be guards, then all guards
+        ///                         must have a bandwidth this high.
+        ///
+        /// "guard-bw-exc-exits" -- If exits can't be guards, then all guards
+        ///                         must have a bandwidth this high.
+        ///
+        /// "ignoring-advertised-bws" -- 1 if we have enough measured bandwidths
+        ///                         that we'll ignore the advertised bandwidth
+        ///                         claims of routers without measured bandwidth.
+        flag_thresholds: Vec<Threshold>,
+    },
+    /// A consensus computed from the votes of every authority
+    Consensus {
+        /// See section 3.8.1 for details.
+        /// (Only included when the vote is generated with consensus-method 2 or later.)
+        /// [At most once for consensuses; does not occur in votes.]
+        /// [No extra arguments]
+        consensus_method: Option<i32>,
+    },
+}
+impl NetworkStatus {
+    /// Whether this is a vote
+    pub fn is_vote(&self) -> bool {
+        match *self {
+            NetworkStatus::Vote { .. } => true,
+            NetworkStatus::Consensus { .. } => false,
+        }
+    }
 }
 
 /// For this element:
@@ -700,19 +810,1271 @@ struct ConsensusDocument {
 /// DIGESTVAL = DIGESTTYPE = one or more non-space printing characters
 /// other than "=".
 /// Included in consensuses only for method 19 and later.
-struct Package {
-    package_name: String,
-    version: String,
-    url: String,
-    digests: Vec<Digest>,
-}
-
-struct Digest {
-    digest_name: String,
-    digest_type: String,
-}
-struct Entry {}
-struct Router {}
-struct Threshold {}
-struct Param {}
-struct Authority {}
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Package {
+    pub package_name: String,
+    pub version: String,
+    pub url: String,
+    pub digests: Vec<Digest>,
+}
+
+/// DIGEST = DIGESTTYPE "=" DIGESTVAL
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Digest {
+    pub digest_type: String,
+    pub digest_value: String,
+}
+/// A single entry of a "proto" family element, such as "Link=1-4"
+/// Entry ::= Keyword "=" Values
+/// Values ::= Value | Value "," Values
+/// Value ::= Int | Int "-" Int
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Entry {
+    /// The name of the protocol
+    pub name: String,
+    /// Supported versions as inclusive ranges
+    pub versions: Vec<(u32, u32)>,
+}
+/// Threshold = ThresholdKey '=' ThresholdVal
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Threshold {
+    pub key: String,
+    pub value: f64,
+    /// Whether the value was given as a percentage
+    pub percent: bool,
+}
+/// A key=value pair from the "params" line.
+/// The same form is used for each weight on the "bandwidth-weights" line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Param {
+    pub key: String,
+    pub value: i32,
+}
+/// "shared-rand-commit" SP Version SP AlgName SP Identity SP Commit [SP Reveal] NL
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SharedRandCommit {
+    pub version: u32,
+    pub algorithm: String,
+    pub identity: String,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub commit: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_option"))]
+    pub reveal: Option<Vec<u8>>,
+}
+/// A group of items in the authority section, starting with "dir-source"
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Authority {
+    /// A convenient identifier for the authority
+    pub nickname: String,
+    /// An uppercase hex fingerprint of the authority's current (v3 authority)
+    /// identity key
+    #[cfg_attr(feature = "serde", serde(rename = "fingerprint"))]
+    pub identity: String,
+    /// The server's hostname
+    pub address: String,
+    /// The server's current IP address
+    pub ip: Ipv4Addr,
+    pub dir_port: u16,
+    pub or_port: u16,
+    /// How to contact the directory server's administrator.
+    /// Absent for the extra "-legacy" entries of a consensus.
+    pub contact: Option<String>,
+    /// A digest of the vote from the authority that contributed to this
+    /// consensus.
+    /// [Consensus only]
+    pub vote_digest: Option<String>,
+    /// A fingerprint for an obsolete identity key still in use.
+    /// [Vote only]
+    pub legacy_dir_key: Option<String>,
+    /// Whether the authority takes part in the shared random protocol.
+    /// [Vote only]
+    pub shared_rand_participate: bool,
+    /// Commits for the shared random protocol.
+    /// [Vote only]
+    pub shared_rand_commits: Vec<SharedRandCommit>,
+    /// The authority's current key certificate.
+    /// [Vote only]
+    pub key_certificate: Option<KeyCertificate>,
+}
+impl Authority {
+    /// The identity from the "dir-source" line
+    pub fn rsa_identity(&self) -> Option<RsaIdentity> {
+        RsaIdentity::from_hex(&self.identity)
+    }
+}
+/// "w" SP "Bandwidth=" INT [SP "Measured=" INT] [SP "Unmeasured=1"] NL
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Bandwidth {
+    /// An estimate of the bandwidth of this relay in kilobytes per second
+    #[cfg_attr(feature = "serde", serde(rename = "consensus_weight"))]
+    pub bandwidth: u32,
+    /// The bandwidth measured by a bandwidth authority.
+    /// [Vote only]
+    pub measured: Option<u32>,
+    /// Whether the bandwidth is based on fewer than 3 measurements
+    pub unmeasured: bool,
+}
+/// "p" SP ("accept" / "reject") SP PortList NL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortPolicy {
+    /// Whether the ports are accepted or rejected
+    pub accept: bool,
+    /// Inclusive port ranges
+    pub ports: Vec<(u16, u16)>,
+}
+/// "m" SP methods 1*(SP algorithm "=" digest) NL
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MicrodescDigests {
+    /// Consensus methods that the authority believes will produce the digests
+    pub methods: Vec<i32>,
+    /// The microdescriptor digests, by algorithm
+    pub digests: Vec<Digest>,
+}
+/// The routers of a consensus keyed by identity
+#[derive(Debug, Clone)]
+pub struct RouterIndex<'a> {
+    rsa: HashMap<RsaIdentity, &'a Router>,
+    /// Only votes list Ed25519 identities
+    ed25519: HashMap<Ed25519Identity, &'a Router>,
+}
+impl<'a> RouterIndex<'a> {
+    /// The router with the given RSA identity
+    pub fn get(&self, identity: &RsaIdentity) -> Option<&'a Router> {
+        self.rsa.get(identity).cloned()
+    }
+    /// The router with the given Ed25519 identity
+    pub fn get_ed25519(&self, identity: &Ed25519Identity) -> Option<&'a Router> {
+        self.ed25519.get(identity).cloned()
+    }
+    /// The router with an identity written in any of the forms that
+    /// `RsaIdentity::parse` or `Ed25519Identity::parse` accept, such as a
+    /// fingerprint pasted from a relay's log
+    pub fn find(&self, identity: &str) -> Option<&'a Router> {
+        RsaIdentity::parse(identity)
+            .and_then(|identity| self.get(&identity))
+            .or_else(|| {
+                Ed25519Identity::parse(identity).and_then(|identity| self.get_ed25519(&identity))
+            })
+    }
+    /// Number of routers with a well-formed RSA identity
+    pub fn len(&self) -> usize {
+        self.rsa.len()
+    }
+    /// Whether there are no routers
+    pub fn is_empty(&self) -> bool {
+        self.rsa.is_empty()
+    }
+}
+/// A router status entry, starting with an "r" line
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Router {
+    /// The OR's nickname
+    pub nickname: String,
+    /// A hash of its identity key, encoded in base64, with trailing equals
+    /// sign(s) removed
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "fingerprint", with = "serialization::fingerprint")
+    )]
+    pub identity: String,
+    /// A hash of its most recent descriptor as signed, encoded in base64.
+    /// Absent in microdesc-flavored consensuses.
+    pub digest: Option<String>,
+    /// The publication time of its most recent descriptor
+    pub publication: Timestamp,
+    /// Its current IP address
+    pub ip: Ipv4Addr,
+    pub or_port: u16,
+    /// Its current directory port, or 0 for none
+    pub dir_port: u16,
+    /// Addresses from the "a" lines
+    pub addresses: Vec<SocketAddr>,
+    /// Status flags from the "s" line
+    pub flags: Vec<String>,
+    /// The "v" line, such as "Tor 0.3.2.9"
+    pub version: Option<String>,
+    /// The "pr" line
+    pub protocols: Vec<Entry>,
+    /// The "w" line
+    pub bandwidth: Option<Bandwidth>,
+    /// The "p" line
+    #[cfg_attr(feature = "serde", serde(rename = "exit_policy_summary"))]
+    pub policy: Option<PortPolicy>,
+    /// The "m" lines.
+    /// [Vote only]
+    pub microdesc_digests: Vec<MicrodescDigests>,
+    /// The "m" line of a microdesc-flavored consensus, which is the base64
+    /// sha256 digest of the router's microdescriptor with trailing =s omitted
+    pub microdesc_digest: Option<String>,
+    /// The "id ed25519" line, where `Some(None)` means the relay has no
+    /// ed25519 identity.
+    /// [Vote only]
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            deserialize_with = "serialization::double_option"
+        )
+    )]
+    pub ed25519_id: Option<Option<String>>,
+}
+/// The offsets of the lines that start with the keyword
+fn line_starts<'a>(input: &'a [u8], keyword: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    (0..input.len()).filter(move |&start| {
+        (start == 0 || input[start - 1] == b'\n')
+            && input[start..].starts_with(keyword)
+            && input
+                .get(start + keyword.len())
+                .is_none_or(|byte| *byte == b' ' || *byte == b'\n')
+    })
+}
+
+/// "directory-signature" [SP Algorithm] SP identity SP signing-key-digest NL Signature
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DirectorySignature {
+    /// The digest algorithm, "sha1" if none was given
+    pub algorithm: String,
+    /// Hex digest of the authority identity key of the signing authority
+    #[cfg_attr(feature = "serde", serde(rename = "fingerprint"))]
+    pub identity: String,
+    /// Hex digest of the current authority signing key of the signing authority
+    pub signing_key_digest: String,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::base64_bytes"))]
+    pub signature: Vec<u8>,
+}
+impl DirectorySignature {
+    /// The identity of the signing authority
+    pub fn rsa_identity(&self) -> Option<RsaIdentity> {
+        RsaIdentity::from_hex(&self.identity)
+    }
+}
+/// Everything after the router status entries
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Footer {
+    /// Weights to apply to router bandwidths during path selection.
+    /// [Consensus only]
+    pub bandwidth_weights: Vec<Param>,
+    /// Exactly one for a vote, at least one for a consensus
+    pub signatures: Vec<DirectorySignature>,
+}
+
+/// Who is downloading a consensus, which decides when it fetches the next
+/// one as described in section 5.1 of dir-spec.txt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downloader {
+    /// Directory caches fetch early so that they can serve clients
+    Cache,
+    /// Clients fetch after caches are likely to have the new consensus
+    Client,
+}
+/// The deadlines authorities follow while voting on a consensus, as
+/// described in section 1.4 of dir-spec.txt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VotingSchedule {
+    /// When authorities publish their votes, VoteSeconds + DistSeconds
+    /// before the consensus becomes valid
+    pub voting_starts: Timestamp,
+    /// When authorities fetch the votes they haven't received, halfway
+    /// through the VoteSeconds
+    pub fetch_missing_votes: Timestamp,
+    /// When authorities compute the consensus and publish their signatures,
+    /// DistSeconds before it becomes valid
+    pub voting_ends: Timestamp,
+    /// When authorities fetch the signatures they haven't received, halfway
+    /// through the DistSeconds
+    pub fetch_missing_signatures: Timestamp,
+    /// The valid-after time of the consensus being voted on
+    pub interval_starts: Timestamp,
+}
+
+impl ConsensusDocument {
+    /// Parses a vote or consensus from its raw bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a vote or consensus with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let document = parse_complete_with(input, options)?;
+        options.check_keywords(document.items(), |keyword| NETWORK_STATUS.knows(keyword))?;
+        if !options.allow_malformed_versions {
+            for item in document.items() {
+                if item.keyword() == "client-versions" || item.keyword() == "server-versions" {
+                    parse_versions(&item.args(), true)?;
+                }
+            }
+        }
+        let mut consensus = Self::from_document(&document)?;
+        let input = options.line_endings(input);
+        let signed = signed_consensus(&input)?;
+        consensus.sha1_digest = Some(Sha1::digest(signed).into());
+        consensus.sha256_digest = Some(Sha256::digest(signed).into());
+        // Embedded key certificates are signed over their own bytes, which
+        // the items don't keep
+        let mut starts = line_starts(&input, b"dir-key-certificate-version");
+        for authority in &mut consensus.authorities {
+            if let Some(ref mut certificate) = authority.key_certificate {
+                if let Some(start) = starts.next() {
+                    certificate.digest = Some(certification_digest(&input[start..])?);
+                }
+            }
+        }
+        Ok(consensus)
+    }
+    /// The value of a consensus parameter, if the document sets it
+    pub fn param(&self, key: &str) -> Option<i32> {
+        self.params
+            .iter()
+            .find(|param| param.key == key)
+            .map(|param| param.value)
+    }
+    /// How a relay version compares to the "server-versions" line, or None
+    /// if the authorities hold no opinion
+    pub fn version_status(&self, version: &TorVersion) -> Option<VersionStatus> {
+        self.server_versions
+            .as_ref()
+            .map(|recommended| version.status(recommended))
+    }
+    /// How a client version compares to the "client-versions" line, or None
+    /// if the authorities hold no opinion
+    pub fn client_version_status(&self, version: &TorVersion) -> Option<VersionStatus> {
+        self.client_versions
+            .as_ref()
+            .map(|recommended| version.status(recommended))
+    }
+    /// Relays running a Tor version that the "server-versions" line doesn't
+    /// recommend, so that their operators can be told to upgrade
+    pub fn unrecommended_relays(&self) -> Vec<(&Router, VersionStatus)> {
+        self.routers
+            .iter()
+            .filter_map(|router| {
+                let status = self.version_status(&router.tor_version()?)?;
+                if status == VersionStatus::Recommended {
+                    None
+                } else {
+                    Some((router, status))
+                }
+            })
+            .collect()
+    }
+    /// Indexes the routers by identity for looking relays up repeatedly
+    pub fn index(&self) -> RouterIndex<'_> {
+        let mut index = RouterIndex {
+            rsa: HashMap::with_capacity(self.routers.len()),
+            ed25519: HashMap::new(),
+        };
+        for router in &self.routers {
+            if let Some(identity) = router.rsa_identity() {
+                index.rsa.insert(identity, router);
+            }
+            if let Some(identity) = router.ed25519_identity() {
+                index.ed25519.insert(identity, router);
+            }
+        }
+        index
+    }
+    /// Whether the document may be used at `now`, between its valid-after
+    /// and valid-until times
+    pub fn is_live(&self, now: Timestamp) -> bool {
+        self.valid_after <= now && now <= self.valid_until
+    }
+    /// Whether the document is within `REASONABLY_LIVE_TIME` of being live,
+    /// which is close enough for clients to bootstrap from it
+    pub fn is_reasonably_live(&self, now: Timestamp) -> bool {
+        self.valid_after - REASONABLY_LIVE_TIME <= now
+            && now <= self.valid_until + REASONABLY_LIVE_TIME
+    }
+    /// The span of time in which to download the next consensus, from
+    /// section 5.1 of dir-spec.txt.
+    /// The interval is the voting interval, from valid-after to
+    /// fresh-until. Caches pick a time in the first half of the interval
+    /// after the consensus stops being fresh. Clients start three quarters
+    /// of an interval after fresh-until and stop 7/8 of the way from there
+    /// to valid-until.
+    pub fn download_window(&self, downloader: Downloader) -> (Timestamp, Timestamp) {
+        let interval = self
+            .fresh_until
+            .unix()
+            .saturating_sub(self.valid_after.unix());
+        match downloader {
+            Downloader::Cache => (self.fresh_until, self.fresh_until + interval / 2),
+            Downloader::Client => {
+                let start = self.fresh_until + interval * 3 / 4;
+                let remaining = self.valid_until.unix().saturating_sub(start.unix());
+                (start, start + remaining * 7 / 8)
+            }
+        }
+    }
+    /// The time to download the next consensus, chosen uniformly from the
+    /// download window using a random number from the caller
+    pub fn next_download(&self, downloader: Downloader, random: u64) -> Timestamp {
+        let (start, end) = self.download_window(downloader);
+        start + random % (end.unix() - start.unix() + 1)
+    }
+    /// The voting deadlines given by the "voting-delay" line.
+    /// A vote is for the consensus starting at its own valid-after time,
+    /// while a consensus gives the schedule of the next one, which becomes
+    /// valid when this one stops being fresh.
+    pub fn voting_schedule(&self) -> VotingSchedule {
+        let interval_starts = if self.vote_status.is_vote() {
+            self.valid_after
+        } else {
+            self.fresh_until
+        };
+        let vote_seconds = self.vote_seconds.max(0) as u64;
+        let dist_seconds = self.dist_seconds.max(0) as u64;
+        VotingSchedule {
+            voting_starts: interval_starts - vote_seconds - dist_seconds,
+            fetch_missing_votes: interval_starts - dist_seconds - vote_seconds / 2,
+            voting_ends: interval_starts - dist_seconds,
+            fetch_missing_signatures: interval_starts - dist_seconds / 2,
+            interval_starts,
+        }
+    }
+    /// Builds a vote or consensus from a parsed document.
+    /// Votes and consensuses share this parser, and items that may only
+    /// occur in one of them are rejected in the other. Malformed version
+    /// entries are skipped, as with the default options.
+    pub fn from_document(document: &Document) -> Result<Self, Error> {
+        let items = document.items();
+        let flavor = match items.first() {
+            Some(item) if item.keyword() == "network-status-version" => {
+                Flavor::from_version_item(item)?
+            }
+            _ => return Err(Error::MissingItem("network-status-version")),
+        };
+        // Which items are allowed everywhere else depends on the vote status
+        let is_vote = match items.iter().find(|item| item.keyword() == "vote-status") {
+            Some(item) => match item.arguments().as_str() {
+                "vote" => true,
+                "consensus" => false,
+                _ => {
+                    return Err(Error::invalid(
+                        "vote-status",
+                        "expected \"vote\" or \"consensus\"",
+                    ))
+                }
+            },
+            None => return Err(Error::MissingItem("vote-status")),
+        };
+        if is_vote && flavor != Flavor::Ns {
+            return Err(Error::invalid(
+                "network-status-version",
+                "votes are always ns-flavored",
+            ));
+        }
+        // Split the document into its sections
+        let authorities_start = items
+            .iter()
+            .position(|item| item.keyword() == "dir-source")
+            .ok_or(Error::MissingItem("dir-source"))?;
+        let footer_start = items[authorities_start..]
+            .iter()
+            .position(|item| {
+                item.keyword() == "directory-footer" || item.keyword() == "directory-signature"
+            })
+            .map(|position| position + authorities_start)
+            .ok_or(Error::MissingItem("directory-signature"))?;
+        let routers_start = items[authorities_start..footer_start]
+            .iter()
+            .position(|item| item.keyword() == "r")
+            .map(|position| position + authorities_start)
+            .unwrap_or(footer_start);
+
+        let mut document = Self::from_preamble(&items[..authorities_start], is_vote, flavor)?;
+        for group in groups(&items[authorities_start..routers_start], "dir-source") {
+            let authority = document.parse_authority(group)?;
+            document.authorities.push(authority);
+        }
+        for group in groups(&items[routers_start..footer_start], "r") {
+            document
+                .routers
+                .push(Router::from_items(group, is_vote, flavor)?);
+        }
+        document.footer = Footer::from_items(&items[footer_start..], is_vote, flavor)?;
+        Ok(document)
+    }
+    /// Builds the document from its preamble, leaving the other sections empty
+    fn from_preamble(items: &[Item], is_vote: bool, flavor: Flavor) -> Result<Self, Error> {
+        let mut network_status_version = None;
+        let mut consensus_methods = None;
+        let mut consensus_method = None;
+        let mut published = None;
+        let mut valid_after = None;
+        let mut fresh_until = None;
+        let mut valid_until = None;
+        let mut voting_delay = None;
+        let mut client_versions = None;
+        let mut server_versions = None;
+        let mut package = Vec::new();
+        let mut known_flags = None;
+        let mut flag_thresholds = Vec::new();
+        let mut recommended_client_protocols = Vec::new();
+        let mut recommended_relay_protocols = Vec::new();
+        let mut required_client_protocols = Vec::new();
+        let mut required_relay_protocols = Vec::new();
+        let mut params = Vec::new();
+        let mut shared_rand_previous_value = None;
+        let mut shared_rand_current_value = None;
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "network-status-version" => network_status_version = Some(arguments.arg(0)?),
+                "vote-status" => (),
+                "consensus-methods" => {
+                    vote_only(item, is_vote)?;
+                    consensus_methods = Some(arguments.all()?);
+                }
+                "consensus-method" => {
+                    consensus_only(item, is_vote)?;
+                    consensus_method = Some(arguments.arg(0)?);
+                }
+                "published" => {
+                    vote_only(item, is_vote)?;
+                    published = Some(arguments.arg_datetime(0, 1)?);
+                }
+                "valid-after" => valid_after = Some(arguments.arg_datetime(0, 1)?),
+                "fresh-until" => fresh_until = Some(arguments.arg_datetime(0, 1)?),
+                "valid-until" => valid_until = Some(arguments.arg_datetime(0, 1)?),
+                "voting-delay" => voting_delay = Some((arguments.arg(0)?, arguments.arg(1)?)),
+                "client-versions" => client_versions = Some(parse_versions(&arguments, false)?),
+                "server-versions" => server_versions = Some(parse_versions(&arguments, false)?),
+                "package" => package.push(parse_package(&arguments)?),
+                "known-flags" => known_flags = Some(arguments.all()?),
+                "flag-thresholds" => {
+                    vote_only(item, is_vote)?;
+                    flag_thresholds = (0..arguments.len())
+                        .map(|index| parse_threshold(&arguments, index))
+                        .collect::<Result<_, _>>()?;
+                }
+                "recommended-client-protocols" => {
+                    recommended_client_protocols = parse_entries(&item.args())?
+                }
+                "recommended-relay-protocols" => {
+                    recommended_relay_protocols = parse_entries(&item.args())?
+                }
+                "required-client-protocols" => {
+                    required_client_protocols = parse_entries(&item.args())?
+                }
+                "required-relay-protocols" => {
+                    required_relay_protocols = parse_entries(&item.args())?
+                }
+                "params" => params = parse_params(&item.args())?,
+                "shared-rand-previous-value" => {
+                    shared_rand_previous_value = Some(parse_shared_rand(&arguments)?)
+                }
+                "shared-rand-current-value" => {
+                    shared_rand_current_value = Some(parse_shared_rand(&arguments)?)
+                }
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        NETWORK_STATUS_PREAMBLE.check_counts(items, is_vote, &[])?;
+        let vote_status = if is_vote {
+            NetworkStatus::Vote {
+                consensus_methods: consensus_methods.unwrap_or_else(|| vec![1]),
+                published: published.ok_or(Error::MissingItem("published"))?,
+                flag_thresholds,
+            }
+        } else {
+            NetworkStatus::Consensus { consensus_method }
+        };
+        let (vote_seconds, dist_seconds) =
+            voting_delay.ok_or(Error::MissingItem("voting-delay"))?;
+        Ok(ConsensusDocument {
+            network_status_version: network_status_version
+                .ok_or(Error::MissingItem("network-status-version"))?,
+            flavor,
+            vote_status,
+            valid_after: valid_after.ok_or(Error::MissingItem("valid-after"))?,
+            fresh_until: fresh_until.ok_or(Error::MissingItem("fresh-until"))?,
+            valid_until: valid_until.ok_or(Error::MissingItem("valid-until"))?,
+            vote_seconds,
+            dist_seconds,
+            client_versions,
+            server_versions,
+            package,
+            known_flags: known_flags.ok_or(Error::MissingItem("known-flags"))?,
+            recommended_client_protocols,
+            recommended_relay_protocols,
+            required_client_protocols,
+            required_relay_protocols,
+            params,
+            shared_rand_previous_value,
+            shared_rand_current_value,
+            authorities: Vec::new(),
+            routers: Vec::new(),
+            footer: Footer::default(),
+            sha1_digest: None,
+            sha256_digest: None,
+        })
+    }
+    /// Parses one group of the authority section.
+    /// The shared random values of a vote live in its authority section,
+    /// so those are stored on the document itself.
+    fn parse_authority(&mut self, items: &[Item]) -> Result<Authority, Error> {
+        let is_vote = self.vote_status.is_vote();
+        let dir_source = &items[0];
+        let arguments = dir_source.args();
+        let mut authority = Authority {
+            nickname: arguments.arg(0)?,
+            identity: arguments.arg(1)?,
+            address: arguments.arg(2)?,
+            ip: arguments.arg(3)?,
+            dir_port: arguments.arg(4)?,
+            or_port: arguments.arg(5)?,
+            contact: None,
+            vote_digest: None,
+            legacy_dir_key: None,
+            shared_rand_participate: false,
+            shared_rand_commits: Vec::new(),
+            key_certificate: None,
+        };
+        let mut certificate_start = None;
+        for (index, item) in items.iter().enumerate().skip(1) {
+            let arguments = item.args();
+            match item.keyword() {
+                "contact" => authority.contact = Some(item.arguments()),
+                "vote-digest" => {
+                    consensus_only(item, is_vote)?;
+                    authority.vote_digest = Some(arguments.arg(0)?);
+                }
+                "legacy-dir-key" => {
+                    vote_only(item, is_vote)?;
+                    authority.legacy_dir_key = Some(arguments.arg(0)?);
+                }
+                "shared-rand-participate" => {
+                    vote_only(item, is_vote)?;
+                    authority.shared_rand_participate = true;
+                }
+                "shared-rand-commit" => {
+                    vote_only(item, is_vote)?;
+                    authority
+                        .shared_rand_commits
+                        .push(parse_shared_rand_commit(&arguments)?);
+                }
+                "shared-rand-previous-value" => {
+                    vote_only(item, is_vote)?;
+                    self.shared_rand_previous_value = Some(parse_shared_rand(&arguments)?);
+                }
+                "shared-rand-current-value" => {
+                    vote_only(item, is_vote)?;
+                    self.shared_rand_current_value = Some(parse_shared_rand(&arguments)?);
+                }
+                // The key certificate runs until the end of the group
+                "dir-key-certificate-version" => {
+                    vote_only(item, is_vote)?;
+                    certificate_start = Some(index);
+                    break;
+                }
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        if let Some(start) = certificate_start {
+            authority.key_certificate = Some(KeyCertificate::from_items(&items[start..])?);
+        }
+        NETWORK_STATUS_AUTHORITY.check_counts(items, is_vote, &[])?;
+        Ok(authority)
+    }
+}
+impl PortPolicy {
+    /// Whether the summary allows exiting to the given port
+    pub fn allows(&self, port: u16) -> bool {
+        let listed = self
+            .ports
+            .iter()
+            .any(|&(low, high)| low <= port && port <= high);
+        listed == self.accept
+    }
+}
+impl Router {
+    /// Whether the relay has the given flag
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|other| other == flag)
+    }
+    /// Whether the relay's exit policy summary allows the given port.
+    /// Relays without a summary, as in microdesc consensuses, are
+    /// treated as rejecting every port.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.policy
+            .as_ref()
+            .is_some_and(|policy| policy.allows(port))
+    }
+    /// The identity from the "r" line
+    pub fn rsa_identity(&self) -> Option<RsaIdentity> {
+        RsaIdentity::from_base64(&self.identity)
+    }
+    /// The identity from the "id ed25519" line of a vote, if the relay has
+    /// one
+    pub fn ed25519_identity(&self) -> Option<Ed25519Identity> {
+        match self.ed25519_id {
+            Some(Some(ref identity)) => Ed25519Identity::from_base64(identity),
+            _ => None,
+        }
+    }
+    /// The Tor version from the "v" line, if the relay runs Tor
+    pub fn tor_version(&self) -> Option<TorVersion> {
+        self.version.as_ref().and_then(|version| {
+            if version.starts_with("Tor ") {
+                TorVersion::parse(version)
+            } else {
+                None
+            }
+        })
+    }
+    /// Builds a router status entry from the items of its group
+    pub(crate) fn from_items(items: &[Item], is_vote: bool, flavor: Flavor) -> Result<Self, Error> {
+        let r = &items[0];
+        let arguments = r.args();
+        let identity = base64_digest(&arguments, 1, 20)?;
+        // Microdesc "r" lines have no descriptor digest, so the remaining
+        // arguments start one place earlier
+        let (digest, rest) = match flavor {
+            Flavor::Ns => (Some(base64_digest(&arguments, 2, 20)?), 3),
+            Flavor::Microdesc => (None, 2),
+        };
+        let mut router = Router {
+            nickname: arguments.arg(0)?,
+            identity,
+            digest,
+            publication: arguments.arg_datetime(rest, rest + 1)?,
+            ip: arguments.arg(rest + 2)?,
+            or_port: arguments.arg(rest + 3)?,
+            dir_port: arguments.arg(rest + 4)?,
+            addresses: Vec::new(),
+            flags: Vec::new(),
+            version: None,
+            protocols: Vec::new(),
+            bandwidth: None,
+            policy: None,
+            microdesc_digests: Vec::new(),
+            microdesc_digest: None,
+            ed25519_id: None,
+        };
+        for item in &items[1..] {
+            let arguments = item.args();
+            match item.keyword() {
+                "a" => router.addresses.push(arguments.arg(0)?),
+                "s" => router.flags = arguments.all()?,
+                "v" => router.version = Some(item.arguments()),
+                "pr" => router.protocols = parse_entries(&item.args())?,
+                "w" => router.bandwidth = Some(parse_bandwidth(&arguments, is_vote)?),
+                "p" => router.policy = Some(parse_port_policy(&arguments)?),
+                "m" if is_vote => router
+                    .microdesc_digests
+                    .push(parse_microdesc_digests(&arguments)?),
+                "m" if flavor == Flavor::Microdesc => {
+                    router.microdesc_digest = Some(base64_digest(&arguments, 0, 32)?)
+                }
+                "m" => {
+                    return Err(Error::invalid(
+                        "m",
+                        "only votes and microdesc consensuses have m lines",
+                    ))
+                }
+                "id" => {
+                    vote_only(item, is_vote)?;
+                    if arguments.str(0)? != "ed25519" {
+                        return Err(arguments.error(0, "expected ed25519"));
+                    }
+                    router.ed25519_id = Some(match arguments.arg(1)? {
+                        ref none if none == "none" => None,
+                        key => Some(key),
+                    });
+                }
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        NETWORK_STATUS_ROUTER.check_counts(items, is_vote, &[])?;
+        Ok(router)
+    }
+}
+impl Footer {
+    /// Builds the footer from the items after the router status entries
+    fn from_items(items: &[Item], is_vote: bool, flavor: Flavor) -> Result<Self, Error> {
+        let mut footer = Footer::default();
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "directory-footer" => (),
+                "bandwidth-weights" => {
+                    consensus_only(item, is_vote)?;
+                    footer.bandwidth_weights = parse_params(&item.args())?;
+                }
+                "directory-signature" => {
+                    let signature = parse_directory_signature(item, &arguments, 0)?;
+                    // "ns"-flavored consensus documents must contain only
+                    // sha1 signatures
+                    if !is_vote && flavor == Flavor::Ns && signature.algorithm != "sha1" {
+                        return Err(Error::invalid(
+                            item.keyword(),
+                            format!(
+                                "{} signature in an ns-flavored consensus",
+                                signature.algorithm
+                            ),
+                        ));
+                    }
+                    footer.signatures.push(signature);
+                }
+                "r" | "dir-source" => {
+                    return Err(Error::UnexpectedItem(item.keyword().to_string()))
+                }
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        NETWORK_STATUS_FOOTER.check_counts(items, is_vote, &[])?;
+        Ok(footer)
+    }
+}
+
+/// "directory-signature" [SP Algorithm] SP identity SP signing-key-digest NL Signature,
+/// with the arguments beginning at `start`
+pub(crate) fn parse_directory_signature(
+    item: &Item,
+    arguments: &Arguments,
+    start: usize,
+) -> Result<DirectorySignature, Error> {
+    // The algorithm is optional and comes first
+    let (algorithm, rest) = if arguments.len() > start + 2 {
+        (arguments.arg(start)?, start + 1)
+    } else {
+        ("sha1".to_string(), start)
+    };
+    Ok(DirectorySignature {
+        algorithm,
+        identity: arguments.arg(rest)?,
+        signing_key_digest: arguments.arg(rest + 1)?,
+        signature: item.object("SIGNATURE")?,
+    })
+}
+/// Splits a section into groups that each begin with the given keyword
+fn groups<'a>(items: &'a [Item], keyword: &str) -> Vec<&'a [Item]> {
+    let starts: Vec<usize> = items
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| item.keyword() == keyword)
+        .map(|(index, _)| index)
+        .collect();
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(Some(&items.len())))
+        .map(|(&start, &end)| &items[start..end])
+        .collect()
+}
+/// Rejects items that may only appear in votes when parsing a consensus
+fn vote_only(item: &Item, is_vote: bool) -> Result<(), Error> {
+    if is_vote {
+        Ok(())
+    } else {
+        Err(Error::VoteOnly(item.keyword().to_string()))
+    }
+}
+/// Rejects items that may only appear in consensuses when parsing a vote
+fn consensus_only(item: &Item, is_vote: bool) -> Result<(), Error> {
+    if is_vote {
+        Err(Error::ConsensusOnly(item.keyword().to_string()))
+    } else {
+        Ok(())
+    }
+}
+/// Splits a comma-separated list of versions, failing on malformed or
+/// unsorted entries only when `strict`
+fn parse_versions(arguments: &Arguments, strict: bool) -> Result<Vec<TorVersion>, Error> {
+    let mut versions: Vec<(usize, TorVersion)> = Vec::new();
+    for index in 0..arguments.len() {
+        for version in arguments.str(index)?.split(',') {
+            if version.is_empty() {
+                continue;
+            }
+            match TorVersion::parse(version) {
+                Some(parsed) => versions.push((index, parsed)),
+                None if strict => {
+                    return Err(arguments.error(index, format!("malformed version \"{}\"", version)))
+                }
+                None => (),
+            }
+        }
+    }
+    if strict {
+        if let Some(pair) = versions.windows(2).find(|pair| pair[0].1 >= pair[1].1) {
+            return Err(arguments.error(
+                pair[1].0,
+                format!("{} is listed before {}", pair[0].1, pair[1].1),
+            ));
+        }
+    }
+    Ok(versions.into_iter().map(|(_, version)| version).collect())
+}
+/// "package" SP PACKAGENAME SP VERSION SP URL SP DIGESTS NL
+fn parse_package(arguments: &Arguments) -> Result<Package, Error> {
+    Ok(Package {
+        package_name: arguments.arg(0)?,
+        version: arguments.arg(1)?,
+        url: arguments.arg(2)?,
+        digests: (3..arguments.len())
+            .map(|index| parse_digest(arguments, index))
+            .collect::<Result<_, _>>()?,
+    })
+}
+/// Checks that the token at `index` is a base64 digest of `length` bytes
+/// and keeps it in its encoded form
+fn base64_digest(arguments: &Arguments, index: usize, length: usize) -> Result<String, Error> {
+    arguments.arg_base64_digest(index, length)?;
+    arguments.arg(index)
+}
+/// DIGEST = DIGESTTYPE "=" DIGESTVAL
+fn parse_digest(arguments: &Arguments, index: usize) -> Result<Digest, Error> {
+    let (digest_type, digest_value) = arguments.arg_pair(index)?;
+    Ok(Digest {
+        digest_type: digest_type.to_string(),
+        digest_value: digest_value.to_string(),
+    })
+}
+/// Threshold = ThresholdKey '=' ThresholdVal
+pub(crate) fn parse_threshold(arguments: &Arguments, index: usize) -> Result<Threshold, Error> {
+    let (key, value) = arguments.arg_pair(index)?;
+    let percent = value.ends_with('%');
+    Ok(Threshold {
+        key: key.to_string(),
+        value: value
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| arguments.error(index, format!("malformed threshold \"{}\"", value)))?,
+        percent,
+    })
+}
+/// Parses key=value pairs with 32-bit integer values
+fn parse_params(arguments: &Arguments) -> Result<Vec<Param>, Error> {
+    arguments
+        .kv_pairs()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, (key, value))| {
+            Ok(Param {
+                key: key.to_string(),
+                value: value.parse().map_err(|_| {
+                    arguments.error(index, format!("malformed value \"{}\"", value))
+                })?,
+            })
+        })
+        .collect()
+}
+/// Entries ::= Entry | Entry SP Entries
+pub(crate) fn parse_entries(arguments: &Arguments) -> Result<Vec<Entry>, Error> {
+    arguments
+        .kv_pairs()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, values))| {
+            Ok(Entry {
+                name: name.to_string(),
+                versions: parse_range_list(values).map_err(|range| {
+                    arguments.error(index, format!("malformed range \"{}\"", range))
+                })?,
+            })
+        })
+        .collect()
+}
+/// Parses a comma-separated list of integers and inclusive ranges,
+/// returning the first malformed range if there is one
+pub(crate) fn parse_range_list<T: ::std::str::FromStr + Copy>(
+    ranges: &str,
+) -> Result<Vec<(T, T)>, &str> {
+    ranges
+        .split(',')
+        .map(|range| {
+            let mut bounds = range.splitn(2, '-').map(|bound| bound.parse::<T>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(low)), None) => Ok((low, low)),
+                (Some(Ok(low)), Some(Ok(high))) => Ok((low, high)),
+                _ => Err(range),
+            }
+        })
+        .collect()
+}
+/// NumReveals SP Value
+fn parse_shared_rand(arguments: &Arguments) -> Result<(i32, Vec<u8>), Error> {
+    Ok((arguments.arg(0)?, arguments.arg_base64(1)?))
+}
+/// Version SP AlgName SP Identity SP Commit [SP Reveal]
+fn parse_shared_rand_commit(arguments: &Arguments) -> Result<SharedRandCommit, Error> {
+    Ok(SharedRandCommit {
+        version: arguments.arg(0)?,
+        algorithm: arguments.arg(1)?,
+        identity: arguments.arg(2)?,
+        commit: arguments.arg_base64(3)?,
+        reveal: if arguments.len() > 4 {
+            Some(arguments.arg_base64(4)?)
+        } else {
+            None
+        },
+    })
+}
+/// "w" SP "Bandwidth=" INT [SP "Measured=" INT] [SP "Unmeasured=1"] NL
+fn parse_bandwidth(arguments: &Arguments, is_vote: bool) -> Result<Bandwidth, Error> {
+    let mut bandwidth = None;
+    let mut measured = None;
+    let mut unmeasured = false;
+    for index in 0..arguments.len() {
+        let (key, value) = arguments.arg_pair(index)?;
+        let parse = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| arguments.error(index, format!("malformed value \"{}\"", value)))
+        };
+        match key {
+            "Bandwidth" => bandwidth = Some(parse()?),
+            "Measured" => {
+                if !is_vote {
+                    return Err(Error::VoteOnly("w Measured".to_string()));
+                }
+                measured = Some(parse()?);
+            }
+            "Unmeasured" => unmeasured = parse()? == 1,
+            // Clients MUST ignore keywords they do not recognize
+            _ => (),
+        }
+    }
+    Ok(Bandwidth {
+        bandwidth: bandwidth.ok_or_else(|| Error::invalid("w", "missing Bandwidth="))?,
+        measured,
+        unmeasured,
+    })
+}
+/// "p" SP ("accept" / "reject") SP PortList NL
+pub(crate) fn parse_port_policy(arguments: &Arguments) -> Result<PortPolicy, Error> {
+    let accept = match arguments.str(0)? {
+        "accept" => true,
+        "reject" => false,
+        _ => return Err(arguments.error(0, "expected accept or reject")),
+    };
+    let ports = parse_range_list(arguments.str(1)?)
+        .map_err(|range| arguments.error(1, format!("malformed range \"{}\"", range)))?;
+    Ok(PortPolicy { accept, ports })
+}
+/// "m" SP methods 1*(SP algorithm "=" digest) NL
+fn parse_microdesc_digests(arguments: &Arguments) -> Result<MicrodescDigests, Error> {
+    let methods = arguments
+        .str(0)?
+        .split(',')
+        .map(|method| method.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| arguments.error(0, "malformed consensus methods"))?;
+    let digests = (1..arguments.len())
+        .map(|index| {
+            let digest = parse_digest(arguments, index)?;
+            // Microdescriptors are named by their sha256 digest
+            if digest.digest_type == "sha256"
+                && decode_base64(&digest.digest_value).map(|value| value.len()) != Some(32)
+            {
+                return Err(arguments.error(
+                    index,
+                    format!(
+                        "expected a 32-byte base64 digest, found \"{}\"",
+                        digest.digest_value
+                    ),
+                ));
+            }
+            Ok(digest)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(MicrodescDigests { methods, digests })
+}
+
+/// Test
+#[test]
+fn test_parse_consensus() {
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/barebones.consensus"))
+        .expect("failed to parse consensus");
+    assert!(!consensus.vote_status.is_vote());
+    assert_eq!(consensus.authorities.len(), 3);
+    assert_eq!(consensus.routers.len(), 3);
+    assert_eq!(
+        consensus.routers[0].publication,
+        Timestamp::parse("2018-02-07 20:43:31").unwrap()
+    );
+    assert_eq!(consensus.footer.signatures.len(), 2);
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/consensus"))
+        .expect("failed to parse consensus");
+    assert_eq!(consensus.routers.len(), 6218);
+}
+
+/// Test
+#[test]
+fn test_consensus_timeline() {
+    let consensus =
+        ConsensusDocument::parse(include_bytes!("../test/barebones.consensus")).unwrap();
+    let time = |timestamp| Timestamp::parse(timestamp).unwrap();
+    assert_eq!(consensus.valid_after, time("2018-02-08 07:00:00"));
+    assert!(consensus.is_live(time("2018-02-08 09:59:59")));
+    assert!(!consensus.is_live(time("2018-02-08 10:00:01")));
+    assert!(consensus.is_reasonably_live(time("2018-02-09 10:00:00")));
+    assert!(!consensus.is_reasonably_live(time("2018-02-07 06:59:59")));
+    assert_eq!(
+        consensus.download_window(Downloader::Client),
+        (time("2018-02-08 08:45:00"), time("2018-02-08 09:50:37"))
+    );
+    assert_eq!(
+        consensus.download_window(Downloader::Cache),
+        (time("2018-02-08 08:00:00"), time("2018-02-08 08:30:00"))
+    );
+    let next = consensus.next_download(Downloader::Cache, 12345);
+    assert!(consensus.fresh_until <= next && next <= time("2018-02-08 08:30:00"));
+    let schedule = consensus.voting_schedule();
+    assert_eq!(schedule.voting_starts, time("2018-02-08 07:50:00"));
+    assert_eq!(schedule.fetch_missing_votes, time("2018-02-08 07:52:30"));
+    assert_eq!(schedule.voting_ends, time("2018-02-08 07:55:00"));
+    assert_eq!(
+        schedule.fetch_missing_signatures,
+        time("2018-02-08 07:57:30")
+    );
+    // Dates must be real and written exactly
+    let broken = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus")).replace(
+        "valid-after 2018-02-08 07:00:00",
+        "valid-after 2018-02-30 07:00:00",
+    );
+    assert!(ConsensusDocument::parse(broken.as_bytes()).is_err());
+}
+
+/// Test
+#[test]
+fn test_recommended_versions() {
+    let consensus =
+        ConsensusDocument::parse(include_bytes!("../test/barebones.consensus")).unwrap();
+    assert!(consensus.unrecommended_relays().is_empty());
+    let outdated = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus"))
+        .replace("v Tor 0.3.1.9", "v Tor 0.3.0.13");
+    let outdated = ConsensusDocument::parse(outdated.as_bytes()).unwrap();
+    let unrecommended = outdated.unrecommended_relays();
+    assert_eq!(unrecommended.len(), 1);
+    assert_eq!(unrecommended[0].0.nickname, "seele");
+    assert_eq!(unrecommended[0].1, VersionStatus::Unrecommended);
+    // Malformed and unsorted lists are only rejected on request
+    let unsorted = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus")).replace(
+        "server-versions 0.2.5.16,0.2.5.17",
+        "server-versions 0.2.5.17,0.2.5.16",
+    );
+    let strict = ParseOptions {
+        allow_malformed_versions: false,
+        ..ParseOptions::default()
+    };
+    assert!(ConsensusDocument::parse_with(unsorted.as_bytes(), &strict).is_err());
+    let unsorted = ConsensusDocument::parse(unsorted.as_bytes()).unwrap();
+    assert_eq!(unsorted.server_versions.unwrap().len(), 9);
+    let malformed = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus"))
+        .replace("server-versions 0.2.5.16", "server-versions 0.2.x,0.2.5.16");
+    assert!(ConsensusDocument::parse_with(malformed.as_bytes(), &strict).is_err());
+    let malformed = ConsensusDocument::parse(malformed.as_bytes()).unwrap();
+    assert_eq!(malformed.server_versions, consensus.server_versions);
+}
+
+/// Test
+#[test]
+fn test_router_identities() {
+    let consensus =
+        ConsensusDocument::parse(include_bytes!("../test/barebones.consensus")).unwrap();
+    let index = consensus.index();
+    assert_eq!(index.len(), 3);
+    let calyx = index
+        .find("$0011BD2485AD45D984EC4159C88FC066E5E3300E")
+        .expect("relay not found");
+    assert_eq!(calyx.nickname, "CalyxInstitute14");
+    assert_eq!(
+        index.get(&calyx.rsa_identity().unwrap()).unwrap().nickname,
+        calyx.nickname
+    );
+    assert!(index
+        .find("0011 BD24 85AD 45D9 84EC 4159 C88F C066 E5E3 300F")
+        .is_none());
+    assert_eq!(
+        consensus.authorities[0].rsa_identity().unwrap().to_hex(),
+        consensus.authorities[0].identity
+    );
+    // Identities and descriptor digests must be 20-byte base64 digests
+    let short = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus")).replace(
+        "r seele AAoQ1DAR6kkoo19hBAX5K0QztNw GqnDAWC9ufcMs1Nm2vD/Fm5zEXc",
+        "r seele AAAA BBBB",
+    );
+    match ConsensusDocument::parse(short.as_bytes()) {
+        Err(Error::InvalidArgument { keyword, index, .. }) => {
+            assert_eq!((keyword.as_str(), index), ("r", 1))
+        }
+        other => panic!("accepted a short identity: {:?}", other.map(|_| ())),
+    }
+}
+
+/// Test
+#[test]
+fn test_router_policies() {
+    let consensus =
+        ConsensusDocument::parse(include_bytes!("../test/barebones.consensus")).unwrap();
+    let exit = &consensus.routers[2];
+    assert!(exit.has_flag("Exit") && !consensus.routers[0].has_flag("Exit"));
+    assert!(exit.allows_port(443) && exit.allows_port(995) && !exit.allows_port(25));
+    assert!(!consensus.routers[0].allows_port(443));
+}
+
+/// Test
+#[test]
+fn test_parse_microdesc_consensus() {
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/barebones.microdesc"))
+        .expect("failed to parse consensus");
+    assert_eq!(consensus.flavor, Flavor::Microdesc);
+    assert!(consensus.routers[0].digest.is_none());
+    assert!(consensus.routers[0].microdesc_digest.is_some());
+    let digest = consensus.routers[0].microdesc_digest.as_ref().unwrap();
+    let short = String::from_utf8_lossy(include_bytes!("../test/barebones.microdesc"))
+        .replace(&format!("m {}", digest), "m AAAA");
+    assert!(ConsensusDocument::parse(short.as_bytes()).is_err());
+    assert_eq!(consensus.footer.signatures[0].algorithm, "sha256");
+    // sha256 signatures are only allowed in the microdesc flavor
+    let ns = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus"))
+        .replace("directory-signature ", "directory-signature sha256 ");
+    assert!(ConsensusDocument::parse(ns.as_bytes()).is_err());
+}
+
+/// Test
+#[test]
+fn test_parse_vote() {
+    let vote = ConsensusDocument::parse(include_bytes!("../test/barebones.vote"))
+        .expect("failed to parse vote");
+    match vote.vote_status {
+        NetworkStatus::Vote {
+            ref flag_thresholds,
+            ..
+        } => assert_eq!(flag_thresholds.len(), 9),
+        NetworkStatus::Consensus { .. } => panic!("parsed a vote as a consensus"),
+    }
+    let certificate = vote.authorities[0].key_certificate.as_ref().unwrap();
+    assert!(certificate.digest.is_some());
+    assert_eq!(
+        vote.routers[0].bandwidth.as_ref().unwrap().measured,
+        Some(52)
+    );
+    assert_eq!(vote.routers[0].ed25519_id, Some(None));
+    let short = String::from_utf8_lossy(include_bytes!("../test/barebones.vote")).replace(
+        "sha256=POO00Yvkh9wkXgwoK4t2D4iv45SwdC7GZWn34yNfNao",
+        "sha256=AAAA",
+    );
+    assert!(ConsensusDocument::parse(short.as_bytes()).is_err());
+    // Vote-only items are rejected in a consensus
+    let consensus = String::from_utf8_lossy(include_bytes!("../test/barebones.vote"))
+        .replace("vote-status vote", "vote-status consensus");
+    assert!(ConsensusDocument::parse(consensus.as_bytes()).is_err());
+}
@@ -0,0 +1,465 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use arguments::Arguments;
+use consensus::{parse_entries, parse_port_policy, Entry, PortPolicy};
+use document::{encode_base64, parse_complete_with, split_documents, Item};
+use error::Error;
+use identity::RsaIdentity;
+use options::ParseOptions;
+use rules::{REQUIRED_PROTO, SANITIZED, SERVER_DESCRIPTOR};
+use sha1::{Digest, Sha1};
+use std::net::{Ipv4Addr, SocketAddr};
+use time::Timestamp;
+
+/// Server descriptors are published by every relay and describe its keys,
+/// addresses, capabilities and exit policy. Consensus "r" lines refer to a
+/// descriptor by the digest of its signed portion.
+///
+/// Spec defined in section 2.1.1 of dir-spec.txt
+#[derive(Debug, Clone)]
+pub struct ServerDescriptor {
+    /// "router" nickname address ORPort SOCKSPort DirPort NL
+    /// [At start, exactly once.]
+    pub nickname: String,
+    pub address: Ipv4Addr,
+    pub or_port: u16,
+    /// Obsolete, always 0
+    pub socks_port: u16,
+    pub dir_port: u16,
+    /// The Ed25519 certificate of the router's signing key, signed by its
+    /// master identity key.
+    /// [Exactly once, right after "router", if present.]
+    pub identity_ed25519: Option<Vec<u8>>,
+    /// The master Ed25519 identity key, base64 with trailing =s omitted.
+    /// [At most once]
+    pub master_key_ed25519: Option<String>,
+    /// Average, burst and observed bandwidth in bytes per second.
+    /// [Exactly once]
+    pub bandwidth: DescriptorBandwidth,
+    /// A human-readable string describing the software the router runs.
+    /// [At most once]
+    pub platform: Option<String>,
+    /// The time, in UTC, when this descriptor was generated.
+    /// [Exactly once]
+    pub published: Timestamp,
+    /// A fingerprint of the router's identity key, in hex without spaces.
+    /// [At most once]
+    pub fingerprint: Option<String>,
+    /// Whether the router is hibernating and should not be used.
+    /// [At most once]
+    pub hibernating: bool,
+    /// The number of seconds that this OR process has been running.
+    /// [At most once]
+    pub uptime: Option<u64>,
+    /// The TAP onion key, DER-encoded.
+    /// [Exactly once]
+    pub onion_key: Vec<u8>,
+    /// The identity key digest signed with the onion key.
+    /// [At most once]
+    pub onion_key_crosscert: Option<Vec<u8>>,
+    /// The curve25519 key used for the ntor handshake.
+    /// [At most once]
+    pub ntor_onion_key: Option<Vec<u8>>,
+    /// The sign bit and the Ed25519 certificate cross-certifying the ntor
+    /// onion key.
+    /// [At most once]
+    pub ntor_onion_key_crosscert: Option<(u8, Vec<u8>)>,
+    /// The router's RSA identity key, DER-encoded.
+    /// [Exactly once]
+    pub signing_key: Vec<u8>,
+    /// The "accept" and "reject" lines, in order.
+    /// [Any number]
+    pub exit_policy: Vec<ExitPolicyRule>,
+    /// The exit policy summary for IPv6.
+    /// [At most once]
+    pub ipv6_policy: Option<PortPolicy>,
+    /// Describes a way to contact the relay's administrator.
+    /// [At most once]
+    pub contact: Option<String>,
+    /// Which distribution method a bridge would like to be used with.
+    /// [At most once]
+    pub bridge_distribution_request: Option<String>,
+    /// Nicknames or $-prefixed hex fingerprints of the router's family.
+    /// [At most once]
+    pub family: Vec<String>,
+    /// Legacy bandwidth history, kept in extra-info documents nowadays.
+    /// [At most once]
+    pub read_history: Option<String>,
+    /// [At most once]
+    pub write_history: Option<String>,
+    /// Whether the router uses a DNS server to answer exit requests.
+    /// [At most once]
+    pub eventdns: Option<bool>,
+    /// Whether the router serves extra-info documents.
+    /// [At most once]
+    pub caches_extra_info: bool,
+    /// The hex sha1 digest of the router's extra-info document, and the
+    /// base64 sha256 digest if given.
+    /// [At most once]
+    pub extra_info_digest: Option<(String, Option<String>)>,
+    /// Hidden service descriptor versions the router stores, which are
+    /// version 2 if the item has no arguments.
+    /// [At most once]
+    pub hidden_service_dir: Option<Vec<u32>>,
+    /// Legacy protocol list, superseded by "proto".
+    /// [At most once]
+    pub protocols: Option<String>,
+    /// Whether the router allows single-hop exits.
+    /// [At most once]
+    pub allow_single_hop_exits: bool,
+    /// Additional advertised addresses for the OR.
+    /// [Any number]
+    pub or_addresses: Vec<SocketAddr>,
+    /// Whether the router accepts tunnelled directory requests.
+    /// [At most once]
+    pub tunnelled_dir_server: bool,
+    /// The protocols supported by the router.
+    /// [Exactly once]
+    pub proto: Vec<Entry>,
+    /// Ed25519 signature of the descriptor, made with the signing key.
+    /// [At most once]
+    pub router_sig_ed25519: Option<Vec<u8>>,
+    /// RSA signature of the descriptor, made with the identity key.
+    /// [At end, exactly once]
+    pub router_signature: Vec<u8>,
+    /// The sha1 digest of the descriptor as signed, base64 with trailing =s
+    /// omitted, as it appears in ns-flavored consensus "r" lines
+    pub digest: String,
+}
+/// "bandwidth" SP bandwidth-avg SP bandwidth-burst SP bandwidth-observed NL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptorBandwidth {
+    pub average: u64,
+    pub burst: u64,
+    pub observed: u64,
+}
+/// "accept" exitpattern NL / "reject" exitpattern NL
+/// exitpattern ::= addrspec ":" portspec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitPolicyRule {
+    /// Whether matching connections are accepted or rejected
+    pub accept: bool,
+    /// "*", an address, or an address with a mask
+    pub address: String,
+    /// Inclusive port range, with "*" covering every port
+    pub ports: (u16, u16),
+}
+
+impl ServerDescriptor {
+    /// Parses a single server descriptor from its exact bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single server descriptor with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let document = parse_complete_with(&input, options)?;
+        options.check_keywords(document.items(), |keyword| SERVER_DESCRIPTOR.knows(keyword))?;
+        let mut descriptor = Self::from_items(document.items(), false)?;
+        if !options.allow_missing_proto {
+            SERVER_DESCRIPTOR.check_counts(document.items(), REQUIRED_PROTO)?;
+        }
+        descriptor.digest =
+            encode_base64(&Sha1::digest(signed_portion(&input, "router-signature")?));
+        Ok(descriptor)
+    }
+    /// Parses every descriptor in a concatenated stream, such as a
+    /// cached-descriptors file. Annotation lines beginning with "@" are
+    /// skipped.
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every descriptor in a concatenated stream with the given options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "router")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+    /// The identity from the "fingerprint" line
+    pub fn rsa_identity(&self) -> Option<RsaIdentity> {
+        self.fingerprint
+            .as_ref()
+            .and_then(|fingerprint| RsaIdentity::from_hex(fingerprint))
+    }
+    /// The identities of the family members given by fingerprint rather
+    /// than nickname
+    pub fn family_identities(&self) -> Vec<RsaIdentity> {
+        family_identities(&self.family)
+    }
+    /// Builds a descriptor from its items, leaving the digest empty.
+    /// Sanitized bridge descriptors have no keys or signatures.
+    pub(crate) fn from_items(items: &[Item], sanitized: bool) -> Result<Self, Error> {
+        SERVER_DESCRIPTOR.check_counts(items, if sanitized { SANITIZED } else { &[] })?;
+        match items.first() {
+            Some(item) if item.keyword() == "router" => (),
+            _ => return Err(Error::MissingItem("router")),
+        }
+        match items.last() {
+            Some(item) if sanitized || item.keyword() == "router-signature" => (),
+            _ => return Err(Error::MissingItem("router-signature")),
+        }
+        // identity-ed25519 must come right after router if present
+        if let Some(position) = items
+            .iter()
+            .position(|item| item.keyword() == "identity-ed25519")
+        {
+            if position != 1 {
+                return Err(Error::UnexpectedItem("identity-ed25519".to_string()));
+            }
+        }
+        let router = &items[0];
+        let arguments = router.args();
+        let mut descriptor = ServerDescriptor {
+            nickname: arguments.arg(0)?,
+            address: arguments.arg(1)?,
+            or_port: arguments.arg(2)?,
+            socks_port: arguments.arg(3)?,
+            dir_port: arguments.arg(4)?,
+            identity_ed25519: None,
+            master_key_ed25519: None,
+            bandwidth: DescriptorBandwidth {
+                average: 0,
+                burst: 0,
+                observed: 0,
+            },
+            platform: None,
+            published: Timestamp::from_unix(0),
+            fingerprint: None,
+            hibernating: false,
+            uptime: None,
+            onion_key: Vec::new(),
+            onion_key_crosscert: None,
+            ntor_onion_key: None,
+            ntor_onion_key_crosscert: None,
+            signing_key: Vec::new(),
+            exit_policy: Vec::new(),
+            ipv6_policy: None,
+            contact: None,
+            bridge_distribution_request: None,
+            family: Vec::new(),
+            read_history: None,
+            write_history: None,
+            eventdns: None,
+            caches_extra_info: false,
+            extra_info_digest: None,
+            hidden_service_dir: None,
+            protocols: None,
+            allow_single_hop_exits: false,
+            or_addresses: Vec::new(),
+            tunnelled_dir_server: false,
+            proto: Vec::new(),
+            router_sig_ed25519: None,
+            router_signature: Vec::new(),
+            digest: String::new(),
+        };
+        for item in &items[1..] {
+            let arguments = item.args();
+            match item.keyword() {
+                "identity-ed25519" => {
+                    descriptor.identity_ed25519 = Some(item.object("ED25519 CERT")?)
+                }
+                "master-key-ed25519" => descriptor.master_key_ed25519 = Some(arguments.arg(0)?),
+                "bandwidth" => {
+                    descriptor.bandwidth = DescriptorBandwidth {
+                        average: arguments.arg(0)?,
+                        burst: arguments.arg(1)?,
+                        observed: arguments.arg(2)?,
+                    }
+                }
+                "platform" => descriptor.platform = Some(item.arguments()),
+                "published" => descriptor.published = arguments.arg_datetime(0, 1)?,
+                "fingerprint" => {
+                    let fingerprint = arguments.all::<String>()?.concat();
+                    if fingerprint.len() != 40 || RsaIdentity::from_hex(&fingerprint).is_none() {
+                        return Err(arguments
+                            .error(0, format!("malformed fingerprint \"{}\"", fingerprint)));
+                    }
+                    descriptor.fingerprint = Some(fingerprint);
+                }
+                "hibernating" => descriptor.hibernating = parse_bool(&arguments)?,
+                "uptime" => descriptor.uptime = Some(arguments.arg(0)?),
+                "onion-key" => descriptor.onion_key = item.object("RSA PUBLIC KEY")?,
+                "onion-key-crosscert" => {
+                    descriptor.onion_key_crosscert = Some(item.object("CROSSCERT")?)
+                }
+                "ntor-onion-key" => descriptor.ntor_onion_key = Some(arguments.arg_base64(0)?),
+                "ntor-onion-key-crosscert" => {
+                    descriptor.ntor_onion_key_crosscert =
+                        Some((arguments.arg(0)?, item.object("ED25519 CERT")?))
+                }
+                "signing-key" => descriptor.signing_key = item.object("RSA PUBLIC KEY")?,
+                "accept" | "reject" => descriptor
+                    .exit_policy
+                    .push(parse_exit_policy_rule(item, &arguments)?),
+                "ipv6-policy" => descriptor.ipv6_policy = Some(parse_port_policy(&arguments)?),
+                "contact" => descriptor.contact = Some(item.arguments()),
+                "bridge-distribution-request" => {
+                    descriptor.bridge_distribution_request = Some(arguments.arg(0)?)
+                }
+                "family" => descriptor.family = arguments.all()?,
+                "read-history" => descriptor.read_history = Some(item.arguments()),
+                "write-history" => descriptor.write_history = Some(item.arguments()),
+                "eventdns" => descriptor.eventdns = Some(parse_bool(&arguments)?),
+                "caches-extra-info" => descriptor.caches_extra_info = true,
+                "extra-info-digest" => {
+                    descriptor.extra_info_digest = Some((
+                        arguments.arg(0)?,
+                        if arguments.len() > 1 {
+                            Some(arguments.arg(1)?)
+                        } else {
+                            None
+                        },
+                    ))
+                }
+                "hidden-service-dir" if arguments.is_empty() => {
+                    descriptor.hidden_service_dir = Some(vec![2])
+                }
+                "hidden-service-dir" => descriptor.hidden_service_dir = Some(arguments.all()?),
+                "protocols" => descriptor.protocols = Some(item.arguments()),
+                "allow-single-hop-exits" => descriptor.allow_single_hop_exits = true,
+                "or-address" => descriptor.or_addresses.push(arguments.arg(0)?),
+                "tunnelled-dir-server" => descriptor.tunnelled_dir_server = true,
+                "proto" => descriptor.proto = parse_entries(&item.args())?,
+                "router-sig-ed25519" => {
+                    descriptor.router_sig_ed25519 = Some(arguments.arg_base64(0)?)
+                }
+                "router-signature" => descriptor.router_signature = item.object("SIGNATURE")?,
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        Ok(descriptor)
+    }
+}
+
+/// The bytes covered by a document's signature: everything from the start
+/// through the newline after the signature item's keyword
+pub(crate) fn signed_portion<'a>(
+    input: &'a [u8],
+    keyword: &'static str,
+) -> Result<&'a [u8], Error> {
+    let marker = format!("\n{}\n", keyword);
+    input
+        .windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+        .map(|position| &input[..position + marker.len()])
+        .ok_or(Error::MissingItem(keyword))
+}
+/// Parses a "0" or "1" flag
+fn parse_bool(arguments: &Arguments) -> Result<bool, Error> {
+    match arguments.str(0)? {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        flag => Err(arguments.error(0, format!("expected 0 or 1, found \"{}\"", flag))),
+    }
+}
+/// exitpattern ::= addrspec ":" portspec
+/// portspec ::= "*" | port | port "-" port
+fn parse_exit_policy_rule(item: &Item, arguments: &Arguments) -> Result<ExitPolicyRule, Error> {
+    let pattern = arguments.str(0)?;
+    let malformed = || arguments.error(0, format!("malformed pattern \"{}\"", pattern));
+    let separator = pattern.rfind(':').ok_or_else(malformed)?;
+    let (address, ports) = (&pattern[..separator], &pattern[separator + 1..]);
+    let ports = if ports == "*" {
+        (1, 65535)
+    } else {
+        let mut bounds = ports.splitn(2, '-').map(|bound| bound.parse::<u16>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(low)), None) => (low, low),
+            (Some(Ok(low)), Some(Ok(high))) => (low, high),
+            _ => return Err(malformed()),
+        }
+    };
+    Ok(ExitPolicyRule {
+        accept: item.keyword() == "accept",
+        address: address.to_string(),
+        ports,
+    })
+}
+
+/// The identities of "$"-prefixed family members
+pub(crate) fn family_identities(family: &[String]) -> Vec<RsaIdentity> {
+    family
+        .iter()
+        .filter(|member| member.starts_with('$'))
+        .filter_map(|member| RsaIdentity::parse(member))
+        .collect()
+}
+
+/// Test
+#[test]
+fn test_parse_server_descriptors() {
+    let descriptors = ServerDescriptor::parse_all(include_bytes!("../test/server-descriptors"))
+        .expect("failed to parse descriptors");
+    assert_eq!(descriptors.len(), 2);
+    assert_eq!(descriptors[0].nickname, "seele");
+    assert_eq!(descriptors[0].digest, "pfrIrJe0IzlYXxll4CTwayCRz+0");
+    assert_eq!(descriptors[1].bandwidth.observed, 16494722);
+    assert_eq!(
+        descriptors[1].platform.as_ref().unwrap(),
+        "Tor 0.3.2.9 on Linux"
+    );
+    assert_eq!(descriptors[1].exit_policy.len(), 4);
+    // A bare "hidden-service-dir" means version 2
+    assert_eq!(descriptors[0].hidden_service_dir, Some(vec![2]));
+    let versions = String::from_utf8_lossy(include_bytes!("../test/server-descriptors")).replacen(
+        "\nhidden-service-dir\n",
+        "\nhidden-service-dir 2 3\n",
+        1,
+    );
+    let parsed = ServerDescriptor::parse_all(versions.as_bytes()).unwrap();
+    assert_eq!(parsed[0].hidden_service_dir, Some(vec![2, 3]));
+    // Errors in later descriptors point into the whole stream
+    let input = String::from_utf8_lossy(include_bytes!("../test/server-descriptors"));
+    let broken = input.replace("162.247.72.201 443 ", "162.247.72.201 44x ");
+    assert_eq!(
+        ServerDescriptor::parse_all(broken.as_bytes()).err(),
+        Some(Error::InvalidArgument {
+            keyword: "router".to_string(),
+            index: 2,
+            offset: broken.find("44x").unwrap(),
+            reason: "malformed \"44x\"".to_string(),
+        })
+    );
+    let broken = input.replace("contact Calyx", "contact Cälyx");
+    let strict = ParseOptions::strict();
+    assert_eq!(
+        ServerDescriptor::parse_all_with(broken.as_bytes(), &strict).err(),
+        Some(Error::InvalidArgument {
+            keyword: "contact".to_string(),
+            index: 0,
+            offset: broken.find('ä').unwrap(),
+            reason: "invalid character 0xC3".to_string(),
+        })
+    );
+    // Relays before 0.2.9 published no "proto" line
+    let old = input.replace(
+        "proto Cons=1-2 Desc=1-2 DirCache=1-2 HSDir=1-2 HSIntro=3-4 HSRend=1-2 Link=1-4 LinkAuth=1,3 Microdesc=1-2 Relay=1-2\n",
+        "",
+    );
+    assert_eq!(
+        ServerDescriptor::parse_all(old.as_bytes()).err(),
+        Some(Error::MissingItem("proto"))
+    );
+    let lenient = ServerDescriptor::parse_all_with(old.as_bytes(), &ParseOptions::lenient())
+        .expect("failed to parse descriptors without proto lines");
+    assert!(lenient[0].proto.is_empty());
+}
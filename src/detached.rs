@@ -0,0 +1,257 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{parse_directory_signature, ConsensusDocument, DirectorySignature, Flavor};
+use document::{encode_hex, parse_complete_with};
+use error::Error;
+use options::ParseOptions;
+use rules::DETACHED_SIGNATURES;
+use time::Timestamp;
+
+/// During voting, authorities exchange the signatures they made on each
+/// flavor of the consensus in a separate document, so that every authority
+/// can serve a consensus with all of them.
+///
+/// Spec defined in section 3.10 of dir-spec.txt
+#[derive(Debug, Clone)]
+pub struct DetachedSignatures {
+    /// "consensus-digest" SP Digest NL
+    /// The hex sha1 digest of the ns-flavored consensus, up to and
+    /// including the space after the first "directory-signature".
+    /// [At start, at most once.]
+    pub consensus_digest: Option<String>,
+    /// The times of the consensus being signed.
+    /// [Exactly once each]
+    pub valid_after: Timestamp,
+    pub fresh_until: Timestamp,
+    pub valid_until: Timestamp,
+    /// Digests of the other flavors.
+    /// [Any number]
+    pub additional_digests: Vec<AdditionalDigest>,
+    /// Signatures on the other flavors.
+    /// [Any number]
+    pub additional_signatures: Vec<AdditionalSignature>,
+    /// Signatures on the ns-flavored consensus.
+    /// [Any number]
+    pub signatures: Vec<DirectorySignature>,
+}
+/// "additional-digest" SP flavor SP algname SP digest NL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdditionalDigest {
+    pub flavor: String,
+    pub algorithm: String,
+    /// Hex digest of the flavored consensus
+    pub digest: String,
+}
+/// "additional-signature" SP flavor SP algname SP identity SP
+/// signing-key-digest NL signature
+#[derive(Debug, Clone)]
+pub struct AdditionalSignature {
+    pub flavor: String,
+    pub signature: DirectorySignature,
+}
+
+impl DetachedSignatures {
+    /// Parses a detached signature document from its raw bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a detached signature document with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let document = parse_complete_with(input, options)?;
+        let items = document.items();
+        options.check_keywords(items, |keyword| DETACHED_SIGNATURES.knows(keyword))?;
+        DETACHED_SIGNATURES.check_counts(items, &[])?;
+        if let Some(position) = items
+            .iter()
+            .position(|item| item.keyword() == "consensus-digest")
+        {
+            if position != 0 {
+                return Err(Error::UnexpectedItem("consensus-digest".to_string()));
+            }
+        }
+        let mut signatures = DetachedSignatures {
+            consensus_digest: None,
+            valid_after: Timestamp::from_unix(0),
+            fresh_until: Timestamp::from_unix(0),
+            valid_until: Timestamp::from_unix(0),
+            additional_digests: Vec::new(),
+            additional_signatures: Vec::new(),
+            signatures: Vec::new(),
+        };
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "consensus-digest" => signatures.consensus_digest = Some(arguments.arg(0)?),
+                "valid-after" => signatures.valid_after = arguments.arg_datetime(0, 1)?,
+                "fresh-until" => signatures.fresh_until = arguments.arg_datetime(0, 1)?,
+                "valid-until" => signatures.valid_until = arguments.arg_datetime(0, 1)?,
+                "additional-digest" => signatures.additional_digests.push(AdditionalDigest {
+                    flavor: arguments.arg(0)?,
+                    algorithm: arguments.arg(1)?,
+                    digest: arguments.arg(2)?,
+                }),
+                "additional-signature" => {
+                    // Unlike "directory-signature", the algorithm is required
+                    if arguments.len() != 4 {
+                        return Err(arguments.error(
+                            arguments.len().min(4),
+                            "expected flavor, algorithm, identity and signing key digest",
+                        ));
+                    }
+                    signatures.additional_signatures.push(AdditionalSignature {
+                        flavor: arguments.arg(0)?,
+                        signature: parse_directory_signature(item, &arguments, 1)?,
+                    })
+                }
+                "directory-signature" => signatures
+                    .signatures
+                    .push(parse_directory_signature(item, &arguments, 0)?),
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        Ok(signatures)
+    }
+    /// Adds the signatures on a consensus's flavor to it, after checking
+    /// that they were made on exactly that consensus: the digest of the
+    /// bytes `consensus` was parsed from and the three timestamps must all
+    /// match. Signatures the consensus already has are skipped.
+    /// Returns how many signatures were added.
+    pub fn attach(&self, consensus: &mut ConsensusDocument) -> Result<usize, Error> {
+        if consensus.vote_status.is_vote() {
+            return Err(Error::invalid(
+                "vote-status",
+                "signatures can only be attached to a consensus",
+            ));
+        }
+        for &(keyword, ours, theirs) in &[
+            ("valid-after", &self.valid_after, &consensus.valid_after),
+            ("fresh-until", &self.fresh_until, &consensus.fresh_until),
+            ("valid-until", &self.valid_until, &consensus.valid_until),
+        ] {
+            if ours != theirs {
+                return Err(Error::invalid(
+                    keyword,
+                    format!("{} does not match the consensus's {}", ours, theirs),
+                ));
+            }
+        }
+        let (keyword, digest, signatures): (_, _, Vec<&DirectorySignature>) = match consensus.flavor
+        {
+            Flavor::Ns => (
+                "consensus-digest",
+                self.consensus_digest
+                    .as_ref()
+                    .map(|digest| ("sha1", digest.as_str())),
+                self.signatures.iter().collect(),
+            ),
+            flavor => (
+                "additional-digest",
+                self.additional_digests
+                    .iter()
+                    .find(|digest| digest.flavor == flavor.name())
+                    .map(|digest| (digest.algorithm.as_str(), digest.digest.as_str())),
+                self.additional_signatures
+                    .iter()
+                    .filter(|signature| signature.flavor == flavor.name())
+                    .map(|signature| &signature.signature)
+                    .collect(),
+            ),
+        };
+        let (algorithm, digest) = digest.ok_or(Error::MissingItem(keyword))?;
+        if !consensus
+            .signed_digest(algorithm)?
+            .eq_ignore_ascii_case(digest)
+        {
+            return Err(Error::invalid(keyword, "does not match the consensus"));
+        }
+        let mut attached = 0;
+        for signature in signatures {
+            let present = consensus.footer.signatures.iter().any(|existing| {
+                existing.identity == signature.identity
+                    && existing.signing_key_digest == signature.signing_key_digest
+                    && existing.algorithm == signature.algorithm
+            });
+            if !present {
+                consensus.footer.signatures.push(signature.clone());
+                attached += 1;
+            }
+        }
+        Ok(attached)
+    }
+}
+
+impl ConsensusDocument {
+    /// The uppercase hex digest of the consensus as signed: everything
+    /// through the space after the first "directory-signature" keyword.
+    /// It is only known for documents parsed from their bytes.
+    pub fn signed_digest(&self, algorithm: &str) -> Result<String, Error> {
+        let digest = match algorithm {
+            "sha1" => self.sha1_digest.as_ref().map(|digest| encode_hex(digest)),
+            "sha256" => self.sha256_digest.as_ref().map(|digest| encode_hex(digest)),
+            _ => {
+                return Err(Error::invalid(
+                    "directory-signature",
+                    format!("unsupported digest algorithm \"{}\"", algorithm),
+                ))
+            }
+        };
+        digest.ok_or_else(|| Error::invalid("directory-signature", "the signed bytes are unknown"))
+    }
+}
+/// The portion of a consensus that its signatures cover
+pub(crate) fn signed_consensus(input: &[u8]) -> Result<&[u8], Error> {
+    const MARKER: &[u8] = b"\ndirectory-signature ";
+    input
+        .windows(MARKER.len())
+        .position(|window| window == MARKER)
+        .map(|position| &input[..position + MARKER.len()])
+        .ok_or(Error::MissingItem("directory-signature"))
+}
+
+/// Test
+#[test]
+fn test_attach_detached_signatures() {
+    let detached = DetachedSignatures::parse(include_bytes!("../test/detached-signatures"))
+        .expect("failed to parse detached signatures");
+    assert_eq!(detached.signatures.len(), 2);
+    assert_eq!(detached.additional_signatures.len(), 2);
+    let input = include_bytes!("../test/barebones.consensus");
+    let mut consensus = ConsensusDocument::parse(input).unwrap();
+    assert_eq!(detached.attach(&mut consensus), Ok(1));
+    assert_eq!(consensus.footer.signatures.len(), 3);
+    assert_eq!(detached.attach(&mut consensus), Ok(0));
+    let mut microdesc =
+        ConsensusDocument::parse(include_bytes!("../test/barebones.microdesc")).unwrap();
+    assert_eq!(detached.attach(&mut microdesc), Ok(1));
+    assert_eq!(microdesc.footer.signatures[2].algorithm, "sha256");
+    // Signatures on other bytes are not attached
+    let tampered = String::from_utf8_lossy(input).replace("seele", "seelf");
+    let mut consensus = ConsensusDocument::parse(tampered.as_bytes()).unwrap();
+    assert_eq!(
+        detached.attach(&mut consensus),
+        Err(Error::invalid(
+            "consensus-digest",
+            "does not match the consensus"
+        ))
+    );
+    // Nor are they when the signed bytes are unknown
+    let mut consensus = ConsensusDocument::parse(input).unwrap();
+    consensus.sha1_digest = None;
+    assert!(detached.attach(&mut consensus).is_err());
+    assert_eq!(consensus.footer.signatures.len(), 2);
+}
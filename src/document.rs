@@ -14,14 +14,51 @@
     You should have received a copy of the GNU General Public License
     along with onyan.  If not, see <http://www.gnu.org/licenses/>.
 */
-use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
-use nom::{anychar, IResult};
+// nom's named! macro doesn't pass outer doc comments through to the function
+#![allow(unused_doc_comments)]
+use arguments::Arguments;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use error::Error;
+use nom::IResult;
+use options::ParseOptions;
+#[cfg(feature = "serde")]
+use serialization;
+
+/// Base64 as used throughout the directory protocol, where trailing `=`
+/// padding is sometimes removed
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+/// Encodes base64 with trailing `=` padding removed, as used for digests
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    BASE64.encode(data).trim_end_matches('=').to_string()
+}
+/// Encodes bytes as uppercase hex, as used for fingerprints and digests
+pub(crate) fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+/// Decodes hex of either case
+pub(crate) fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) || !data.is_ascii() {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&data[index..index + 2], 16).ok())
+        .collect()
+}
+/// Decodes base64 with or without padding, ignoring embedded newlines
+pub(crate) fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let data: String = data.chars().filter(|c| *c != '\n').collect();
+    BASE64.decode(data).ok()
+}
 
 /// The highest level object is a Document, which consists of one or more Items.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Document {
     /// Items contained within the document
     items: Vec<Item>,
@@ -36,15 +73,20 @@ impl Document {
     /// Build from parsed items
     /// # Parameters
     /// * `items` - `Vec` of `Option<Item>` where None represents a newline to be skipped
+    ///
     /// This exists because an Item can also be
     /// a newline, but those aren't useful
     fn from_parsed_items(items: Vec<Option<Item>>) -> Self {
-        Self::new(items.into_iter().flat_map(|item| item).collect())
+        Self::new(items.into_iter().flatten().collect())
+    }
+    /// Items contained within the document, in order
+    pub fn items(&self) -> &[Item] {
+        &self.items
     }
 }
 /// Parses a document
 /// Document ::= (Item | NL)+
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 named!(
     pub parse_document<Document>,
     do_parse!(
@@ -69,26 +111,208 @@ named!(
     )
 );
 
+/// Parses a document that must span the whole input, using the default
+/// options
+/// # Parameters
+/// * `input` - raw bytes of the document
+pub fn parse_complete(input: &[u8]) -> Result<Document, Error> {
+    parse_complete_with(input, &ParseOptions::default())
+}
+
+/// Parses a document that must span the whole input, after checking and
+/// rewriting its lines as the options say
+pub fn parse_complete_with(input: &[u8], options: &ParseOptions) -> Result<Document, Error> {
+    parse_checked(&options.preprocess(input)?)
+}
+
+/// Runs the metaformat parser over input that has already been checked
+fn parse_checked(input: &[u8]) -> Result<Document, Error> {
+    match parse_document(input) {
+        IResult::Done(rest, ref _document) if !rest.is_empty() => {
+            Err(Error::Syntax(input.len() - rest.len()))
+        }
+        IResult::Done(_, mut document) => {
+            for (item, offset) in document.items.iter_mut().zip(keyword_line_offsets(input)) {
+                item.offset = offset;
+            }
+            Ok(document)
+        }
+        IResult::Error(_) => Err(Error::Syntax(0)),
+        IResult::Incomplete(_) => Err(Error::Syntax(input.len())),
+    }
+}
+
+/// The offset of every keyword line in a document that parsed, which are
+/// the lines that are neither blank nor part of an object
+fn keyword_line_offsets(input: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut in_object = false;
+    let mut offset = 0;
+    for line in input.split_inclusive(|byte| *byte == b'\n') {
+        if line.starts_with(b"-----BEGIN ") {
+            in_object = true;
+        }
+        if in_object {
+            in_object = !line.starts_with(b"-----END ");
+        } else if line != b"\n" {
+            offsets.push(offset);
+        }
+        offset += line.len();
+    }
+    offsets
+}
+
+/// Separates the annotation lines beginning with "@" at the start of the
+/// input from the document that follows them
+pub(crate) fn strip_annotations(input: &[u8]) -> (Vec<&str>, &[u8]) {
+    let mut annotations = Vec::new();
+    let mut rest = input;
+    while rest.starts_with(b"@") {
+        let line_end = rest
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map(|position| position + 1)
+            .unwrap_or(rest.len());
+        if let Ok(line) = ::std::str::from_utf8(&rest[..line_end]) {
+            annotations.push(line.trim_end());
+        }
+        rest = &rest[line_end..];
+    }
+    (annotations, rest)
+}
+/// Checks that an "@type" annotation, if there is one, names the expected
+/// document type. The version after the type is not checked.
+pub(crate) fn check_type_annotation(annotations: &[&str], expected: &str) -> Result<(), Error> {
+    for annotation in annotations {
+        let mut parts = annotation.split_whitespace();
+        if parts.next() != Some("@type") {
+            continue;
+        }
+        match parts.next() {
+            Some(name) if name == expected => (),
+            name => {
+                return Err(Error::invalid(
+                    "@type",
+                    format!("expected {}, found {}", expected, name.unwrap_or("nothing")),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Splits a stream of concatenated documents into the exact bytes of each
+/// document along with its offset in the stream.
+/// Every document starts with a line whose keyword is `first_keyword`, and
+/// annotation lines beginning with "@" are not part of any document.
+pub(crate) fn split_documents<'a>(input: &'a [u8], first_keyword: &str) -> Vec<(usize, &'a [u8])> {
+    let mut documents = Vec::new();
+    let mut start = None;
+    let mut line_start = 0;
+    while line_start < input.len() {
+        let line_end = input[line_start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map(|position| line_start + position + 1)
+            .unwrap_or(input.len());
+        let line = &input[line_start..line_end];
+        let is_first = line.starts_with(first_keyword.as_bytes())
+            && line[first_keyword.len()..]
+                .first()
+                .is_none_or(|byte| *byte == b' ' || *byte == b'\t' || *byte == b'\n');
+        if is_first || line.starts_with(b"@") {
+            if let Some(start) = start.take() {
+                documents.push((start, &input[start..line_start]));
+            }
+        }
+        if is_first {
+            start = Some(line_start);
+        }
+        line_start = line_end;
+    }
+    if let Some(start) = start {
+        documents.push((start, &input[start..]));
+    }
+    documents
+}
+
 /// Every Item begins with a KeywordLine, followed by zero or more Objects.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Item {
     /// The main line for the item
+    #[cfg_attr(feature = "serde", serde(flatten))]
     keyword_line: KeywordLine,
     /// Additional objects
     objects: Vec<Object>,
+    /// Byte offset of the keyword line in the document
+    #[cfg_attr(feature = "serde", serde(skip))]
+    offset: usize,
 }
 impl Item {
     /// Constructor
     // TODO: Add objects to the constructor
     fn new(keyword_line: KeywordLine, objects: Vec<Object>) -> Self {
         Item {
-            keyword_line: keyword_line,
-            objects: objects,
+            keyword_line,
+            objects,
+            offset: 0,
         }
     }
+    /// The main line for the item
+    pub fn keyword_line(&self) -> &KeywordLine {
+        &self.keyword_line
+    }
+    /// Shorthand for the keyword of the main line
+    pub fn keyword(&self) -> &str {
+        self.keyword_line.keyword().as_str()
+    }
+    /// Byte offset of the keyword line in the document, counted after any
+    /// rewriting done by `ParseOptions`
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    /// The arguments of the main line split into tokens
+    pub fn args(&self) -> Arguments<'_> {
+        Arguments::new(self)
+    }
+    /// Additional objects
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+    /// The arguments of the main line as a string, empty if there were none.
+    /// Bytes that aren't valid UTF-8 are replaced with U+FFFD.
+    pub fn arguments(&self) -> String {
+        String::from_utf8_lossy(self.argument_bytes()).into_owned()
+    }
+    /// The raw bytes of the arguments of the main line, empty if there were
+    /// none
+    pub fn argument_bytes(&self) -> &[u8] {
+        self.keyword_line.arguments().unwrap_or_default()
+    }
+    /// The arguments of the main line, if they are valid UTF-8
+    pub fn arguments_utf8(&self) -> Option<&str> {
+        ::std::str::from_utf8(self.argument_bytes()).ok()
+    }
+    /// Decodes the first object of the item, which must have the given keyword
+    pub(crate) fn object(&self, keyword: &str) -> Result<Vec<u8>, Error> {
+        let object = self
+            .objects
+            .first()
+            .ok_or_else(|| Error::invalid(self.keyword(), "missing object"))?;
+        if object.keyword() != keyword {
+            return Err(Error::invalid(
+                self.keyword(),
+                format!("expected {} object, found {}", keyword, object.keyword()),
+            ));
+        }
+        object
+            .decode()
+            .ok_or_else(|| Error::invalid(self.keyword(), "object is not valid base64"))
+    }
 }
 /// Item ::= KeywordLine Object*
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 named!(
     item<Item>,
     do_parse!(
@@ -99,40 +323,79 @@ named!(
 );
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct KeywordLine {
     keyword: Keyword,
-    arguments: Option<Vec<char>>,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::arguments"))]
+    arguments: Option<Vec<u8>>,
+    /// Byte offset of the arguments in the line
+    #[cfg_attr(feature = "serde", serde(skip))]
+    arguments_offset: usize,
 }
 impl KeywordLine {
-    /// Constructor
-    fn new(keyword: Keyword, arguments: Option<Vec<char>>) -> Self {
-        KeywordLine { keyword, arguments }
+    /// Constructor for a line without arguments
+    fn new(keyword: Keyword) -> Self {
+        KeywordLine {
+            keyword,
+            arguments: None,
+            arguments_offset: 0,
+        }
+    }
+    /// Constructor for a line with arguments after `separator` bytes of
+    /// whitespace
+    fn with_arguments(keyword: Keyword, separator: usize, arguments: &[u8]) -> Self {
+        KeywordLine {
+            arguments_offset: keyword.as_str().len() + separator,
+            keyword,
+            arguments: Some(arguments.to_vec()),
+        }
+    }
+    /// The keyword at the start of the line
+    pub fn keyword(&self) -> &Keyword {
+        &self.keyword
+    }
+    /// Everything after the whitespace following the keyword, if present
+    pub fn arguments(&self) -> Option<&[u8]> {
+        self.arguments.as_deref()
+    }
+    /// Byte offset of the arguments from the start of the line
+    pub fn arguments_offset(&self) -> usize {
+        self.arguments_offset
     }
 }
 /// KeywordLine ::= Keyword NL | Keyword WS ArgumentChar+ NL
-#[cfg_attr(rustfmt, rustfmt_skip)]
+/// Trailing whitespace after a keyword is tolerated, since real
+/// consensuses contain lines such as "pr " with no entries.
+/// Which bytes may appear in the arguments is up to
+/// `ParseOptions::non_ascii`, which is checked before parsing.
+#[rustfmt::skip]
 named!(
     keyword_line<KeywordLine>,
     alt_complete!(
         do_parse!(
             keyword: keyword >>
             newline >>
-            (KeywordLine::new(keyword, None))
+            (KeywordLine::new(keyword))
+        ) |
+        do_parse!(
+            keyword: keyword >>
+            separator: whitespace >>
+            arguments: take_till1!(|byte| byte == b'\n') >>
+            newline >>
+            (KeywordLine::with_arguments(keyword, separator.len(), arguments))
         ) |
         do_parse!(
             keyword: keyword >>
             whitespace >>
-            arguments: many1!(argument_char) >>
             newline >>
-            //TODO: Add arguments back
-            (KeywordLine::new(keyword, Some(arguments))) 
+            (KeywordLine::new(keyword))
         )
     )
 );
 
 /// Keyword = KeywordChar+
 /// KeywordChar ::= 'A' ... 'Z' | 'a' ... 'z' | '0' ... '9' | '-'
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 named!(
     keyword<Keyword>,
     do_parse!(
@@ -142,6 +405,8 @@ named!(
     )
 );
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Keyword {
     keyword: String,
 }
@@ -152,23 +417,57 @@ impl Keyword {
             keyword: characters.into_iter().collect(),
         }
     }
+    /// Builds an object keyword, which is made of keywords separated by
+    /// single spaces
+    fn from_object_bytes(bytes: &[u8]) -> Option<Self> {
+        let valid = !bytes.is_empty()
+            && bytes.split(|byte| *byte == b' ').all(|word| {
+                !word.is_empty()
+                    && word
+                        .iter()
+                        .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'-')
+            });
+        if valid {
+            Some(Keyword {
+                keyword: String::from_utf8_lossy(bytes).into_owned(),
+            })
+        } else {
+            None
+        }
+    }
+    /// The keyword as a string
+    pub fn as_str(&self) -> &str {
+        &self.keyword
+    }
 }
 
-/// ArgumentChar ::= any printing ASCII character except NL.
-// TODO: generate this in a better way
-named!(argument_char<char>, one_of!(" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~"));
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Object {
     keyword: Keyword,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::chars"))]
     data: Vec<char>,
 }
 impl Object {
     fn new(keyword: Keyword, data: Vec<char>) -> Self {
         Object { keyword, data }
     }
+    /// The keyword from the begin and end lines
+    pub fn keyword(&self) -> &str {
+        self.keyword.as_str()
+    }
+    /// The base64-encoded data between the begin and end lines,
+    /// including newlines
+    pub fn data(&self) -> &[char] {
+        &self.data
+    }
+    /// Decodes the base64 data of the object
+    pub fn decode(&self) -> Option<Vec<u8>> {
+        decode_base64(&self.data.iter().collect::<String>())
+    }
 }
 /// Object ::= BeginLine Base64-encoded-data EndLine
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 named!(
     object<Object>,
     do_parse!(
@@ -180,30 +479,37 @@ named!(
         (Object::new(begin_line, data))
     )
 );
-/// BeginLine ::= "-----BEGIN " Keyword "-----" NL
-#[cfg_attr(rustfmt, rustfmt_skip)]
+/// BeginLine ::= "-----BEGIN " Keyword (SP Keyword)* "-----" NL
+#[rustfmt::skip]
 named!(
     begin_line<Keyword>,
     delimited!(
         tag!("-----BEGIN "),
-        keyword,
+        object_keyword,
         do_parse!(tag!("-----") >> newline >> ())
     )
 );
-/// EndLine ::= "-----END " Keyword "-----" NL
-#[cfg_attr(rustfmt, rustfmt_skip)]
+/// EndLine ::= "-----END " Keyword (SP Keyword)* "-----" NL
+#[rustfmt::skip]
 named!(
     end_line<Keyword>,
     delimited!(
         tag!("-----END "),
-        keyword,
+        object_keyword,
         do_parse!(tag!("-----") >> newline >> ())
     )
 );
+/// Object keywords such as "RSA PUBLIC KEY" may contain single spaces,
+/// and run until the dashes that close the line
+#[rustfmt::skip]
+named!(
+    object_keyword<Keyword>,
+    map_opt!(take_until!("-----"), Keyword::from_object_bytes)
+);
 /// NL = The ascii LF character (hex value 0x0a).
 named!(newline<char>, char!('\n'));
 /// WS = (SP | TAB)+
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 named!(
     whitespace<Vec<char>>,
     many1!(one_of!(" \t"))
@@ -212,6 +518,8 @@ named!(
 /// Test
 #[test]
 fn test_parse_document() {
+    use std::fs::File;
+    use std::io::prelude::*;
     let mut file = File::open("test/barebones.consensus").expect("file not found");
     let mut file_bytes: Vec<u8> = Vec::new();
     file.read_to_end(&mut file_bytes).unwrap();
@@ -0,0 +1,135 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::error;
+use std::fmt;
+
+/// Errors encountered while turning raw bytes into typed documents
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The metaformat parser could not continue past the given byte offset
+    Syntax(usize),
+    /// A required item was not present
+    MissingItem(&'static str),
+    /// An item appeared where it is not allowed
+    UnexpectedItem(String),
+    /// An item appeared more times than it may
+    DuplicateItem(String),
+    /// An item that may only appear in votes appeared in a consensus
+    VoteOnly(String),
+    /// An item that may only appear in consensuses appeared in a vote
+    ConsensusOnly(String),
+    /// An item was present but its arguments or objects were malformed
+    InvalidItem {
+        /// Keyword of the offending item
+        keyword: String,
+        /// What was wrong with it
+        reason: String,
+    },
+    /// One argument of an item was missing or malformed
+    InvalidArgument {
+        /// Keyword of the offending item
+        keyword: String,
+        /// Index of the argument, counting from 0
+        index: usize,
+        /// Byte offset of the argument in the document, or of the end of
+        /// the line if it was missing. Errors from `ParseOptions` checks
+        /// point at the offending byte instead.
+        offset: usize,
+        /// What was wrong with it
+        reason: String,
+    },
+    /// An onion address had the wrong length, checksum or version
+    InvalidOnionAddress(String),
+    /// A relay query could not be parsed
+    InvalidQuery {
+        /// Byte offset in the query where the problem starts
+        offset: usize,
+        /// What was wrong with it
+        reason: String,
+    },
+    /// An encrypted layer could not be decrypted, usually because the key
+    /// material was wrong
+    Decryption(String),
+}
+impl Error {
+    /// Shorthand for building an `InvalidItem`
+    pub(crate) fn invalid<K: Into<String>, R: Into<String>>(keyword: K, reason: R) -> Self {
+        Error::InvalidItem {
+            keyword: keyword.into(),
+            reason: reason.into(),
+        }
+    }
+    /// Rebases the byte offset of a syntax or argument error found in a
+    /// document that starts `offset` bytes into a larger input
+    pub(crate) fn shift(self, offset: usize) -> Self {
+        match self {
+            Error::Syntax(position) => Error::Syntax(offset + position),
+            Error::InvalidArgument {
+                keyword,
+                index,
+                offset: position,
+                reason,
+            } => Error::InvalidArgument {
+                keyword,
+                index,
+                offset: offset + position,
+                reason,
+            },
+            error => error,
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Syntax(offset) => write!(f, "syntax error at byte {}", offset),
+            Error::MissingItem(keyword) => write!(f, "missing required item \"{}\"", keyword),
+            Error::UnexpectedItem(ref keyword) => write!(f, "unexpected item \"{}\"", keyword),
+            Error::DuplicateItem(ref keyword) => {
+                write!(f, "item \"{}\" appears too many times", keyword)
+            }
+            Error::VoteOnly(ref keyword) => {
+                write!(f, "\"{}\" may only appear in votes", keyword)
+            }
+            Error::ConsensusOnly(ref keyword) => {
+                write!(f, "\"{}\" may only appear in consensuses", keyword)
+            }
+            Error::InvalidItem {
+                ref keyword,
+                ref reason,
+            } => write!(f, "invalid \"{}\" item: {}", keyword, reason),
+            Error::InvalidArgument {
+                ref keyword,
+                index,
+                offset,
+                ref reason,
+            } => write!(
+                f,
+                "invalid argument {} of \"{}\" item at byte {}: {}",
+                index, keyword, offset, reason
+            ),
+            Error::InvalidOnionAddress(ref reason) => {
+                write!(f, "invalid onion address: {}", reason)
+            }
+            Error::InvalidQuery { offset, ref reason } => {
+                write!(f, "invalid query at byte {}: {}", offset, reason)
+            }
+            Error::Decryption(ref reason) => write!(f, "decryption failed: {}", reason),
+        }
+    }
+}
+impl error::Error for Error {}
@@ -0,0 +1,541 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use arguments::Arguments;
+use descriptor::{signed_portion, ServerDescriptor};
+use document::{encode_base64, encode_hex, parse_complete_with, split_documents, Item};
+use error::Error;
+use options::ParseOptions;
+use rules::{EXTRA_INFO, SANITIZED};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use time::Timestamp;
+
+/// Counts keyed by two-letter country code, port, status or similar
+pub type Counts<T> = BTreeMap<String, T>;
+
+/// Extra-info documents are published alongside server descriptors and
+/// hold statistics that clients don't need, such as bandwidth history and
+/// usage statistics.
+///
+/// Spec defined in section 2.1.2 of dir-spec.txt
+#[derive(Debug, Clone)]
+pub struct ExtraInfo {
+    /// "extra-info" Nickname Fingerprint NL
+    /// [At start, exactly once.]
+    pub nickname: String,
+    /// Hex fingerprint of the router's identity key
+    pub fingerprint: String,
+    /// The Ed25519 certificate of the router's signing key.
+    /// [At most once]
+    pub identity_ed25519: Option<Vec<u8>>,
+    /// The time this document was generated.
+    /// [Exactly once]
+    pub published: Timestamp,
+    /// Bytes read and written by the router.
+    /// [At most once each]
+    pub read_history: Option<History>,
+    pub write_history: Option<History>,
+    pub ipv6_read_history: Option<History>,
+    pub ipv6_write_history: Option<History>,
+    /// Bytes read and written while answering directory requests.
+    /// [At most once each]
+    pub dirreq_read_history: Option<History>,
+    pub dirreq_write_history: Option<History>,
+    /// Digests of the GeoIP databases used for country statistics.
+    /// [At most once each]
+    pub geoip_db_digest: Option<String>,
+    pub geoip6_db_digest: Option<String>,
+    /// The "dirreq-*" statistics
+    pub dirreq_stats: Option<DirreqStats>,
+    /// The "entry-*" statistics
+    pub entry_stats: Option<EntryStats>,
+    /// The "cell-*" statistics
+    pub cell_stats: Option<CellStats>,
+    /// The "exit-*" statistics
+    pub exit_stats: Option<ExitStats>,
+    /// Connections that were mostly reading, writing or both.
+    /// [At most once]
+    pub conn_bi_direct: Option<ConnBiDirect>,
+    /// The "hidserv-*" statistics
+    pub hidserv_stats: Option<HidservStats>,
+    /// Counts of padding cells.
+    /// [At most once]
+    pub padding_counts: Option<PaddingCounts>,
+    /// The "bridge-*" statistics of a bridge
+    pub bridge_stats: Option<BridgeStats>,
+    /// Pluggable transports supported by a bridge, such as "obfs4"
+    /// [Any number]
+    pub transports: Vec<String>,
+    /// Items this parser doesn't know about, such as statistics added after
+    /// it was written, kept as keyword and arguments
+    pub unrecognized: Vec<(String, String)>,
+    /// Ed25519 signature of the document.
+    /// [At most once]
+    pub router_sig_ed25519: Option<Vec<u8>>,
+    /// RSA signature of the document.
+    /// [At end, exactly once]
+    pub router_signature: Vec<u8>,
+    /// The uppercase hex sha1 digest of the document as signed, as it appears
+    /// in the "extra-info-digest" line of the server descriptor
+    pub digest: String,
+    /// The base64 sha256 digest of the whole document, as it appears as the
+    /// second argument of "extra-info-digest"
+    pub digest_sha256: String,
+}
+/// The end of a statistics interval and its length
+/// YYYY-MM-DD HH:MM:SS (NSEC s)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub end: Timestamp,
+    /// Length of the interval in seconds
+    pub seconds: u64,
+}
+/// A history line: the end of the most recent interval, the interval
+/// length, and one value per interval, oldest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    pub interval: Interval,
+    pub values: Vec<u64>,
+}
+impl History {
+    /// Pairs each value with the end of the interval it covers
+    pub fn series(&self) -> Vec<(Timestamp, u64)> {
+        let count = self.values.len() as u64;
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let intervals_before_end = count - 1 - index as u64;
+                (
+                    self.interval.end - intervals_before_end * self.interval.seconds,
+                    *value,
+                )
+            })
+            .collect()
+    }
+}
+/// Statistics about directory requests
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirreqStats {
+    /// "dirreq-stats-end"
+    pub interval: Option<Interval>,
+    /// Unique IP addresses per country that requested a consensus
+    pub v3_ips: Counts<u64>,
+    /// Consensus requests per country
+    pub v3_reqs: Counts<u64>,
+    /// Responses by status, such as "ok" or "not-found"
+    pub v3_resp: Counts<u64>,
+    /// Statistics of directly downloaded consensuses
+    pub v3_direct_dl: Counts<u64>,
+    /// Statistics of consensuses downloaded over tunnelled connections
+    pub v3_tunneled_dl: Counts<u64>,
+}
+/// Statistics about clients connecting as a guard
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntryStats {
+    /// "entry-stats-end"
+    pub interval: Option<Interval>,
+    /// Unique IP addresses per country
+    pub ips: Counts<u64>,
+}
+/// Statistics about cells, with each list split into circuit deciles
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellStats {
+    /// "cell-stats-end"
+    pub interval: Option<Interval>,
+    pub processed_cells: Vec<u64>,
+    pub queued_cells: Vec<f64>,
+    /// Mean time cells spent in queue, in milliseconds
+    pub time_in_queue: Vec<u64>,
+    pub circuits_per_decile: Option<u64>,
+}
+/// Statistics about exit traffic, keyed by port or "other"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExitStats {
+    /// "exit-stats-end"
+    pub interval: Option<Interval>,
+    pub kibibytes_written: Counts<u64>,
+    pub kibibytes_read: Counts<u64>,
+    pub streams_opened: Counts<u64>,
+}
+/// "conn-bi-direct" YYYY-MM-DD HH:MM:SS (NSEC s) BELOW,READ,WRITE,BOTH NL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnBiDirect {
+    pub interval: Interval,
+    /// Connections that transferred too little data to classify
+    pub below: u64,
+    pub read: u64,
+    pub write: u64,
+    pub both: u64,
+}
+/// Obfuscated onion service statistics
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HidservStats {
+    /// "hidserv-stats-end"
+    pub interval: Option<Interval>,
+    pub rend_relayed_cells: Option<ObfuscatedValue>,
+    pub dir_onions_seen: Option<ObfuscatedValue>,
+}
+/// A statistic with noise added, along with the noise parameters such as
+/// "delta_f", "epsilon" and "bin_size"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObfuscatedValue {
+    pub value: i64,
+    pub parameters: Counts<f64>,
+}
+/// "padding-counts" YYYY-MM-DD HH:MM:SS (NSEC s) key=val ... NL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaddingCounts {
+    pub interval: Interval,
+    pub counts: Counts<u64>,
+}
+/// Statistics about clients connecting to a bridge
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BridgeStats {
+    /// "bridge-stats-end"
+    pub interval: Option<Interval>,
+    /// Unique IP addresses per country
+    pub ips: Counts<u64>,
+    /// Unique IP addresses per IP version, "v4" or "v6"
+    pub ip_versions: Counts<u64>,
+    /// Unique IP addresses per pluggable transport
+    pub ip_transports: Counts<u64>,
+}
+
+impl ExtraInfo {
+    /// Parses a single extra-info document from its exact bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single extra-info document with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let document = parse_complete_with(&input, options)?;
+        options.check_keywords(document.items(), |keyword| EXTRA_INFO.knows(keyword))?;
+        let mut extra_info = Self::from_items(document.items(), false)?;
+        extra_info.digest = encode_hex(&Sha1::digest(signed_portion(&input, "router-signature")?));
+        extra_info.digest_sha256 = encode_base64(&Sha256::digest(&input));
+        Ok(extra_info)
+    }
+    /// Parses every extra-info document in a concatenated stream, such as a
+    /// cached-extrainfo file. Annotation lines beginning with "@" are
+    /// skipped.
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every extra-info document in a concatenated stream with the given options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "extra-info")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+    /// Whether this is the extra-info document named by a server
+    /// descriptor's "extra-info-digest" line
+    pub fn matches(&self, descriptor: &ServerDescriptor) -> bool {
+        match descriptor.extra_info_digest {
+            Some((ref sha1, ref sha256)) => {
+                sha1.eq_ignore_ascii_case(&self.digest)
+                    && sha256
+                        .as_ref()
+                        .is_none_or(|sha256| sha256.trim_end_matches('=') == self.digest_sha256)
+            }
+            None => false,
+        }
+    }
+    /// Builds an extra-info document from its items, leaving the digests
+    /// empty. Sanitized bridge extra-info documents have no signatures.
+    pub(crate) fn from_items(items: &[Item], sanitized: bool) -> Result<Self, Error> {
+        EXTRA_INFO.check_counts(items, if sanitized { SANITIZED } else { &[] })?;
+        match items.last() {
+            Some(item) if sanitized || item.keyword() == "router-signature" => (),
+            _ => return Err(Error::MissingItem("router-signature")),
+        }
+        let first = match items.first() {
+            Some(item) if item.keyword() == "extra-info" => item,
+            _ => return Err(Error::MissingItem("extra-info")),
+        };
+        let arguments = first.args();
+        let mut extra_info = ExtraInfo {
+            nickname: arguments.arg(0)?,
+            fingerprint: arguments.arg(1)?,
+            identity_ed25519: None,
+            published: Timestamp::from_unix(0),
+            read_history: None,
+            write_history: None,
+            ipv6_read_history: None,
+            ipv6_write_history: None,
+            dirreq_read_history: None,
+            dirreq_write_history: None,
+            geoip_db_digest: None,
+            geoip6_db_digest: None,
+            dirreq_stats: None,
+            entry_stats: None,
+            cell_stats: None,
+            exit_stats: None,
+            conn_bi_direct: None,
+            hidserv_stats: None,
+            padding_counts: None,
+            bridge_stats: None,
+            transports: Vec::new(),
+            unrecognized: Vec::new(),
+            router_sig_ed25519: None,
+            router_signature: Vec::new(),
+            digest: String::new(),
+            digest_sha256: String::new(),
+        };
+        for item in &items[1..] {
+            let arguments = item.args();
+            match item.keyword() {
+                "identity-ed25519" => {
+                    extra_info.identity_ed25519 = Some(item.object("ED25519 CERT")?)
+                }
+                "published" => extra_info.published = arguments.arg_datetime(0, 1)?,
+                "read-history" => extra_info.read_history = Some(parse_history(&arguments)?),
+                "write-history" => extra_info.write_history = Some(parse_history(&arguments)?),
+                "ipv6-read-history" => {
+                    extra_info.ipv6_read_history = Some(parse_history(&arguments)?)
+                }
+                "ipv6-write-history" => {
+                    extra_info.ipv6_write_history = Some(parse_history(&arguments)?)
+                }
+                "dirreq-read-history" => {
+                    extra_info.dirreq_read_history = Some(parse_history(&arguments)?)
+                }
+                "dirreq-write-history" => {
+                    extra_info.dirreq_write_history = Some(parse_history(&arguments)?)
+                }
+                "geoip-db-digest" => extra_info.geoip_db_digest = Some(arguments.arg(0)?),
+                "geoip6-db-digest" => extra_info.geoip6_db_digest = Some(arguments.arg(0)?),
+                "dirreq-stats-end" => {
+                    dirreq(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "dirreq-v3-ips" => dirreq(&mut extra_info).v3_ips = parse_counts(&arguments, 0)?,
+                "dirreq-v3-reqs" => dirreq(&mut extra_info).v3_reqs = parse_counts(&arguments, 0)?,
+                "dirreq-v3-resp" => dirreq(&mut extra_info).v3_resp = parse_counts(&arguments, 0)?,
+                "dirreq-v3-direct-dl" => {
+                    dirreq(&mut extra_info).v3_direct_dl = parse_counts(&arguments, 0)?
+                }
+                "dirreq-v3-tunneled-dl" => {
+                    dirreq(&mut extra_info).v3_tunneled_dl = parse_counts(&arguments, 0)?
+                }
+                "entry-stats-end" => {
+                    entry(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "entry-ips" => entry(&mut extra_info).ips = parse_counts(&arguments, 0)?,
+                "cell-stats-end" => {
+                    cell(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "cell-processed-cells" => {
+                    cell(&mut extra_info).processed_cells = parse_list(&arguments, 0)?
+                }
+                "cell-queued-cells" => {
+                    cell(&mut extra_info).queued_cells = parse_list(&arguments, 0)?
+                }
+                "cell-time-in-queue" => {
+                    cell(&mut extra_info).time_in_queue = parse_list(&arguments, 0)?
+                }
+                "cell-circuits-per-decile" => {
+                    cell(&mut extra_info).circuits_per_decile = Some(arguments.arg(0)?)
+                }
+                "exit-stats-end" => {
+                    exit(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "exit-kibibytes-written" => {
+                    exit(&mut extra_info).kibibytes_written = parse_counts(&arguments, 0)?
+                }
+                "exit-kibibytes-read" => {
+                    exit(&mut extra_info).kibibytes_read = parse_counts(&arguments, 0)?
+                }
+                "exit-streams-opened" => {
+                    exit(&mut extra_info).streams_opened = parse_counts(&arguments, 0)?
+                }
+                "conn-bi-direct" => {
+                    let counts: Vec<u64> = parse_list(&arguments, 4)?;
+                    if counts.len() != 4 {
+                        return Err(arguments.error(4, "expected four counts"));
+                    }
+                    extra_info.conn_bi_direct = Some(ConnBiDirect {
+                        interval: parse_interval(&arguments)?,
+                        below: counts[0],
+                        read: counts[1],
+                        write: counts[2],
+                        both: counts[3],
+                    });
+                }
+                "hidserv-stats-end" => {
+                    hidserv(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "hidserv-rend-relayed-cells" => {
+                    hidserv(&mut extra_info).rend_relayed_cells =
+                        Some(parse_obfuscated(&arguments)?)
+                }
+                "hidserv-dir-onions-seen" => {
+                    hidserv(&mut extra_info).dir_onions_seen = Some(parse_obfuscated(&arguments)?)
+                }
+                "padding-counts" => {
+                    extra_info.padding_counts = Some(PaddingCounts {
+                        interval: parse_interval(&arguments)?,
+                        counts: parse_counts(&arguments, 4)?,
+                    })
+                }
+                "bridge-stats-end" => {
+                    bridge(&mut extra_info).interval = Some(parse_interval(&arguments)?)
+                }
+                "bridge-ips" => bridge(&mut extra_info).ips = parse_counts(&arguments, 0)?,
+                "bridge-ip-versions" => {
+                    bridge(&mut extra_info).ip_versions = parse_counts(&arguments, 0)?
+                }
+                "bridge-ip-transports" => {
+                    bridge(&mut extra_info).ip_transports = parse_counts(&arguments, 0)?
+                }
+                "transport" => extra_info.transports.push(item.arguments()),
+                "router-sig-ed25519" => {
+                    extra_info.router_sig_ed25519 = Some(arguments.arg_base64(0)?)
+                }
+                "router-signature" => extra_info.router_signature = item.object("SIGNATURE")?,
+                // Read by the bridge module, which knows about sanitization
+                "router-digest" | "router-digest-sha256" if sanitized => (),
+                keyword => extra_info
+                    .unrecognized
+                    .push((keyword.to_string(), item.arguments())),
+            }
+        }
+        Ok(extra_info)
+    }
+}
+
+/// Accessors that create each group of statistics the first time one of its
+/// lines is seen
+fn dirreq(extra_info: &mut ExtraInfo) -> &mut DirreqStats {
+    extra_info.dirreq_stats.get_or_insert_with(Default::default)
+}
+fn entry(extra_info: &mut ExtraInfo) -> &mut EntryStats {
+    extra_info.entry_stats.get_or_insert_with(Default::default)
+}
+fn cell(extra_info: &mut ExtraInfo) -> &mut CellStats {
+    extra_info.cell_stats.get_or_insert_with(Default::default)
+}
+fn exit(extra_info: &mut ExtraInfo) -> &mut ExitStats {
+    extra_info.exit_stats.get_or_insert_with(Default::default)
+}
+fn hidserv(extra_info: &mut ExtraInfo) -> &mut HidservStats {
+    extra_info
+        .hidserv_stats
+        .get_or_insert_with(Default::default)
+}
+fn bridge(extra_info: &mut ExtraInfo) -> &mut BridgeStats {
+    extra_info.bridge_stats.get_or_insert_with(Default::default)
+}
+
+/// YYYY-MM-DD HH:MM:SS (NSEC s)
+fn parse_interval(arguments: &Arguments) -> Result<Interval, Error> {
+    let seconds = match (arguments.str(2), arguments.str(3)) {
+        (Ok(seconds), Ok("s)")) if seconds.starts_with('(') => seconds[1..].parse().ok(),
+        _ => None,
+    };
+    Ok(Interval {
+        end: arguments.arg_datetime(0, 1)?,
+        seconds: seconds.ok_or_else(|| arguments.error(2, "malformed interval"))?,
+    })
+}
+/// YYYY-MM-DD HH:MM:SS (NSEC s) NUM,NUM,NUM,NUM,NUM... NL
+fn parse_history(arguments: &Arguments) -> Result<History, Error> {
+    Ok(History {
+        interval: parse_interval(arguments)?,
+        values: parse_list(arguments, 4)?,
+    })
+}
+/// The comma-separated values of the arguments from `start`, each with
+/// the index of its argument
+fn split_values<'a>(
+    arguments: &Arguments<'a>,
+    start: usize,
+) -> Result<Vec<(usize, &'a str)>, Error> {
+    let mut values = Vec::new();
+    for index in start..arguments.len() {
+        for value in arguments.str(index)?.split(',') {
+            if !value.is_empty() {
+                values.push((index, value));
+            }
+        }
+    }
+    Ok(values)
+}
+/// Parses a comma-separated list from the argument at `start` on, which may
+/// be empty
+fn parse_list<T: FromStr>(arguments: &Arguments, start: usize) -> Result<Vec<T>, Error> {
+    split_values(arguments, start)?
+        .into_iter()
+        .map(|(index, value)| {
+            value
+                .parse()
+                .map_err(|_| arguments.error(index, format!("malformed value \"{}\"", value)))
+        })
+        .collect()
+}
+/// Parses comma- or space-separated key=value pairs, such as "us=16,de=8",
+/// from the argument at `start` on
+fn parse_counts<T: FromStr>(arguments: &Arguments, start: usize) -> Result<Counts<T>, Error> {
+    split_values(arguments, start)?
+        .into_iter()
+        .map(|(index, pair)| {
+            let malformed = || arguments.error(index, format!("malformed pair \"{}\"", pair));
+            let equals = pair
+                .find('=')
+                .filter(|&equals| equals > 0)
+                .ok_or_else(malformed)?;
+            let value = pair[equals + 1..].parse().map_err(|_| malformed())?;
+            Ok((pair[..equals].to_string(), value))
+        })
+        .collect()
+}
+/// SP num SP key=val SP key=val ... NL
+fn parse_obfuscated(arguments: &Arguments) -> Result<ObfuscatedValue, Error> {
+    Ok(ObfuscatedValue {
+        value: arguments.arg(0)?,
+        parameters: parse_counts(arguments, 1)?,
+    })
+}
+
+/// Test
+#[test]
+fn test_parse_extra_info() {
+    let extra_infos = ExtraInfo::parse_all(include_bytes!("../test/extra-infos"))
+        .expect("failed to parse extra-info documents");
+    let extra_info = &extra_infos[0];
+    let history = extra_info.write_history.as_ref().unwrap();
+    let series = history.series();
+    assert_eq!(series.len(), 4);
+    assert_eq!(series[3].0, history.interval.end);
+    assert_eq!(series[0].0.to_string(), "2018-02-07 13:38:43");
+    let dirreq = extra_info.dirreq_stats.as_ref().unwrap();
+    assert_eq!(dirreq.v3_ips["us"], 16);
+    assert_eq!(
+        extra_info.exit_stats.as_ref().unwrap().streams_opened["443"],
+        4
+    );
+    assert_eq!(extra_info.unrecognized[0].0, "future-stats-end");
+    let descriptors = ServerDescriptor::parse_all(include_bytes!("../test/server-descriptors"))
+        .expect("failed to parse descriptors");
+    assert!(extra_info.matches(&descriptors[1]));
+}
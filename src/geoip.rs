@@ -0,0 +1,131 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::Router;
+use error::Error;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// A GeoIP database in the format tor ships as its "geoip" and "geoip6"
+/// files, mapping address ranges to two-letter country codes.
+///
+/// IPv4 lines are "INTIPLOW,INTIPHIGH,CC" with the addresses as decimal
+/// integers, IPv6 lines are "IPV6LOW,IPV6HIGH,CC", and lines starting with
+/// "#" are comments.
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    /// Inclusive IPv4 ranges, sorted by their first address
+    ipv4: Vec<(u32, u32, String)>,
+    /// Inclusive IPv6 ranges, sorted by their first address
+    ipv6: Vec<(u128, u128, String)>,
+}
+impl GeoIp {
+    /// Parses a geoip or geoip6 file
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        let mut geoip = GeoIp::default();
+        geoip.add(input)?;
+        Ok(geoip)
+    }
+    /// Adds the ranges in a geoip or geoip6 file, so that one database can
+    /// cover both address families
+    pub fn add(&mut self, input: &[u8]) -> Result<(), Error> {
+        let mut offset = 0;
+        for line in input.split(|byte| *byte == b'\n') {
+            let line_offset = offset;
+            offset += line.len() + 1;
+            let line = ::std::str::from_utf8(line).map_err(|_| Error::Syntax(line_offset))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',');
+            let (low, high, country) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(low), Some(high), Some(country)) if country.len() == 2 => {
+                    (low, high, country.to_uppercase())
+                }
+                _ => return Err(Error::Syntax(line_offset)),
+            };
+            if let (Ok(low), Ok(high)) = (low.parse::<u32>(), high.parse::<u32>()) {
+                self.ipv4.push((low, high, country));
+            } else if let (Ok(low), Ok(high)) = (low.parse::<Ipv6Addr>(), high.parse::<Ipv6Addr>())
+            {
+                self.ipv6.push((low.into(), high.into(), country));
+            } else {
+                return Err(Error::Syntax(line_offset));
+            }
+        }
+        self.ipv4.sort();
+        self.ipv6.sort();
+        Ok(())
+    }
+    /// The country code of an address, if a range covers it
+    pub fn country(&self, address: IpAddr) -> Option<&str> {
+        match address {
+            IpAddr::V4(address) => lookup(&self.ipv4, u32::from(address)),
+            IpAddr::V6(address) => lookup(&self.ipv6, u128::from(address)),
+        }
+    }
+    /// The country code of a relay's IPv4 address, which is what tor and
+    /// Relay Search use
+    pub fn router_country(&self, router: &Router) -> Option<&str> {
+        self.country(IpAddr::V4(router.ip))
+    }
+    /// Whether the database has no ranges
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+}
+
+/// Finds the country of the last range starting at or before the address
+fn lookup<T: Ord + Copy>(ranges: &[(T, T, String)], address: T) -> Option<&str> {
+    let after = ranges.partition_point(|range| range.0 <= address);
+    match after.checked_sub(1).map(|index| &ranges[index]) {
+        Some(&(_, high, ref country)) if address <= high && country != "??" => {
+            Some(country.as_str())
+        }
+        _ => None,
+    }
+}
+
+/// Test
+#[test]
+fn test_geoip() {
+    let mut geoip = GeoIp::parse(
+        b"# Last updated based on February 6 2018 Maxmind GeoLite2 Country\n\
+          16777216,16777471,au\n\
+          1134559232,1134690303,US\n\
+          2734489600,2734555135,??\n",
+    )
+    .unwrap();
+    geoip
+        .add(b"2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP\n")
+        .unwrap();
+    let country = |address: &str| geoip.country(address.parse().unwrap());
+    assert_eq!(country("1.0.0.1"), Some("AU"));
+    assert_eq!(country("67.161.31.147"), Some("US"));
+    assert_eq!(country("1.0.1.0"), None);
+    assert_eq!(country("162.253.0.1"), None);
+    assert_eq!(country("2001:200::1"), Some("JP"));
+    assert_eq!(country("2001:201::1"), None);
+    assert_eq!(
+        geoip.country(IpAddr::V4(::std::net::Ipv4Addr::new(0, 0, 0, 0))),
+        None
+    );
+    assert_eq!(GeoIp::parse(b"1,2\n").unwrap_err(), Error::Syntax(0));
+    assert_eq!(
+        GeoIp::parse(b"# comment\n1,two,US\n").unwrap_err(),
+        Error::Syntax(10)
+    );
+}
@@ -0,0 +1,316 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{ConsensusDocument, Router};
+use document::decode_base64;
+use error::Error;
+use onion::{OnionAddress, TimePeriod, DEFAULT_PERIOD_LENGTH, ROTATION_OFFSET};
+use sha3::{Digest, Sha3_256};
+
+/// Default number of descriptor replicas, the "hsdir_n_replicas" parameter
+pub const DEFAULT_N_REPLICAS: i32 = 2;
+/// Default number of HSDirs each replica is stored on, the
+/// "hsdir_spread_store" parameter
+pub const DEFAULT_SPREAD_STORE: i32 = 4;
+
+/// An onion service is identified either by its address, from which the
+/// blinded key of every period can be derived, or by the blinded key of a
+/// single period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceKey {
+    Address(OnionAddress),
+    Blinded([u8; 32]),
+}
+/// The HSDirs of a consensus, sorted by their position on the hash ring of
+/// one time period.
+///
+/// Spec defined in section 2.2.3 of rend-spec-v3.txt
+#[derive(Debug, Clone)]
+pub struct HsDirRing<'a> {
+    pub period: TimePeriod,
+    /// The shared random value the positions were derived from
+    pub shared_random_value: Vec<u8>,
+    /// hsdir_index(node) and the node, in ascending order of index
+    pub positions: Vec<([u8; 32], &'a Router)>,
+}
+/// The HSDirs a descriptor is stored on during one time period
+#[derive(Debug, Clone)]
+pub struct ResponsibleHsDirs<'a> {
+    pub period: TimePeriod,
+    pub blinded_key: [u8; 32],
+    /// In the order they were chosen, replica by replica
+    pub hsdirs: Vec<&'a Router>,
+}
+
+impl<'a> HsDirRing<'a> {
+    /// Places every HSDir-flagged relay with an Ed25519 identity on the ring
+    /// of a time period.
+    /// `ed25519_id` finds the identity of a relay, which consensuses don't
+    /// carry themselves; see `router_ed25519_id` for votes.
+    pub fn new<F>(
+        consensus: &'a ConsensusDocument,
+        period: TimePeriod,
+        shared_random_value: Vec<u8>,
+        ed25519_id: F,
+    ) -> Self
+    where
+        F: Fn(&Router) -> Option<[u8; 32]>,
+    {
+        let mut positions: Vec<([u8; 32], &'a Router)> = consensus
+            .routers
+            .iter()
+            .filter(|router| router.has_flag("HSDir"))
+            .filter_map(|router| {
+                ed25519_id(router)
+                    .map(|identity| (hsdir_index(&identity, &shared_random_value, period), router))
+            })
+            .collect();
+        positions.sort_by_key(|&(index, _)| index);
+        HsDirRing {
+            period,
+            shared_random_value,
+            positions,
+        }
+    }
+    /// The HSDirs that store a descriptor with the given blinded key: for
+    /// each replica, the first `spread_store` relays at or after
+    /// hs_index(replicanum) on the ring that weren't already chosen
+    pub fn responsible(
+        &self,
+        blinded_key: &[u8; 32],
+        n_replicas: u64,
+        spread_store: usize,
+    ) -> Vec<&'a Router> {
+        let mut hsdirs: Vec<&'a Router> = Vec::new();
+        if self.positions.is_empty() {
+            return hsdirs;
+        }
+        for replica in 1..=n_replicas {
+            let hs_index = hs_index(blinded_key, replica, self.period);
+            let start = self
+                .positions
+                .iter()
+                .position(|&(index, _)| index >= hs_index)
+                .unwrap_or(0);
+            let mut added = 0;
+            for offset in 0..self.positions.len() {
+                if added == spread_store {
+                    break;
+                }
+                let router = self.positions[(start + offset) % self.positions.len()].1;
+                if !hsdirs.iter().any(|chosen| ::std::ptr::eq(*chosen, router)) {
+                    hsdirs.push(router);
+                    added += 1;
+                }
+            }
+        }
+        hsdirs
+    }
+}
+
+/// The position of an HSDir on the hash ring of a time period:
+/// hsdir_index(node) = H("node-idx" | node_identity |
+///     shared_random_value | INT_8(period_num) | INT_8(period_length))
+pub fn hsdir_index(
+    identity: &[u8; 32],
+    shared_random_value: &[u8],
+    period: TimePeriod,
+) -> [u8; 32] {
+    Sha3_256::new()
+        .chain_update(b"node-idx")
+        .chain_update(identity)
+        .chain_update(shared_random_value)
+        .chain_update(period.number.to_be_bytes())
+        .chain_update(period.length.to_be_bytes())
+        .finalize()
+        .into()
+}
+/// The position a replica of a descriptor is stored at on the hash ring:
+/// hs_index(replicanum) = H("store-at-idx" | blinded_public_key |
+///     INT_8(replicanum) | INT_8(period_length) | INT_8(period_num))
+pub fn hs_index(blinded_key: &[u8; 32], replica: u64, period: TimePeriod) -> [u8; 32] {
+    Sha3_256::new()
+        .chain_update(b"store-at-idx")
+        .chain_update(blinded_key)
+        .chain_update(replica.to_be_bytes())
+        .chain_update(period.length.to_be_bytes())
+        .chain_update(period.number.to_be_bytes())
+        .finalize()
+        .into()
+}
+/// The time period a consensus was valid in, using its "hsdir-interval"
+/// parameter as the period length
+pub fn current_period(consensus: &ConsensusDocument) -> TimePeriod {
+    let length = match consensus.param("hsdir-interval") {
+        Some(length) if length > 0 => length as u64,
+        _ => DEFAULT_PERIOD_LENGTH,
+    };
+    TimePeriod::containing(consensus.valid_after, length)
+}
+/// The shared random value in effect when a time period began, which is the
+/// one its ring is built from.
+///
+/// New values are published every `2 * ROTATION_OFFSET` minutes, halfway
+/// through a time period, so a consensus knows the value for its own period
+/// and, in the second half of it, for the next. Later periods return None.
+/// When the consensus is missing a value it should have, the disaster value
+/// from section 2.3.4 of srv-spec.txt is used, as tor does.
+pub fn shared_random_value(
+    consensus: &ConsensusDocument,
+    period: TimePeriod,
+) -> Result<Option<Vec<u8>>, Error> {
+    let valid_after = consensus.valid_after;
+    let run_length = 2 * ROTATION_OFFSET * 60;
+    let latest_run = valid_after.unix() / run_length;
+    let period_run = period.start().unix() / run_length;
+    let value = if period_run == latest_run {
+        &consensus.shared_rand_current_value
+    } else if period_run + 1 == latest_run {
+        &consensus.shared_rand_previous_value
+    } else if period_run > latest_run {
+        return Ok(None);
+    } else {
+        return Err(Error::invalid(
+            "valid-after",
+            "the consensus is too new to know this period's shared random value",
+        ));
+    };
+    Ok(Some(match *value {
+        Some((_, ref value)) => value.clone(),
+        // disaster_srv = H("shared-random-disaster" | INT_8(period_length) |
+        //     INT_8(period_num))
+        None => Sha3_256::new()
+            .chain_update(b"shared-random-disaster")
+            .chain_update(period.length.to_be_bytes())
+            .chain_update(period.number.to_be_bytes())
+            .finalize()
+            .to_vec(),
+    }))
+}
+/// The HSDirs responsible for a service in the consensus's current time
+/// period and, once its shared random value is known, the next one.
+///
+/// A blinded key belongs to a single period, so with `ServiceKey::Blinded`
+/// the same key is placed on both rings; it is usually only meaningful for
+/// the period it was derived for.
+pub fn responsible_hsdirs<'a, F>(
+    consensus: &'a ConsensusDocument,
+    service: &ServiceKey,
+    ed25519_id: F,
+) -> Result<(ResponsibleHsDirs<'a>, Option<ResponsibleHsDirs<'a>>), Error>
+where
+    F: Fn(&Router) -> Option<[u8; 32]>,
+{
+    let n_replicas = consensus
+        .param("hsdir_n_replicas")
+        .unwrap_or(DEFAULT_N_REPLICAS)
+        .max(1) as u64;
+    let spread_store = consensus
+        .param("hsdir_spread_store")
+        .unwrap_or(DEFAULT_SPREAD_STORE)
+        .max(1) as usize;
+    let responsible = |period: TimePeriod, value: Vec<u8>| -> Result<_, Error> {
+        let blinded_key = match *service {
+            ServiceKey::Address(ref address) => address.blinded_key(period)?,
+            ServiceKey::Blinded(key) => key,
+        };
+        let ring = HsDirRing::new(consensus, period, value, &ed25519_id);
+        Ok(ResponsibleHsDirs {
+            period,
+            blinded_key,
+            hsdirs: ring.responsible(&blinded_key, n_replicas, spread_store),
+        })
+    };
+    let current = current_period(consensus);
+    let value = shared_random_value(consensus, current)?
+        .ok_or(Error::MissingItem("shared-rand-current-value"))?;
+    let next = match shared_random_value(consensus, current.next())? {
+        Some(value) => Some(responsible(current.next(), value)?),
+        None => None,
+    };
+    Ok((responsible(current, value)?, next))
+}
+/// The Ed25519 identity of a relay in a vote, from its "id" line
+pub fn router_ed25519_id(router: &Router) -> Option<[u8; 32]> {
+    match router.ed25519_id {
+        Some(Some(ref identity)) => decode_base64(identity).and_then(|identity| {
+            if identity.len() == 32 {
+                let mut key = [0; 32];
+                key.copy_from_slice(&identity);
+                Some(key)
+            } else {
+                None
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Test
+#[test]
+fn test_responsible_hsdirs() {
+    use document::decode_hex;
+    use sha2::Sha256;
+    // Known answers for a key of 42s and a shared random value of 43s in
+    // period 42 of the default length, computed independently with
+    // Python's hashlib.sha3_256
+    let period = TimePeriod {
+        number: 42,
+        length: DEFAULT_PERIOD_LENGTH,
+    };
+    assert_eq!(
+        hs_index(&[42; 32], 1, period).to_vec(),
+        decode_hex("b1a61fa67525d5f47f77e52012a130eaf08ac1eccd2dd65c1a92bdaa5e6f7a27").unwrap()
+    );
+    assert_eq!(
+        hsdir_index(&[42; 32], &[43; 32], period).to_vec(),
+        decode_hex("3876a9f7ddca5cefc9e190a3639c93d2741f6fa9c4cc0af37750cddc89ec8b16").unwrap()
+    );
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/consensus"))
+        .expect("failed to parse consensus");
+    // The consensus has no Ed25519 identities, so derive stand-ins from the
+    // RSA identities
+    let ed25519_id = |router: &Router| {
+        decode_base64(&router.identity).map(|identity| Sha256::digest(identity).into())
+    };
+    let address =
+        OnionAddress::parse("aoqqpp7tzyil4hlq3umoos6atft6jvrqtosq2xy53sdgiesvgg4bqead.onion")
+            .unwrap();
+    let (current, next) = responsible_hsdirs(&consensus, &ServiceKey::Address(address), ed25519_id)
+        .expect("failed to compute responsible HSDirs");
+    assert_eq!(current.period.number, 17570);
+    let nicknames: Vec<&str> = current
+        .hsdirs
+        .iter()
+        .map(|router| router.nickname.as_str())
+        .collect();
+    assert_eq!(
+        nicknames,
+        vec![
+            "FreedomForParrots",
+            "yesieditedtheconfig",
+            "zabrelay",
+            "floberelay2",
+            "RocklinTorRelay",
+            "dc6jgk5b",
+            "tsumura",
+            "ididntdoitmortician",
+        ]
+    );
+    let next = next.expect("the next period's shared random value is known");
+    assert_eq!(next.period.number, 17571);
+    assert_eq!(next.hsdirs[0].nickname, "mdfnet1");
+}
@@ -0,0 +1,173 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use document::{decode_base64, decode_hex, encode_base64, encode_hex};
+use std::fmt;
+
+/// The SHA-1 digest of a relay's RSA identity key, which is how relays and
+/// authorities are named throughout the directory protocol.
+///
+/// The same digest is written several ways: unpadded base64 on "r" lines,
+/// uppercase hex on "dir-source" and "directory-signature" lines, hex in
+/// groups of four on descriptor "fingerprint" lines and '$'-prefixed hex on
+/// "family" lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RsaIdentity(pub [u8; 20]);
+impl RsaIdentity {
+    /// Builds an identity from exactly 20 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut identity = [0; 20];
+        if bytes.len() != identity.len() {
+            return None;
+        }
+        identity.copy_from_slice(bytes);
+        Some(RsaIdentity(identity))
+    }
+    /// Decodes base64 with or without trailing `=` padding
+    pub fn from_base64(base64: &str) -> Option<Self> {
+        decode_base64(base64).and_then(|bytes| Self::from_bytes(&bytes))
+    }
+    /// Decodes 40 hex digits of either case
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        decode_hex(hex).and_then(|bytes| Self::from_bytes(&bytes))
+    }
+    /// Parses an identity in any of the forms it is written in, including
+    /// "$"-prefixed family members with a "=nickname" or "~nickname" suffix
+    /// and fingerprints with spaces between groups of digits
+    pub fn parse(identity: &str) -> Option<Self> {
+        let identity = identity.trim();
+        if let Some(hex) = identity.strip_prefix('$') {
+            let end = hex.find(['=', '~']).unwrap_or(hex.len());
+            return Self::from_hex(&hex[..end]);
+        }
+        let compact: String = identity.chars().filter(|c| *c != ' ').collect();
+        if compact.len() == 40 {
+            Self::from_hex(&compact)
+        } else {
+            Self::from_base64(&compact)
+        }
+    }
+    /// The digest
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+    /// Unpadded base64, as on "r" lines
+    pub fn to_base64(&self) -> String {
+        encode_base64(&self.0)
+    }
+    /// Uppercase hex, as on "dir-source" lines
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.0)
+    }
+    /// Uppercase hex in groups of four, as on descriptor "fingerprint" lines
+    pub fn to_spaced_hex(&self) -> String {
+        self.0
+            .chunks(2)
+            .map(encode_hex)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+impl fmt::Display for RsaIdentity {
+    /// Writes the identity as uppercase hex, the form users paste
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// A relay's Ed25519 identity key, written in unpadded base64 on "id
+/// ed25519" lines of votes and microdescriptors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ed25519Identity(pub [u8; 32]);
+impl Ed25519Identity {
+    /// Builds an identity from exactly 32 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut identity = [0; 32];
+        if bytes.len() != identity.len() {
+            return None;
+        }
+        identity.copy_from_slice(bytes);
+        Some(Ed25519Identity(identity))
+    }
+    /// Decodes base64 with or without trailing `=` padding
+    pub fn from_base64(base64: &str) -> Option<Self> {
+        decode_base64(base64).and_then(|bytes| Self::from_bytes(&bytes))
+    }
+    /// Decodes 64 hex digits of either case
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        decode_hex(hex).and_then(|bytes| Self::from_bytes(&bytes))
+    }
+    /// Parses an identity written in either base64 or hex
+    pub fn parse(identity: &str) -> Option<Self> {
+        let identity = identity.trim();
+        if identity.len() == 64 {
+            Self::from_hex(identity)
+        } else {
+            Self::from_base64(identity)
+        }
+    }
+    /// The key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    /// Unpadded base64, as on "id ed25519" lines
+    pub fn to_base64(&self) -> String {
+        encode_base64(&self.0)
+    }
+    /// Uppercase hex
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.0)
+    }
+}
+impl fmt::Display for Ed25519Identity {
+    /// Writes the identity as unpadded base64
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_base64())
+    }
+}
+
+/// Test
+#[test]
+fn test_identity() {
+    let identity = RsaIdentity::parse("AAoQ1DAR6kkoo19hBAX5K0QztNw").unwrap();
+    let hex = "000A10D43011EA4928A35F610405F92B4433B4DC";
+    assert_eq!(identity.to_hex(), hex);
+    assert_eq!(RsaIdentity::parse(hex), Some(identity));
+    assert_eq!(RsaIdentity::parse(&hex.to_lowercase()), Some(identity));
+    assert_eq!(
+        RsaIdentity::parse("AAoQ1DAR6kkoo19hBAX5K0QztNw="),
+        Some(identity)
+    );
+    let spaced = identity.to_spaced_hex();
+    assert_eq!(spaced, "000A 10D4 3011 EA49 28A3 5F61 0405 F92B 4433 B4DC");
+    assert_eq!(RsaIdentity::parse(&spaced), Some(identity));
+    assert_eq!(
+        RsaIdentity::parse(&format!("${}~seele", hex)),
+        Some(identity)
+    );
+    assert_eq!(
+        RsaIdentity::parse(&format!("${}=seele", hex)),
+        Some(identity)
+    );
+    assert_eq!(RsaIdentity::parse("seele"), None);
+    assert_eq!(RsaIdentity::parse(&hex[2..]), None);
+    let ed25519 = Ed25519Identity::parse("V4S7mH3nZy4I6hjcuTWqsdAr9HPdn85/AbeYMV9LQwE").unwrap();
+    assert_eq!(Ed25519Identity::parse(&ed25519.to_hex()), Some(ed25519));
+    assert_eq!(
+        ed25519.to_string(),
+        "V4S7mH3nZy4I6hjcuTWqsdAr9HPdn85/AbeYMV9LQwE"
+    );
+}
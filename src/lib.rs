@@ -14,7 +14,60 @@
     You should have received a copy of the GNU General Public License
     along with onyan.  If not, see <http://www.gnu.org/licenses/>.
 */
+extern crate aes;
+extern crate base64;
+extern crate ctr;
+extern crate curve25519_dalek;
+#[cfg(feature = "archive")]
+extern crate flate2;
 #[macro_use]
 extern crate nom;
+extern crate rsa;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate sha3;
+#[cfg(feature = "archive")]
+extern crate tar;
+#[cfg(feature = "archive")]
+extern crate xz2;
+#[cfg(feature = "archive")]
+extern crate zstd;
+pub mod annotation;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod arguments;
+pub mod bandwidth;
+pub mod bridge;
+pub mod certificate;
+pub mod compare;
+pub mod consdiff;
 pub mod consensus;
+pub mod descriptor;
+pub mod detached;
 pub mod document;
+pub mod error;
+pub mod extrainfo;
+pub mod geoip;
+pub mod hsdir;
+pub mod identity;
+pub mod microdescriptor;
+pub mod onion;
+pub mod options;
+pub mod query;
+pub mod rules;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod signature;
+pub mod stats;
+pub mod time;
+pub mod version;
+
+pub use annotation::{parse_any, parse_any_with, AnyDocument};
+pub use options::ParseOptions;
@@ -0,0 +1,883 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! `onyan` answers questions about Tor directory documents from the
+//! terminal. Every command reads a file, or standard input when the file is
+//! missing or "-", and exits with 0 when all is well, 1 when it found
+//! problems and 2 when it could not run.
+extern crate onyan;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+
+use onyan::annotation::{validate_any_with, Annotation};
+use onyan::certificate::KeyCertificate;
+use onyan::consdiff::ConsensusDiff;
+use onyan::consensus::{ConsensusDocument, Router};
+use onyan::error::Error;
+use onyan::geoip::GeoIp;
+use onyan::query::{Comparison, Query};
+use onyan::signature::SignatureStatus;
+use onyan::version::TorVersion;
+use onyan::{parse_any_with, AnyDocument, ParseOptions};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+const USAGE: &str = "\
+usage: onyan COMMAND [OPTIONS] [ARGUMENTS]
+
+Files may be \"-\" or left out to read standard input.
+
+commands:
+  parse [--json] [FILE]          print any document, as Rust debug output or JSON
+  validate [FILE]                report spec violations with line numbers
+  relays [FILTERS] [--csv] [FILE]
+                                 list the relays of a consensus
+  show FINGERPRINT [FILE]        print one relay of a consensus
+  diff [--consdiff] OLD NEW      compare two consensuses, or print a consdiff
+  verify --certs DIR [FILE]      check that a majority of the authorities
+                                 whose key certificates are in DIR signed
+
+relay filters, which must all match and may be repeated:
+  --where QUERY                  matches a query such as
+                                 \"Exit AND bandwidth > 5000 AND allows port 443\"
+                                 with conditions on flags, nickname, fingerprint,
+                                 address, port, bandwidth, version and supports
+  --flag FLAG                    has the flag
+  --version VERSION              runs the Tor version, or any of its series
+                                 when there's no patch level
+  --min-version VERSION          runs at least the Tor version
+  --port PORT                    exit policy summary allows the port
+  --country CC                   address is in the country, with --geoip
+  --geoip FILE                   tor geoip or geoip6 file
+  --min-bandwidth WEIGHT         has at least the consensus weight
+
+parsing:
+  --strict                       reject anything the spec doesn't allow
+  --lenient                      accept whatever can be made sense of
+";
+
+/// Appends to the output of a command, which is written once the command
+/// is done
+macro_rules! out {
+    ($out:expr, $($format:tt)*) => {
+        $out.push_str(&format!($($format)*))
+    };
+}
+/// Appends a line to the output of a command
+macro_rules! outln {
+    ($out:expr) => {
+        $out.push('\n')
+    };
+    ($out:expr, $($format:tt)*) => {{
+        out!($out, $($format)*);
+        $out.push('\n');
+    }};
+}
+
+/// Exit status when the command found nothing wrong
+const SUCCESS: i32 = 0;
+/// Exit status when the command ran but found problems
+const PROBLEMS: i32 = 1;
+/// Exit status when the command could not run
+const FAILURE: i32 = 2;
+
+/// Options every command accepts
+const PARSING: &[&str] = &["--strict", "--lenient"];
+
+/// The options and other arguments after the command
+struct Arguments {
+    /// Switches that were given
+    switches: Vec<String>,
+    /// Options with values, in the order they were given
+    values: Vec<(String, String)>,
+    /// Everything else
+    positional: Vec<String>,
+}
+impl Arguments {
+    /// Sorts the arguments, accepting only the given switches and options
+    /// with values besides the parsing switches
+    fn parse(arguments: &[String], switches: &[&str], values: &[&str]) -> Result<Self, String> {
+        let mut parsed = Arguments {
+            switches: Vec::new(),
+            values: Vec::new(),
+            positional: Vec::new(),
+        };
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            let name = argument.as_str();
+            if switches.contains(&name) || PARSING.contains(&name) {
+                parsed.switches.push(argument.clone());
+            } else if values.contains(&name) {
+                let value = arguments
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", name))?;
+                parsed.values.push((argument.clone(), value.clone()));
+            } else if name.starts_with("--") {
+                return Err(format!("unknown option {}", name));
+            } else {
+                parsed.positional.push(argument.clone());
+            }
+        }
+        Ok(parsed)
+    }
+    fn has(&self, switch: &str) -> bool {
+        self.switches.iter().any(|other| other == switch)
+    }
+    /// The last value given for an option
+    fn value(&self, name: &str) -> Option<&str> {
+        self.all(name).pop()
+    }
+    /// Every value given for an option
+    fn all(&self, name: &str) -> Vec<&str> {
+        self.values
+            .iter()
+            .filter(|value| value.0 == name)
+            .map(|value| value.1.as_str())
+            .collect()
+    }
+    /// Parses the last value given for an option
+    fn parsed<T: ::std::str::FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value for {}: {}", name, value)),
+            None => Ok(None),
+        }
+    }
+    /// The positional argument at the index, which must be the last one
+    fn file(&self, index: usize) -> Result<Option<&str>, String> {
+        if self.positional.len() > index + 1 {
+            return Err(format!(
+                "unexpected argument {}",
+                self.positional[index + 1]
+            ));
+        }
+        Ok(self.positional.get(index).map(|file| file.as_str()))
+    }
+    fn parse_options(&self) -> Result<ParseOptions, String> {
+        match (self.has("--strict"), self.has("--lenient")) {
+            (true, true) => Err("--strict and --lenient can't be combined".to_string()),
+            (true, false) => Ok(ParseOptions::strict()),
+            (false, true) => Ok(ParseOptions::lenient()),
+            (false, false) => Ok(ParseOptions::default()),
+        }
+    }
+}
+
+fn main() {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let mut output = String::new();
+    let mut status = execute(&arguments, &mut io::stdin(), &mut output);
+    // A reader that has seen enough, like head, closes the pipe early,
+    // which isn't worth reporting
+    if let Err(error) = io::stdout().write_all(output.as_bytes()) {
+        if error.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("onyan: standard output: {}", error);
+            status = FAILURE;
+        }
+    }
+    process::exit(status);
+}
+
+/// Runs a command and returns its exit status, reporting why on standard
+/// error when it could not run
+fn execute(arguments: &[String], stdin: &mut dyn Read, out: &mut String) -> i32 {
+    match run(arguments, stdin, out) {
+        Ok(status) => status,
+        Err(message) => {
+            eprintln!("onyan: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn run(arguments: &[String], stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let (command, arguments) = match arguments.split_first() {
+        Some(split) => split,
+        None => return Err(format!("missing command\n\n{}", USAGE)),
+    };
+    match command.as_str() {
+        "parse" => parse(&Arguments::parse(arguments, &["--json"], &[])?, stdin, out),
+        "validate" => validate(&Arguments::parse(arguments, &[], &[])?, stdin, out),
+        "relays" => relays(
+            &Arguments::parse(
+                arguments,
+                &["--csv"],
+                &[
+                    "--where",
+                    "--flag",
+                    "--version",
+                    "--min-version",
+                    "--port",
+                    "--country",
+                    "--geoip",
+                    "--min-bandwidth",
+                ],
+            )?,
+            stdin,
+            out,
+        ),
+        "show" => show(&Arguments::parse(arguments, &[], &[])?, stdin, out),
+        "diff" => diff(
+            &Arguments::parse(arguments, &["--consdiff"], &[])?,
+            stdin,
+            out,
+        ),
+        "verify" => verify(&Arguments::parse(arguments, &[], &["--certs"])?, stdin, out),
+        "help" | "-h" | "--help" => {
+            out!(out, "{}", USAGE);
+            Ok(SUCCESS)
+        }
+        other => Err(format!("unknown command \"{}\"\n\n{}", other, USAGE)),
+    }
+}
+
+/// `onyan parse`
+fn parse(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let file = arguments.file(0)?;
+    let input = read_input(file, stdin)?;
+    let options = arguments.parse_options()?;
+    let document =
+        parse_any_with(&input, &options).map_err(|error| describe(file, &input, &error))?;
+    if arguments.has("--json") {
+        print_json(&input, &options, &document, out)?;
+    } else {
+        outln!(out, "{:#?}", document);
+    }
+    Ok(SUCCESS)
+}
+
+/// Prints typed JSON for the documents that have it, and the generic
+/// metaformat for the others
+#[cfg(feature = "serde")]
+fn print_json(
+    input: &[u8],
+    options: &ParseOptions,
+    document: &AnyDocument,
+    out: &mut String,
+) -> Result<(), String> {
+    let json = match *document {
+        AnyDocument::Consensus(ref consensus) => serde_json::to_string_pretty(consensus),
+        AnyDocument::KeyCertificate(ref certificate) => serde_json::to_string_pretty(certificate),
+        AnyDocument::BandwidthFile(ref file) => serde_json::to_string_pretty(file),
+        AnyDocument::Generic(ref document) => serde_json::to_string_pretty(document),
+        AnyDocument::ServerDescriptors(_)
+        | AnyDocument::ExtraInfos(_)
+        | AnyDocument::Microdescriptors(_)
+        | AnyDocument::DetachedSignatures(_)
+        | AnyDocument::BridgeNetworkStatus(_)
+        | AnyDocument::BridgeDescriptors(_)
+        | AnyDocument::BridgeExtraInfos(_)
+        | AnyDocument::HsDescriptor(_) => {
+            // Streams have annotations between their documents, which the
+            // metaformat doesn't allow
+            let lines: Vec<&[u8]> = input
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.starts_with(b"@"))
+                .collect();
+            let document = onyan::document::parse_complete_with(&lines.join(&b'\n'), options)
+                .map_err(|error| error.to_string())?;
+            serde_json::to_string_pretty(&document)
+        }
+    };
+    outln!(out, "{}", json.map_err(|error| error.to_string())?);
+    Ok(())
+}
+#[cfg(not(feature = "serde"))]
+fn print_json(_: &[u8], _: &ParseOptions, _: &AnyDocument, _: &mut String) -> Result<(), String> {
+    Err("--json needs onyan built with the serde feature".to_string())
+}
+
+/// `onyan validate`
+fn validate(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let file = arguments.file(0)?;
+    let input = read_input(file, stdin)?;
+    let options = arguments.parse_options()?;
+    let name = file.unwrap_or("-");
+    let violations = match validate_any_with(&input, &options) {
+        Ok(violations) => violations,
+        Err(error) => {
+            outln!(out, "{}", describe(file, &input, &error));
+            return Ok(PROBLEMS);
+        }
+    };
+    for &(line, ref violation) in &violations {
+        outln!(out, "{}:{}: {}", name, line, violation);
+    }
+    // The typed parsers check arguments, which the rules don't
+    let parsed = parse_any_with(&input, &options);
+    if let Err(ref error) = parsed {
+        outln!(out, "{}", describe(file, &input, error));
+    }
+    if violations.is_empty() && parsed.is_ok() {
+        Ok(SUCCESS)
+    } else {
+        Ok(PROBLEMS)
+    }
+}
+
+/// `onyan relays`
+fn relays(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let file = arguments.file(0)?;
+    let (_, consensus) = read_consensus(file, arguments, stdin)?;
+    let mut conditions = Vec::new();
+    for expression in arguments.all("--where") {
+        conditions.push(parse_query(expression)?);
+    }
+    for flag in arguments.all("--flag") {
+        conditions.push(Query::Flag(flag.to_string()));
+    }
+    if let Some(version) = parsed_version(arguments, "--version")? {
+        conditions.push(Query::Version(Comparison::Equal, version));
+    }
+    if let Some(version) = parsed_version(arguments, "--min-version")? {
+        conditions.push(Query::Version(Comparison::GreaterOrEqual, version));
+    }
+    if let Some(port) = arguments.parsed("--port")? {
+        conditions.push(Query::AllowsPort(port));
+    }
+    if let Some(weight) = arguments.parsed("--min-bandwidth")? {
+        conditions.push(Query::Bandwidth(Comparison::GreaterOrEqual, weight));
+    }
+    let geoip = read_geoip(arguments, stdin)?;
+    let country = arguments
+        .value("--country")
+        .map(|country| country.to_uppercase());
+    if country.is_some() && geoip.is_none() {
+        return Err("--country needs --geoip".to_string());
+    }
+    let rows: Vec<Vec<String>> = consensus
+        .select(&Query::And(conditions))
+        .into_iter()
+        .filter(|router| match (&country, &geoip) {
+            (Some(country), Some(geoip)) => geoip.router_country(router) == Some(country.as_str()),
+            _ => true,
+        })
+        .map(|router| {
+            let mut row = vec![
+                router.nickname.clone(),
+                fingerprint(router),
+                format!("{}:{}", router.ip, router.or_port),
+                router.flags.join(" "),
+                router
+                    .tor_version()
+                    .map(|version| version.to_string())
+                    .unwrap_or_default(),
+                weight(router).to_string(),
+            ];
+            if let Some(ref geoip) = geoip {
+                row.push(geoip.router_country(router).unwrap_or("??").to_string());
+            }
+            row
+        })
+        .collect();
+    let mut header = vec![
+        "nickname",
+        "fingerprint",
+        "address",
+        "flags",
+        "version",
+        "weight",
+    ];
+    if geoip.is_some() {
+        header.push("country");
+    }
+    if arguments.has("--csv") {
+        print_csv(&header, &rows, out);
+    } else {
+        print_table(&header, &rows, out);
+    }
+    Ok(SUCCESS)
+}
+
+/// `onyan show`
+fn show(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let identity = match arguments.positional.first() {
+        Some(identity) => identity,
+        None => return Err("show needs a fingerprint".to_string()),
+    };
+    let (_, consensus) = read_consensus(arguments.file(1)?, arguments, stdin)?;
+    let router = match consensus.index().find(identity) {
+        Some(router) => router,
+        None => {
+            outln!(out, "no relay with identity {}", identity);
+            return Ok(PROBLEMS);
+        }
+    };
+    outln!(out, "nickname     {}", router.nickname);
+    outln!(out, "fingerprint  {}", fingerprint(router));
+    if let Some(identity) = router.ed25519_identity() {
+        outln!(out, "ed25519      {}", identity);
+    }
+    outln!(out, "published    {}", router.publication);
+    outln!(out, "address      {}:{}", router.ip, router.or_port);
+    for address in &router.addresses {
+        outln!(out, "address      {}", address);
+    }
+    if router.dir_port != 0 {
+        outln!(out, "dir port     {}", router.dir_port);
+    }
+    outln!(out, "flags        {}", router.flags.join(" "));
+    if let Some(ref version) = router.version {
+        outln!(out, "version      {}", version);
+    }
+    if !router.protocols.is_empty() {
+        let protocols: Vec<String> = router
+            .protocols
+            .iter()
+            .map(|entry| format!("{}={}", entry.name, ranges(&entry.versions)))
+            .collect();
+        outln!(out, "protocols    {}", protocols.join(" "));
+    }
+    if let Some(ref bandwidth) = router.bandwidth {
+        out!(out, "weight       {}", bandwidth.bandwidth);
+        if bandwidth.unmeasured {
+            out!(out, " (unmeasured)");
+        }
+        outln!(out);
+    }
+    if let Some(ref policy) = router.policy {
+        let action = if policy.accept { "accept" } else { "reject" };
+        outln!(out, "exit policy  {} {}", action, ranges(&policy.ports));
+    }
+    Ok(SUCCESS)
+}
+
+/// `onyan diff`
+fn diff(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let (old, new) = match (arguments.positional.first(), arguments.file(1)?) {
+        (Some(old), Some(new)) => (old.as_str(), new),
+        _ => return Err("diff needs two consensuses".to_string()),
+    };
+    if old == "-" && new == "-" {
+        return Err("only one consensus can come from standard input".to_string());
+    }
+    let (old_input, old) = read_consensus(Some(old), arguments, stdin)?;
+    let (new_input, new) = read_consensus(Some(new), arguments, stdin)?;
+    if arguments.has("--consdiff") {
+        let diff = ConsensusDiff::generate(document_bytes(&old_input), document_bytes(&new_input))
+            .map_err(|error| error.to_string())?;
+        out!(out, "{}", String::from_utf8_lossy(&diff.to_bytes()));
+    } else {
+        out!(out, "{}", old.compare(&new));
+    }
+    Ok(SUCCESS)
+}
+
+/// `onyan verify`
+fn verify(arguments: &Arguments, stdin: &mut dyn Read, out: &mut String) -> Result<i32, String> {
+    let directory = arguments
+        .value("--certs")
+        .ok_or_else(|| "verify needs --certs DIR".to_string())?;
+    let options = arguments.parse_options()?;
+    let mut paths = fs::read_dir(directory)
+        .map_err(|error| format!("{}: {}", directory, error))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("{}: {}", directory, error))?;
+    paths.sort();
+    let mut certificates = Vec::new();
+    for path in paths.iter().filter(|path| path.is_file()) {
+        let input = fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        match KeyCertificate::parse_all_with(&input, &options) {
+            Ok(parsed) => certificates.extend(parsed),
+            Err(error) => eprintln!("onyan: skipping {}: {}", path.display(), error),
+        }
+    }
+    let (_, consensus) = read_consensus(arguments.file(0)?, arguments, stdin)?;
+    let checks = consensus
+        .check_signatures(&certificates)
+        .map_err(|error| error.to_string())?;
+    for check in &checks {
+        outln!(out, "{}", check);
+    }
+    // The certificates stand for tor's list of trusted authorities, so the
+    // majority is taken over them rather than the consensus's own
+    // "dir-source" lines
+    let trusted: BTreeSet<String> = certificates
+        .iter()
+        .map(|certificate| certificate.fingerprint.to_uppercase())
+        .collect();
+    let valid: BTreeSet<String> = checks
+        .iter()
+        .filter(|check| check.status == SignatureStatus::Valid)
+        .map(|check| check.identity.to_uppercase())
+        .filter(|identity| trusted.contains(identity))
+        .collect();
+    let authorities = trusted.len();
+    outln!(
+        out,
+        "{} of {} trusted authorities signed, {} needed",
+        valid.len(),
+        authorities,
+        authorities / 2 + 1
+    );
+    if valid.len() > authorities / 2 {
+        Ok(SUCCESS)
+    } else {
+        Ok(PROBLEMS)
+    }
+}
+
+/// Reads a file, or standard input for "-" or no file
+fn read_input(file: Option<&str>, stdin: &mut dyn Read) -> Result<Vec<u8>, String> {
+    let mut input = Vec::new();
+    match file {
+        None | Some("-") => stdin
+            .read_to_end(&mut input)
+            .map(|_| ())
+            .map_err(|error| format!("standard input: {}", error))?,
+        Some(file) => input = fs::read(file).map_err(|error| format!("{}: {}", file, error))?,
+    }
+    Ok(input)
+}
+/// Reads and parses a consensus or vote, returning its bytes as well
+fn read_consensus(
+    file: Option<&str>,
+    arguments: &Arguments,
+    stdin: &mut dyn Read,
+) -> Result<(Vec<u8>, ConsensusDocument), String> {
+    let input = read_input(file, stdin)?;
+    let options = arguments.parse_options()?;
+    match parse_any_with(&input, &options) {
+        Ok(AnyDocument::Consensus(consensus)) => Ok((input, *consensus)),
+        Ok(_) => Err(format!("{}: not a consensus", file.unwrap_or("-"))),
+        Err(error) => Err(describe(file, &input, &error)),
+    }
+}
+/// Parses a query, pointing out where it went wrong if it can't
+fn parse_query(expression: &str) -> Result<Query, String> {
+    Query::parse(expression).map_err(|error| match error {
+        Error::InvalidQuery { offset, ref reason } => format!(
+            "{}\n  {}\n  {:>width$}",
+            reason,
+            expression,
+            "^",
+            width = expression[..offset].chars().count() + 1
+        ),
+        error => error.to_string(),
+    })
+}
+/// Parses the last Tor version given for an option
+fn parsed_version(arguments: &Arguments, name: &str) -> Result<Option<TorVersion>, String> {
+    match arguments.value(name) {
+        Some(version) => TorVersion::parse(version)
+            .map(Some)
+            .ok_or_else(|| format!("invalid value for {}: {}", name, version)),
+        None => Ok(None),
+    }
+}
+/// Reads every file given with --geoip into one database
+fn read_geoip(arguments: &Arguments, stdin: &mut dyn Read) -> Result<Option<GeoIp>, String> {
+    let files = arguments.all("--geoip");
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let mut geoip = GeoIp::default();
+    for file in files {
+        let input = read_input(Some(file), stdin)?;
+        geoip
+            .add(&input)
+            .map_err(|error| describe(Some(file), &input, &error))?;
+    }
+    Ok(Some(geoip))
+}
+/// The document after any annotations, which is what signatures and
+/// digests cover
+fn document_bytes(input: &[u8]) -> &[u8] {
+    Annotation::parse_all(input)
+        .map(|(_, document)| document)
+        .unwrap_or(input)
+}
+/// Describes an error, with the line for syntax errors
+fn describe(file: Option<&str>, input: &[u8], error: &Error) -> String {
+    let name = file.unwrap_or("-");
+    match *error {
+        Error::Syntax(offset) => {
+            let offset = offset.min(input.len());
+            let line = input[..offset]
+                .iter()
+                .filter(|byte| **byte == b'\n')
+                .count()
+                + 1;
+            format!("{}:{}: {}", name, line, error)
+        }
+        _ => format!("{}: {}", name, error),
+    }
+}
+/// The relay's identity as uppercase hex
+fn fingerprint(router: &Router) -> String {
+    router
+        .rsa_identity()
+        .map(|identity| identity.to_hex())
+        .unwrap_or_else(|| router.identity.clone())
+}
+/// The relay's consensus weight
+fn weight(router: &Router) -> u32 {
+    router
+        .bandwidth
+        .as_ref()
+        .map(|bandwidth| bandwidth.bandwidth)
+        .unwrap_or(0)
+}
+/// Formats inclusive ranges as "1-2,4"
+fn ranges<T: PartialEq + ToString>(ranges: &[(T, T)]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(low, high)| {
+            if low == high {
+                low.to_string()
+            } else {
+                format!("{}-{}", low.to_string(), high.to_string())
+            }
+        })
+        .collect();
+    ranges.join(",")
+}
+/// Prints rows in aligned columns
+fn print_table(header: &[&str], rows: &[Vec<String>], out: &mut String) {
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.len());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|name| name.to_uppercase()).collect();
+    for row in ::std::iter::once(&header).chain(rows) {
+        let fields: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(field, width)| format!("{:<width$}", field, width = width))
+            .collect();
+        outln!(out, "{}", fields.join("  ").trim_end());
+    }
+}
+/// Prints rows as CSV, quoting fields that need it
+fn print_csv(header: &[&str], rows: &[Vec<String>], out: &mut String) {
+    outln!(out, "{}", header.join(","));
+    for row in rows {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|field| {
+                if field.contains([',', '"', '\n']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.clone()
+                }
+            })
+            .collect();
+        outln!(out, "{}", fields.join(","));
+    }
+}
+
+/// Runs onyan with the arguments and standard input, returning the exit
+/// status and the output
+#[cfg(test)]
+fn onyan(arguments: &[&str], mut stdin: &[u8]) -> (i32, String) {
+    let arguments: Vec<String> = arguments
+        .iter()
+        .map(|argument| argument.to_string())
+        .collect();
+    let mut output = String::new();
+    let status = execute(&arguments, &mut stdin, &mut output);
+    (status, output)
+}
+
+/// Test
+#[test]
+fn test_arguments() {
+    let arguments: Vec<String> = [
+        "--csv", "--flag", "Exit", "--strict", "--flag", "Fast", "--port", "443", "-",
+    ]
+    .iter()
+    .map(|argument| argument.to_string())
+    .collect();
+    let parsed = Arguments::parse(&arguments, &["--csv"], &["--flag", "--port"]).unwrap();
+    assert!(parsed.has("--csv"));
+    assert!(parsed.has("--strict"));
+    assert!(!parsed.has("--lenient"));
+    assert_eq!(parsed.all("--flag"), vec!["Exit", "Fast"]);
+    assert_eq!(parsed.value("--flag"), Some("Fast"));
+    assert_eq!(parsed.parsed::<u16>("--port"), Ok(Some(443)));
+    assert_eq!(parsed.parsed::<u16>("--min-bandwidth"), Ok(None));
+    assert_eq!(parsed.file(0), Ok(Some("-")));
+    assert!(parsed.file(1).is_ok());
+    assert!(parsed.parse_options().is_ok());
+    assert_eq!(
+        parsed.parsed::<u8>("--flag"),
+        Err("invalid value for --flag: Fast".to_string())
+    );
+
+    let arguments: Vec<String> = vec!["a".to_string(), "b".to_string()];
+    let parsed = Arguments::parse(&arguments, &[], &[]).unwrap();
+    assert_eq!(parsed.file(0), Err("unexpected argument b".to_string()));
+    assert_eq!(parsed.file(1), Ok(Some("b")));
+    assert_eq!(parsed.file(2), Ok(None));
+
+    let arguments = vec!["--csv".to_string()];
+    assert_eq!(
+        Arguments::parse(&arguments, &[], &[]).err(),
+        Some("unknown option --csv".to_string())
+    );
+    let arguments = vec!["--port".to_string()];
+    assert_eq!(
+        Arguments::parse(&arguments, &[], &["--port"]).err(),
+        Some("--port needs a value".to_string())
+    );
+    let arguments = vec!["--strict".to_string(), "--lenient".to_string()];
+    assert!(Arguments::parse(&arguments, &[], &[])
+        .unwrap()
+        .parse_options()
+        .is_err());
+}
+
+/// Test
+#[test]
+fn test_commands() {
+    let consensus: &[u8] = include_bytes!("../test/barebones.consensus");
+
+    // Usage errors exit with 2
+    assert_eq!(onyan(&[], b""), (FAILURE, String::new()));
+    assert_eq!(onyan(&["frobnicate"], b""), (FAILURE, String::new()));
+    assert_eq!(onyan(&["parse", "--csv"], b""), (FAILURE, String::new()));
+    assert_eq!(
+        onyan(&["parse", "test/missing"], b""),
+        (FAILURE, String::new())
+    );
+    assert_eq!(onyan(&["help"], b"").0, SUCCESS);
+
+    // parse reads a file, "-" or standard input
+    let (status, output) = onyan(&["parse", "test/barebones.consensus"], b"");
+    assert_eq!(status, SUCCESS);
+    assert!(output.contains("CalyxInstitute14"));
+    assert_eq!(onyan(&["parse", "-"], consensus), (status, output.clone()));
+    assert_eq!(onyan(&["parse"], consensus), (status, output));
+    assert_eq!(
+        onyan(&["parse"], b"network-status-version"),
+        (FAILURE, String::new())
+    );
+    let (status, output) = onyan(&["parse", "--json", "test/bandwidth-file"], b"");
+    if cfg!(feature = "serde") {
+        assert_eq!(status, SUCCESS);
+        assert!(output.contains("\"node_id\""));
+    } else {
+        assert_eq!((status, output), (FAILURE, String::new()));
+    }
+
+    // validate exits with 1 for a document with problems
+    assert_eq!(
+        onyan(&["validate", "test/barebones.consensus"], b""),
+        (SUCCESS, String::new())
+    );
+    let broken: Vec<&[u8]> = consensus
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.starts_with(b"valid-after "))
+        .collect();
+    let (status, output) = onyan(&["validate"], &broken.join(&b'\n'));
+    assert_eq!(status, PROBLEMS);
+    assert!(output.starts_with("-:1: preamble \"valid-after\" appears 0 times"));
+
+    // relays filters and formats
+    let (status, output) = onyan(&["relays", "--flag", "Exit", "--csv"], consensus);
+    assert_eq!(status, SUCCESS);
+    assert!(output.starts_with("nickname,fingerprint,"));
+    assert_eq!(output.lines().count(), 2);
+    assert!(output.contains("CalyxInstitute14,0011BD2485AD45D984EC4159C88FC066E5E3300E"));
+    let (status, output) = onyan(&["relays", "--where", "bandwidth < 1000"], consensus);
+    assert_eq!(status, SUCCESS);
+    assert!(output.starts_with("NICKNAME "));
+    assert!(output.contains("seele") && output.contains("Unnamed"));
+    assert!(!output.contains("CalyxInstitute14"));
+    assert_eq!(
+        onyan(&["relays", "--where", "Exit AND"], consensus),
+        (FAILURE, String::new())
+    );
+    assert_eq!(
+        onyan(&["relays", "--min-bandwidth", "lots"], consensus),
+        (FAILURE, String::new())
+    );
+    assert_eq!(
+        onyan(&["relays"], include_bytes!("../test/key-certificate")),
+        (FAILURE, String::new())
+    );
+
+    // show finds a relay by its identity
+    let (status, output) = onyan(
+        &["show", "0011BD2485AD45D984EC4159C88FC066E5E3300E"],
+        consensus,
+    );
+    assert_eq!(status, SUCCESS);
+    assert!(output.starts_with("nickname     CalyxInstitute14\n"));
+    assert!(output.contains("\nweight       15800\n"));
+    let (status, output) = onyan(&["show", "ABCD", "test/barebones.consensus"], b"");
+    assert_eq!(
+        (status, output.as_str()),
+        (PROBLEMS, "no relay with identity ABCD\n")
+    );
+    assert_eq!(onyan(&["show"], consensus), (FAILURE, String::new()));
+
+    // diff compares two consensuses, one of which may be standard input
+    let (status, output) = onyan(&["diff", "-", "test/signed.consensus"], consensus);
+    assert_eq!(status, SUCCESS);
+    assert!(output.contains("relays: 0 added, 0 removed, 0 changed\n"));
+    let (status, output) = onyan(
+        &[
+            "diff",
+            "--consdiff",
+            "test/barebones.consensus",
+            "test/signed.consensus",
+        ],
+        b"",
+    );
+    assert_eq!(status, SUCCESS);
+    assert!(output.starts_with("network-status-diff-version 1\n"));
+    assert_eq!(
+        onyan(&["diff", "-", "-"], consensus),
+        (FAILURE, String::new())
+    );
+    assert_eq!(onyan(&["diff", "-"], consensus), (FAILURE, String::new()));
+
+    // verify counts the authorities of the certificates it is given, and
+    // exits with 1 when too few of them signed
+    let certificates = env::temp_dir().join(format!("onyan-test-{}", process::id()));
+    fs::create_dir_all(&certificates).unwrap();
+    fs::copy("test/key-certificate", certificates.join("key-certificate")).unwrap();
+    let directory = certificates.to_str().unwrap();
+    let verify = || {
+        onyan(
+            &["verify", "--certs", directory, "test/signed.consensus"],
+            b"",
+        )
+    };
+    let (status, output) = verify();
+    assert_eq!(status, SUCCESS);
+    assert!(output.contains(" sha1: valid\n"));
+    assert!(output.ends_with("1 of 1 trusted authorities signed, 1 needed\n"));
+    let other = String::from_utf8_lossy(include_bytes!("../test/key-certificate")).replace(
+        "fingerprint FE8BE7DAE28F5C8E59E7B948DC10F4CC4C8BB639",
+        "fingerprint 0232AF901C31A04EE9848595AF9BB7620D4C5B2E",
+    );
+    fs::write(certificates.join("other-certificate"), other).unwrap();
+    let (status, output) = verify();
+    fs::remove_dir_all(&certificates).unwrap();
+    assert_eq!(status, PROBLEMS);
+    assert!(output.ends_with("1 of 2 trusted authorities signed, 2 needed\n"));
+    assert_eq!(onyan(&["verify"], consensus), (FAILURE, String::new()));
+}
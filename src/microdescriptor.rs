@@ -0,0 +1,214 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{parse_port_policy, ConsensusDocument, PortPolicy, Router};
+use descriptor::family_identities;
+use document::{encode_base64, parse_complete_with, split_documents, Item};
+use error::Error;
+use identity::{Ed25519Identity, RsaIdentity};
+use options::ParseOptions;
+use rules::MICRODESCRIPTOR;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Microdescriptors are a stripped-down version of server descriptors
+/// generated by the directory authorities. Clients fetch them by the
+/// digest listed in the "m" line of a microdesc-flavored consensus.
+///
+/// Spec defined in section 3.3 of dir-spec.txt
+#[derive(Debug, Clone)]
+pub struct Microdescriptor {
+    /// The TAP onion key, DER-encoded.
+    /// [Exactly once, at start]
+    pub onion_key: Vec<u8>,
+    /// The curve25519 key used for the ntor circuit extended handshake.
+    /// [Exactly once]
+    pub ntor_onion_key: Vec<u8>,
+    /// Additional advertised addresses for the OR.
+    /// [Any number]
+    pub addresses: Vec<SocketAddr>,
+    /// The "family" entry from the router's server descriptor.
+    /// [At most once]
+    pub family: Vec<String>,
+    /// The exit policy summary for IPv4.
+    /// [At most once]
+    pub policy: Option<PortPolicy>,
+    /// The exit policy summary for IPv6.
+    /// [At most once]
+    pub ipv6_policy: Option<PortPolicy>,
+    /// The ed25519 identity of the router, base64 with trailing =s omitted.
+    /// [At most once]
+    pub ed25519_id: Option<String>,
+    /// The sha256 digest of the exact bytes of the microdescriptor, base64
+    /// with trailing =s omitted, as it appears in consensus "m" lines
+    pub digest: String,
+}
+impl Microdescriptor {
+    /// Parses a single microdescriptor from its exact bytes
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses a single microdescriptor with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let input = options.line_endings(input);
+        let document = parse_complete_with(&input, options)?;
+        options.check_keywords(document.items(), |keyword| MICRODESCRIPTOR.knows(keyword))?;
+        let mut microdescriptor = Self::from_items(document.items())?;
+        microdescriptor.digest = encode_base64(&Sha256::digest(&input));
+        Ok(microdescriptor)
+    }
+    /// Parses every microdescriptor in a concatenated stream, such as the
+    /// response to a microdescriptor download or a cached-microdescs file.
+    /// Annotation lines beginning with "@" are skipped.
+    pub fn parse_all(input: &[u8]) -> Result<Vec<Self>, Error> {
+        Self::parse_all_with(input, &ParseOptions::default())
+    }
+    /// Parses every microdescriptor in a concatenated stream with the given options
+    pub fn parse_all_with(input: &[u8], options: &ParseOptions) -> Result<Vec<Self>, Error> {
+        let input = options.line_endings(input);
+        split_documents(&input, "onion-key")
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Self::parse_with(bytes, options).map_err(|error| error.shift(offset))
+            })
+            .collect()
+    }
+    /// The identity from the "id ed25519" line
+    pub fn ed25519_identity(&self) -> Option<Ed25519Identity> {
+        self.ed25519_id
+            .as_ref()
+            .and_then(|identity| Ed25519Identity::from_base64(identity))
+    }
+    /// The identities of the family members given by fingerprint rather
+    /// than nickname
+    pub fn family_identities(&self) -> Vec<RsaIdentity> {
+        family_identities(&self.family)
+    }
+    /// Builds a microdescriptor from its items, leaving the digest empty
+    fn from_items(items: &[Item]) -> Result<Self, Error> {
+        match items.first() {
+            Some(item) if item.keyword() == "onion-key" => (),
+            _ => return Err(Error::MissingItem("onion-key")),
+        }
+        let mut microdescriptor = Microdescriptor {
+            onion_key: Vec::new(),
+            ntor_onion_key: Vec::new(),
+            addresses: Vec::new(),
+            family: Vec::new(),
+            policy: None,
+            ipv6_policy: None,
+            ed25519_id: None,
+            digest: String::new(),
+        };
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "onion-key" => microdescriptor.onion_key = item.object("RSA PUBLIC KEY")?,
+                "ntor-onion-key" => microdescriptor.ntor_onion_key = arguments.arg_base64(0)?,
+                "a" => microdescriptor.addresses.push(arguments.arg(0)?),
+                "family" => microdescriptor.family = arguments.all()?,
+                "p" => microdescriptor.policy = Some(parse_port_policy(&arguments)?),
+                "p6" => microdescriptor.ipv6_policy = Some(parse_port_policy(&arguments)?),
+                // Other key types may be listed and are ignored
+                "id" if arguments.get(0) == Some(&b"ed25519"[..]) => {
+                    microdescriptor.ed25519_id = Some(arguments.arg(1)?)
+                }
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        MICRODESCRIPTOR.check_counts(items, &[])?;
+        Ok(microdescriptor)
+    }
+}
+
+/// Microdescriptors indexed by their digest
+#[derive(Debug, Default)]
+pub struct MicrodescriptorStore {
+    microdescriptors: HashMap<String, Microdescriptor>,
+}
+impl MicrodescriptorStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds every microdescriptor in a concatenated stream
+    pub fn add_all(&mut self, input: &[u8]) -> Result<(), Error> {
+        for microdescriptor in Microdescriptor::parse_all(input)? {
+            self.insert(microdescriptor);
+        }
+        Ok(())
+    }
+    /// Adds a microdescriptor, replacing any with the same digest
+    pub fn insert(&mut self, microdescriptor: Microdescriptor) {
+        self.microdescriptors
+            .insert(microdescriptor.digest.clone(), microdescriptor);
+    }
+    /// Looks up a microdescriptor by its digest as it appears in an "m" line
+    pub fn get(&self, digest: &str) -> Option<&Microdescriptor> {
+        self.microdescriptors.get(digest.trim_end_matches('='))
+    }
+    /// Number of microdescriptors in the store
+    pub fn len(&self) -> usize {
+        self.microdescriptors.len()
+    }
+    /// Whether the store is empty
+    pub fn is_empty(&self) -> bool {
+        self.microdescriptors.is_empty()
+    }
+    /// Joins every router of a microdesc-flavored consensus with its
+    /// microdescriptor, if the store has it
+    pub fn relays<'a>(&'a self, consensus: &'a ConsensusDocument) -> Vec<Relay<'a>> {
+        consensus
+            .routers
+            .iter()
+            .map(|router| Relay {
+                router,
+                microdescriptor: router
+                    .microdesc_digest
+                    .as_ref()
+                    .and_then(|digest| self.get(digest)),
+            })
+            .collect()
+    }
+}
+
+/// A consensus entry merged with the microdescriptor it refers to
+#[derive(Debug, Clone, Copy)]
+pub struct Relay<'a> {
+    /// The router status entry from the consensus
+    pub router: &'a Router,
+    /// The microdescriptor named by the entry's "m" line, if known
+    pub microdescriptor: Option<&'a Microdescriptor>,
+}
+
+/// Test
+#[test]
+fn test_parse_microdescriptors() {
+    let mut store = MicrodescriptorStore::new();
+    store
+        .add_all(include_bytes!("../test/microdescs"))
+        .expect("failed to parse microdescriptors");
+    assert_eq!(store.len(), 3);
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/barebones.microdesc"))
+        .expect("failed to parse consensus");
+    let relays = store.relays(&consensus);
+    assert!(relays.iter().all(|relay| relay.microdescriptor.is_some()));
+    let exit = relays[2].microdescriptor.unwrap();
+    assert!(exit.policy.as_ref().unwrap().accept);
+    assert_eq!(exit.ntor_onion_key.len(), 32);
+}
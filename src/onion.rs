@@ -0,0 +1,670 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use aes::Aes256;
+use arguments::Arguments;
+use certificate::Ed25519Certificate;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use ctr::Ctr128BE;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use document::{parse_complete_with, Item};
+use error::Error;
+use options::ParseOptions;
+use rules::{HS_DESCRIPTOR, HS_ENCRYPTED, HS_INTRODUCTION_POINT, HS_SUPERENCRYPTED};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Digest, Sha3_256, Shake256};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use time::Timestamp;
+
+/// Minutes that time periods are offset from the epoch. Shared random
+/// values are published at 00:00 UTC and periods start 12 hours later, at
+/// 12:00 UTC on the live network, as in [TIME-PERIODS] of rend-spec-v3.txt
+pub const ROTATION_OFFSET: u64 = 12 * 60;
+/// The default time period length in minutes, overridden by the
+/// "hsdir-interval" consensus parameter
+pub const DEFAULT_PERIOD_LENGTH: u64 = 24 * 60;
+
+/// The address of a version 3 onion service, which encodes the service's
+/// Ed25519 identity key.
+///
+/// onion_address = base32(PUBKEY | CHECKSUM | VERSION) + ".onion"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    pub public_key: [u8; 32],
+}
+/// The time period that blinded keys and HSDir positions are derived for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimePeriod {
+    /// Periods elapsed since the epoch, offset by `ROTATION_OFFSET`
+    pub number: u64,
+    /// Length of each period in minutes
+    pub length: u64,
+}
+/// The outer layer of a version 3 onion service descriptor, as uploaded to
+/// HSDirs. Everything useful is in the encrypted layers.
+///
+/// Spec defined in section 2.4 of rend-spec-v3.txt
+#[derive(Debug, Clone)]
+pub struct HsDescriptor {
+    /// "hs-descriptor" SP version-number NL
+    /// [At start, exactly once.]
+    pub version: u32,
+    /// Minutes the descriptor should be cached for.
+    /// [Exactly once]
+    pub descriptor_lifetime: u32,
+    /// Certifies the descriptor signing key with the blinded key.
+    /// [Exactly once]
+    pub descriptor_signing_key_cert: Ed25519Certificate,
+    /// Increases every time the service publishes a new descriptor.
+    /// [Exactly once]
+    pub revision_counter: u64,
+    /// The encrypted middle layer: salt, ciphertext and MAC.
+    /// [Exactly once]
+    pub superencrypted: Vec<u8>,
+    /// Signature of the descriptor with the descriptor signing key.
+    /// [At end, exactly once]
+    pub signature: Vec<u8>,
+}
+/// The middle layer, which holds client authorization data and the inner
+/// encrypted layer.
+///
+/// Spec defined in section 2.5.1.2 of rend-spec-v3.txt
+#[derive(Debug, Clone)]
+pub struct SuperencryptedLayer {
+    /// "desc-auth-type" SP type NL, "x25519" even without client
+    /// authorization.
+    /// [Exactly once]
+    pub desc_auth_type: String,
+    /// The ephemeral x25519 key used to encrypt the descriptor cookie.
+    /// [Exactly once]
+    pub desc_auth_ephemeral_key: Vec<u8>,
+    /// One entry per authorized client, padded with fake entries.
+    /// [At least once]
+    pub auth_clients: Vec<AuthClient>,
+    /// The encrypted inner layer: salt, ciphertext and MAC.
+    /// [Exactly once]
+    pub encrypted: Vec<u8>,
+}
+/// "auth-client" SP client-id SP iv SP encrypted-cookie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthClient {
+    pub client_id: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub encrypted_cookie: Vec<u8>,
+}
+/// The inner layer, which lists the service's introduction points.
+///
+/// Spec defined in section 2.5.2.2 of rend-spec-v3.txt
+#[derive(Debug, Clone)]
+pub struct EncryptedLayer {
+    /// Circuit handshake types the service supports, 2 for ntor.
+    /// [At start, exactly once.]
+    pub create2_formats: Vec<u32>,
+    /// Authentication types required at introduction points.
+    /// [At most once]
+    pub intro_auth_required: Vec<String>,
+    /// Whether the service is non-anonymous.
+    /// [At most once]
+    pub single_onion_service: bool,
+    /// [Any number]
+    pub introduction_points: Vec<IntroductionPoint>,
+}
+/// A relay that clients can reach the service through
+#[derive(Debug, Clone)]
+pub struct IntroductionPoint {
+    /// "introduction-point" SP link-specifiers NL
+    /// How to connect to the relay.
+    pub link_specifiers: Vec<LinkSpecifier>,
+    /// "onion-key" SP "ntor" SP key NL
+    /// The relay's ntor onion key.
+    pub onion_key: Vec<u8>,
+    /// Certifies the introduction point authentication key with the
+    /// descriptor signing key.
+    pub auth_key: Ed25519Certificate,
+    /// "enc-key" SP "ntor" SP key NL
+    /// The key clients encrypt INTRODUCE2 payloads to.
+    pub enc_key: Vec<u8>,
+    /// Cross-certifies the encryption key with the descriptor signing key.
+    pub enc_key_cert: Ed25519Certificate,
+    /// Legacy RSA key for introduction points running old versions.
+    pub legacy_key: Option<Vec<u8>>,
+    pub legacy_key_cert: Option<Vec<u8>>,
+}
+/// A way to reach a relay, as used in EXTEND2 cells.
+///
+/// Spec defined in section 5.1.2 of tor-spec.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkSpecifier {
+    /// TLS-over-TCP, IPv4 or IPv6 address and port
+    Address(SocketAddr),
+    /// The sha1 digest of the relay's RSA identity key
+    LegacyId([u8; 20]),
+    /// The relay's Ed25519 identity key
+    Ed25519Id([u8; 32]),
+    /// A specifier type this parser doesn't know about
+    Unrecognized(u8, Vec<u8>),
+}
+
+impl OnionAddress {
+    /// Parses an address with or without the ".onion" suffix, checking its
+    /// version and checksum
+    pub fn parse(address: &str) -> Result<Self, Error> {
+        let address = address.trim_end_matches(".onion");
+        let decoded = decode_base32(address)
+            .ok_or_else(|| Error::InvalidOnionAddress("malformed base32".to_string()))?;
+        if decoded.len() != 35 {
+            return Err(Error::InvalidOnionAddress(format!(
+                "expected 35 bytes, found {}",
+                decoded.len()
+            )));
+        }
+        if decoded[34] != 3 {
+            return Err(Error::InvalidOnionAddress(format!(
+                "unsupported version {}",
+                decoded[34]
+            )));
+        }
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&decoded[..32]);
+        let onion_address = OnionAddress { public_key };
+        if decoded[32..34] != onion_address.checksum() {
+            return Err(Error::InvalidOnionAddress("checksum mismatch".to_string()));
+        }
+        Ok(onion_address)
+    }
+    /// CHECKSUM = H(".onion checksum" | PUBKEY | VERSION)[:2]
+    fn checksum(&self) -> [u8; 2] {
+        let digest = sha3_256(&[b".onion checksum", &self.public_key, &[3]]);
+        [digest[0], digest[1]]
+    }
+    /// Derives the blinded public key the service uses during a time period.
+    ///
+    /// h = H(BLIND_STRING | A | B | N), clamped, and A' = h A, where
+    /// N = "key-blind" | INT_8(period-number) | INT_8(period_length)
+    pub fn blinded_key(&self, period: TimePeriod) -> Result<[u8; 32], Error> {
+        const BLIND_STRING: &[u8] = b"Derive temporary signing key\0";
+        const BASEPOINT: &[u8] = b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)";
+        let point = CompressedEdwardsY(self.public_key)
+            .decompress()
+            .ok_or_else(|| Error::InvalidOnionAddress("not a valid Ed25519 key".to_string()))?;
+        let factor = sha3_256(&[
+            BLIND_STRING,
+            &self.public_key,
+            BASEPOINT,
+            b"key-blind",
+            &period.number.to_be_bytes(),
+            &period.length.to_be_bytes(),
+        ]);
+        Ok(point.mul_clamped(factor).compress().to_bytes())
+    }
+    /// subcredential = H("subcredential" | credential | blinded-public-key),
+    /// where credential = H("credential" | public-identity-key)
+    pub fn subcredential(&self, blinded_key: &[u8; 32]) -> [u8; 32] {
+        let credential = sha3_256(&[b"credential", &self.public_key]);
+        sha3_256(&[b"subcredential", &credential, blinded_key])
+    }
+}
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut decoded = self.public_key.to_vec();
+        decoded.extend_from_slice(&self.checksum());
+        decoded.push(3);
+        write!(f, "{}.onion", encode_base32(&decoded))
+    }
+}
+impl TimePeriod {
+    /// The time period containing a moment, for periods of `length` minutes
+    pub fn containing(time: Timestamp, length: u64) -> Self {
+        TimePeriod {
+            number: (time.unix() / 60).saturating_sub(ROTATION_OFFSET) / length,
+            length,
+        }
+    }
+    /// When the period begins
+    pub fn start(&self) -> Timestamp {
+        Timestamp::from_unix((self.number * self.length + ROTATION_OFFSET) * 60)
+    }
+    /// The period after this one
+    pub fn next(&self) -> Self {
+        TimePeriod {
+            number: self.number + 1,
+            length: self.length,
+        }
+    }
+}
+impl HsDescriptor {
+    /// Parses the outer layer of a descriptor
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses the outer layer of a descriptor with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let document = parse_complete_with(input, options)?;
+        let items = document.items();
+        options.check_keywords(items, |keyword| HS_DESCRIPTOR.knows(keyword))?;
+        HS_DESCRIPTOR.check_counts(items, &[])?;
+        match items.first() {
+            Some(item) if item.keyword() == "hs-descriptor" => (),
+            _ => return Err(Error::MissingItem("hs-descriptor")),
+        }
+        match items.last() {
+            Some(item) if item.keyword() == "signature" => (),
+            _ => return Err(Error::MissingItem("signature")),
+        }
+        let mut version = 0;
+        let mut descriptor_lifetime = 0;
+        let mut descriptor_signing_key_cert = None;
+        let mut revision_counter = 0;
+        let mut superencrypted = Vec::new();
+        let mut signature = Vec::new();
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "hs-descriptor" => {
+                    version = arguments.arg(0)?;
+                    if version != 3 {
+                        return Err(Error::invalid(
+                            item.keyword(),
+                            format!("unsupported version {}", version),
+                        ));
+                    }
+                }
+                "descriptor-lifetime" => descriptor_lifetime = arguments.arg(0)?,
+                "descriptor-signing-key-cert" => {
+                    descriptor_signing_key_cert = Some(parse_certificate(item)?)
+                }
+                "revision-counter" => revision_counter = arguments.arg(0)?,
+                "superencrypted" => superencrypted = item.object("MESSAGE")?,
+                "signature" => signature = arguments.arg_base64(0)?,
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        Ok(HsDescriptor {
+            version,
+            descriptor_lifetime,
+            descriptor_signing_key_cert: descriptor_signing_key_cert
+                .ok_or(Error::MissingItem("descriptor-signing-key-cert"))?,
+            revision_counter,
+            superencrypted,
+            signature,
+        })
+    }
+    /// The blinded key of the period the descriptor was published for, as
+    /// certified by the descriptor signing key certificate
+    pub fn blinded_key(&self) -> Result<[u8; 32], Error> {
+        self.descriptor_signing_key_cert
+            .signing_key()
+            .ok_or_else(|| {
+                Error::invalid(
+                    "descriptor-signing-key-cert",
+                    "missing signing key extension",
+                )
+            })
+    }
+    /// Decrypts and parses the middle layer for the service at `address`
+    pub fn superencrypted_layer(
+        &self,
+        address: &OnionAddress,
+    ) -> Result<SuperencryptedLayer, Error> {
+        let plaintext =
+            self.decrypt_layer(address, &self.superencrypted, b"hsdir-superencrypted-data")?;
+        SuperencryptedLayer::parse(&plaintext)
+    }
+    /// Decrypts the middle and inner layers and parses the introduction
+    /// points. Services with client authorization are not supported, since
+    /// their inner layer is also keyed with the descriptor cookie.
+    pub fn encrypted_layer(&self, address: &OnionAddress) -> Result<EncryptedLayer, Error> {
+        let superencrypted = self.superencrypted_layer(address)?;
+        let plaintext =
+            self.decrypt_layer(address, &superencrypted.encrypted, b"hsdir-encrypted-data")?;
+        EncryptedLayer::parse(&plaintext)
+    }
+    /// Checks the MAC of a layer and decrypts it. The layer is
+    /// SALT | ENCRYPTED | MAC, keyed with
+    /// SHAKE256(SECRET_DATA | subcredential | INT_8(revision_counter) | SALT | STRING_CONSTANT)
+    fn decrypt_layer(
+        &self,
+        address: &OnionAddress,
+        layer: &[u8],
+        constant: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        const SALT_LEN: usize = 16;
+        const MAC_LEN: usize = 32;
+        if layer.len() < SALT_LEN + MAC_LEN {
+            return Err(Error::Decryption("layer is too short".to_string()));
+        }
+        let blinded_key = self.blinded_key()?;
+        let subcredential = address.subcredential(&blinded_key);
+        let (salt, rest) = layer.split_at(SALT_LEN);
+        let (ciphertext, mac) = rest.split_at(rest.len() - MAC_LEN);
+        let mut keys = [0; 80];
+        let mut shake = Shake256::default();
+        shake.update(&blinded_key);
+        shake.update(&subcredential);
+        shake.update(&self.revision_counter.to_be_bytes());
+        shake.update(salt);
+        shake.update(constant);
+        shake.finalize_xof().read(&mut keys);
+        let (key, rest) = keys.split_at(32);
+        let (iv, mac_key) = rest.split_at(16);
+        let expected = sha3_256(&[
+            &(mac_key.len() as u64).to_be_bytes(),
+            mac_key,
+            &(salt.len() as u64).to_be_bytes(),
+            salt,
+            ciphertext,
+        ]);
+        if expected[..] != mac[..] {
+            return Err(Error::Decryption(
+                "MAC mismatch, is this the right onion address?".to_string(),
+            ));
+        }
+        let mut plaintext = ciphertext.to_vec();
+        Ctr128BE::<Aes256>::new(key.into(), iv.into()).apply_keystream(&mut plaintext);
+        // The plaintext is padded with NUL bytes
+        while plaintext.last() == Some(&0) {
+            plaintext.pop();
+        }
+        Ok(plaintext)
+    }
+}
+impl SuperencryptedLayer {
+    /// Parses the decrypted middle layer
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses the decrypted middle layer with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let document = parse_complete_with(input, options)?;
+        let items = document.items();
+        options.check_keywords(items, |keyword| HS_SUPERENCRYPTED.knows(keyword))?;
+        HS_SUPERENCRYPTED.check_counts(items, &[])?;
+        let mut layer = SuperencryptedLayer {
+            desc_auth_type: String::new(),
+            desc_auth_ephemeral_key: Vec::new(),
+            auth_clients: Vec::new(),
+            encrypted: Vec::new(),
+        };
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "desc-auth-type" => layer.desc_auth_type = arguments.arg(0)?,
+                "desc-auth-ephemeral-key" => {
+                    layer.desc_auth_ephemeral_key = arguments.arg_base64(0)?
+                }
+                "auth-client" => layer.auth_clients.push(AuthClient {
+                    client_id: arguments.arg_base64(0)?,
+                    iv: arguments.arg_base64(1)?,
+                    encrypted_cookie: arguments.arg_base64(2)?,
+                }),
+                "encrypted" => layer.encrypted = item.object("MESSAGE")?,
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        Ok(layer)
+    }
+}
+impl EncryptedLayer {
+    /// Parses the decrypted inner layer
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        Self::parse_with(input, &ParseOptions::default())
+    }
+    /// Parses the decrypted inner layer with the given options
+    pub fn parse_with(input: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let document = parse_complete_with(input, options)?;
+        let items = document.items();
+        options.check_keywords(items, |keyword| HS_ENCRYPTED.knows(keyword))?;
+        match items.first() {
+            Some(item) if item.keyword() == "create2-formats" => (),
+            _ => return Err(Error::MissingItem("create2-formats")),
+        }
+        let header_end = items
+            .iter()
+            .position(|item| item.keyword() == "introduction-point")
+            .unwrap_or(items.len());
+        let header = &items[..header_end];
+        HS_ENCRYPTED.check_counts(header, &[])?;
+        let mut layer = EncryptedLayer {
+            create2_formats: Vec::new(),
+            intro_auth_required: Vec::new(),
+            single_onion_service: false,
+            introduction_points: Vec::new(),
+        };
+        for item in header {
+            let arguments = item.args();
+            match item.keyword() {
+                "create2-formats" => layer.create2_formats = arguments.all()?,
+                "intro-auth-required" => layer.intro_auth_required = arguments.all()?,
+                "single-onion-service" => layer.single_onion_service = true,
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        let points = &items[header_end..];
+        let starts: Vec<usize> = points
+            .iter()
+            .enumerate()
+            .filter(|&(_, item)| item.keyword() == "introduction-point")
+            .map(|(index, _)| index)
+            .chain(Some(points.len()))
+            .collect();
+        for bounds in starts.windows(2) {
+            layer
+                .introduction_points
+                .push(IntroductionPoint::from_items(
+                    &points[bounds[0]..bounds[1]],
+                )?);
+        }
+        Ok(layer)
+    }
+}
+impl IntroductionPoint {
+    /// Builds an introduction point from the items of its group
+    fn from_items(items: &[Item]) -> Result<Self, Error> {
+        HS_INTRODUCTION_POINT.check_counts(items, false, &[])?;
+        let mut link_specifiers = Vec::new();
+        let mut onion_key = Vec::new();
+        let mut auth_key = None;
+        let mut enc_key = Vec::new();
+        let mut enc_key_cert = None;
+        let mut legacy_key = None;
+        let mut legacy_key_cert = None;
+        for item in items {
+            let arguments = item.args();
+            match item.keyword() {
+                "introduction-point" => {
+                    link_specifiers = parse_link_specifiers(&arguments.arg_base64(0)?)
+                        .ok_or_else(|| arguments.error(0, "malformed link specifiers"))?
+                }
+                "onion-key" => onion_key = parse_ntor_key(&arguments)?,
+                "auth-key" => auth_key = Some(parse_certificate(item)?),
+                "enc-key" => enc_key = parse_ntor_key(&arguments)?,
+                "enc-key-cert" => enc_key_cert = Some(parse_certificate(item)?),
+                "legacy-key" => legacy_key = Some(item.object("RSA PUBLIC KEY")?),
+                "legacy-key-cert" => legacy_key_cert = Some(item.object("CROSSCERT")?),
+                // Unrecognized keywords are ignored
+                _ => (),
+            }
+        }
+        Ok(IntroductionPoint {
+            link_specifiers,
+            onion_key,
+            auth_key: auth_key.ok_or(Error::MissingItem("auth-key"))?,
+            enc_key,
+            enc_key_cert: enc_key_cert.ok_or(Error::MissingItem("enc-key-cert"))?,
+            legacy_key,
+            legacy_key_cert,
+        })
+    }
+}
+
+/// H(x) = SHA3-256(x) over the concatenation of several parts
+fn sha3_256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    hasher.finalize().into()
+}
+/// RFC 4648 base32 without padding, in lowercase as onion addresses are
+fn encode_base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+/// Decodes base32 of either case without padding
+fn decode_base32(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.chars() {
+        let value = match c.to_ascii_lowercase() {
+            c @ 'a'..='z' => c as u32 - 'a' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+/// "ntor" SP key
+fn parse_ntor_key(arguments: &Arguments) -> Result<Vec<u8>, Error> {
+    if arguments.str(0)? != "ntor" {
+        return Err(arguments.error(0, "expected an ntor key"));
+    }
+    arguments.arg_base64(1)
+}
+/// An item whose object is an "ED25519 CERT"
+fn parse_certificate(item: &Item) -> Result<Ed25519Certificate, Error> {
+    Ed25519Certificate::parse(&item.object("ED25519 CERT")?).map_err(|error| match error {
+        Error::InvalidItem { reason, .. } => Error::invalid(item.keyword(), reason),
+        error => error,
+    })
+}
+/// NSPEC (1 byte), then NSPEC times LSTYPE (1 byte) LSLEN (1 byte) LSPEC
+fn parse_link_specifiers(data: &[u8]) -> Option<Vec<LinkSpecifier>> {
+    let (&count, mut rest) = data.split_first()?;
+    let mut specifiers = Vec::new();
+    for _ in 0..count {
+        let (&kind, after) = rest.split_first()?;
+        let (&length, after) = after.split_first()?;
+        let body = after.get(..usize::from(length))?;
+        rest = &after[usize::from(length)..];
+        let port = |at: usize| (u16::from(body[at]) << 8) | u16::from(body[at + 1]);
+        specifiers.push(match (kind, body.len()) {
+            (0, 6) => LinkSpecifier::Address(SocketAddr::new(
+                Ipv4Addr::new(body[0], body[1], body[2], body[3]).into(),
+                port(4),
+            )),
+            (1, 18) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[..16]);
+                LinkSpecifier::Address(SocketAddr::new(Ipv6Addr::from(octets).into(), port(16)))
+            }
+            (2, 20) => {
+                let mut id = [0; 20];
+                id.copy_from_slice(body);
+                LinkSpecifier::LegacyId(id)
+            }
+            (3, 32) => {
+                let mut id = [0; 32];
+                id.copy_from_slice(body);
+                LinkSpecifier::Ed25519Id(id)
+            }
+            (0..=3, _) => return None,
+            _ => LinkSpecifier::Unrecognized(kind, body.to_vec()),
+        });
+    }
+    Some(specifiers)
+}
+
+/// Test
+#[test]
+fn test_decrypt_hs_descriptor() {
+    use document::decode_hex;
+    // The address of RFC 8032's first test key, from tor's test_build_address
+    let mut public_key = [0; 32];
+    public_key.copy_from_slice(
+        &decode_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").unwrap(),
+    );
+    assert_eq!(
+        OnionAddress { public_key }.to_string(),
+        "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion"
+    );
+    // test/hs-descriptor was encrypted by a separate Python implementation
+    // of rend-spec-v3, whose blinded key and subcredential are checked here
+    let address =
+        OnionAddress::parse("aoqqpp7tzyil4hlq3umoos6atft6jvrqtosq2xy53sdgiesvgg4bqead.onion")
+            .expect("failed to parse onion address");
+    assert_eq!(
+        address.to_string(),
+        "aoqqpp7tzyil4hlq3umoos6atft6jvrqtosq2xy53sdgiesvgg4bqead.onion"
+    );
+    let descriptor = HsDescriptor::parse(include_bytes!("../test/hs-descriptor"))
+        .expect("failed to parse descriptor");
+    assert_eq!(descriptor.revision_counter, 42);
+    let period = TimePeriod::containing(
+        Timestamp::parse("2018-02-08 07:00:00").unwrap(),
+        DEFAULT_PERIOD_LENGTH,
+    );
+    assert_eq!(period.number, 17569);
+    assert_eq!(period.start().to_string(), "2018-02-07 12:00:00");
+    let blinded_key = address.blinded_key(period).unwrap();
+    assert_eq!(blinded_key, descriptor.blinded_key().unwrap());
+    assert_eq!(
+        blinded_key.to_vec(),
+        decode_hex("03b376ee10e32cce2105e2ad034c5e1b8a6f87ff322505b77a6b3140ff1b0f37").unwrap()
+    );
+    assert_eq!(
+        address.subcredential(&blinded_key).to_vec(),
+        decode_hex("93c4b3043d1d056459b5470d710f73ff6560b7fea45b4dd2499e2a3e7ff6bf57").unwrap()
+    );
+    let layer = descriptor
+        .encrypted_layer(&address)
+        .expect("failed to decrypt descriptor");
+    assert_eq!(layer.create2_formats, vec![2]);
+    assert!(layer.single_onion_service);
+    assert_eq!(layer.introduction_points.len(), 2);
+    assert_eq!(
+        layer.introduction_points[1].link_specifiers[0],
+        LinkSpecifier::Address("203.0.113.25:9002".parse().unwrap())
+    );
+    let other = OnionAddress {
+        public_key: [9; 32],
+    };
+    assert!(descriptor.superencrypted_layer(&other).is_err());
+}
@@ -0,0 +1,407 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use document::Item;
+use error::Error;
+use std::borrow::Cow;
+
+/// How forgiving the parsers are about the layout of a document.
+///
+/// dir-spec requires votes and consensuses to separate arguments with a
+/// single SP, while other documents allow WS = (SP|TAB)+. Parsers may also
+/// accept "opt" prefixes, trailing whitespace and unknown keywords, and old
+/// archives sometimes have CRLF line endings. `strict` suits
+/// authority-grade validation and `lenient` suits archive processing.
+/// The default accepts what the parsers have always accepted, along with
+/// UTF-8 in arguments, which relays publish in their contact lines.
+///
+/// When line endings or "opt" prefixes are rewritten, or invalid UTF-8 is
+/// replaced, the offsets of items and of syntax errors count bytes of the
+/// rewritten document. Errors for the layout checks themselves point into
+/// the document as given, after CRLF line endings are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Require keywords and arguments to be separated by a single SP
+    pub single_space: bool,
+    /// Accept CRLF line endings, which are converted to NL before parsing
+    /// and before computing digests
+    pub allow_crlf: bool,
+    /// Accept items prefixed with the obsolete "opt" keyword and treat them
+    /// as the item that follows it
+    pub allow_opt: bool,
+    /// Accept whitespace at the end of keyword lines, as in "pr " lines
+    /// with no entries
+    pub allow_trailing_whitespace: bool,
+    /// Whether items with keywords the document type doesn't define are
+    /// skipped or rejected
+    pub unknown_keywords: UnknownKeywords,
+    /// The longest line allowed, in bytes without the line ending
+    pub max_line_length: Option<usize>,
+    /// What to do with bytes outside printable ASCII in arguments
+    pub non_ascii: NonAscii,
+    /// Skip "client-versions" and "server-versions" entries that don't
+    /// parse, and accept the lists out of order, as tor does
+    pub allow_malformed_versions: bool,
+    /// Accept server descriptors without a "proto" line, as relays before
+    /// 0.2.9 published them
+    pub allow_missing_proto: bool,
+}
+/// What to do with bytes outside printable ASCII in the arguments of an
+/// item. Control characters other than TAB are always an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonAscii {
+    /// Fail with `Error::InvalidArgument` at the offending byte, as dir-spec
+    /// only allows printable ASCII
+    Reject,
+    /// Accept UTF-8, replacing invalid sequences with U+FFFD. Each
+    /// replacement is three bytes long, so offsets after it shift.
+    Replace,
+    /// Keep the bytes as they are, so `Item::arguments_utf8` may be None
+    PassThrough,
+}
+/// What to do with items whose keyword the document type doesn't define
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownKeywords {
+    /// Skip them, as dir-spec requires for forward compatibility
+    Ignore,
+    /// Fail with `Error::UnexpectedItem`
+    Reject,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            single_space: false,
+            allow_crlf: false,
+            allow_opt: false,
+            allow_trailing_whitespace: true,
+            unknown_keywords: UnknownKeywords::Ignore,
+            max_line_length: None,
+            non_ascii: NonAscii::Replace,
+            allow_malformed_versions: true,
+            allow_missing_proto: false,
+        }
+    }
+}
+impl ParseOptions {
+    /// Options for checking documents exactly as authorities produce them
+    pub fn strict() -> Self {
+        ParseOptions {
+            single_space: true,
+            allow_crlf: false,
+            allow_opt: false,
+            // Authorities themselves write "pr " lines for relays that list
+            // no protocols
+            allow_trailing_whitespace: true,
+            unknown_keywords: UnknownKeywords::Reject,
+            max_line_length: None,
+            non_ascii: NonAscii::Reject,
+            allow_malformed_versions: false,
+            allow_missing_proto: false,
+        }
+    }
+    /// Options for reading whatever old archives contain
+    pub fn lenient() -> Self {
+        ParseOptions {
+            single_space: false,
+            allow_crlf: true,
+            allow_opt: true,
+            allow_trailing_whitespace: true,
+            unknown_keywords: UnknownKeywords::Ignore,
+            max_line_length: None,
+            non_ascii: NonAscii::PassThrough,
+            allow_malformed_versions: true,
+            allow_missing_proto: true,
+        }
+    }
+    /// Converts CRLF line endings to NL if they are allowed, so that the
+    /// digests of a document don't depend on how it was stored
+    pub(crate) fn line_endings<'a>(&self, input: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.allow_crlf || !input.contains(&b'\r') {
+            return Cow::Borrowed(input);
+        }
+        let mut converted = Vec::with_capacity(input.len());
+        for (index, byte) in input.iter().enumerate() {
+            if *byte != b'\r' || input.get(index + 1) != Some(&b'\n') {
+                converted.push(*byte);
+            }
+        }
+        Cow::Owned(converted)
+    }
+    /// Checks the layout of every line and rewrites what the options allow,
+    /// leaving the document ready for the metaformat parser
+    pub(crate) fn preprocess<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        let input = self.line_endings(input);
+        let unchecked =
+            !self.single_space && self.allow_trailing_whitespace && self.max_line_length.is_none();
+        if unchecked && !(self.allow_opt && has_opt(&input)) && input.iter().all(is_plain) {
+            return Ok(input);
+        }
+        let mut output = Vec::with_capacity(input.len());
+        let mut in_object = false;
+        let mut offset = 0;
+        for line in input.split_inclusive(|byte| *byte == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let line_start = offset;
+            offset += line.len();
+            if let Some(maximum) = self.max_line_length {
+                if content.len() > maximum {
+                    return Err(invalid_argument(
+                        content,
+                        line_start,
+                        maximum,
+                        format!(
+                            "line is {} bytes long, more than {}",
+                            content.len(),
+                            maximum
+                        ),
+                    ));
+                }
+            }
+            if content.starts_with(b"-----BEGIN ") {
+                in_object = true;
+            }
+            if in_object {
+                in_object = !content.starts_with(b"-----END ");
+                output.extend_from_slice(line);
+                continue;
+            }
+            let mut keyword_line = content;
+            if self.allow_opt {
+                if let Some(rest) = opt_item(keyword_line) {
+                    keyword_line = rest;
+                }
+            }
+            let prefix = content.len() - keyword_line.len();
+            let keyword_start = line_start + prefix;
+            if self.single_space {
+                if let Some(position) = keyword_line.iter().enumerate().position(|(at, byte)| {
+                    *byte == b'\t' || (*byte == b' ' && at > 0 && keyword_line[at - 1] == b' ')
+                }) {
+                    return Err(invalid_argument(
+                        keyword_line,
+                        keyword_start,
+                        position,
+                        "arguments must be separated by a single space",
+                    ));
+                }
+            }
+            if !self.allow_trailing_whitespace
+                && matches!(keyword_line.last(), Some(&b' ') | Some(&b'\t'))
+            {
+                return Err(invalid_argument(
+                    keyword_line,
+                    keyword_start,
+                    keyword_line.len() - 1,
+                    "trailing whitespace",
+                ));
+            }
+            let checked = self.check_characters(keyword_line).map_err(|position| {
+                invalid_argument(
+                    keyword_line,
+                    keyword_start,
+                    position,
+                    format!("invalid character 0x{:02X}", keyword_line[position]),
+                )
+            })?;
+            if let Some(replaced) = checked {
+                output.extend_from_slice(replaced.as_bytes());
+                output.extend_from_slice(&line[content.len()..]);
+                continue;
+            }
+            output.extend_from_slice(keyword_line);
+            output.extend_from_slice(&line[content.len()..]);
+        }
+        Ok(Cow::Owned(output))
+    }
+    /// Checks the bytes of a keyword line against the non-ASCII policy,
+    /// returning the position of the first invalid byte, or the line with
+    /// invalid UTF-8 replaced if it had to be rewritten
+    fn check_characters(&self, line: &[u8]) -> Result<Option<String>, usize> {
+        if let Some(position) = line
+            .iter()
+            .position(|byte| byte.is_ascii_control() && *byte != b'\t')
+        {
+            return Err(position);
+        }
+        match self.non_ascii {
+            NonAscii::Reject => match line.iter().position(|byte| !byte.is_ascii()) {
+                Some(position) => Err(position),
+                None => Ok(None),
+            },
+            NonAscii::Replace if ::std::str::from_utf8(line).is_err() => {
+                Ok(Some(String::from_utf8_lossy(line).into_owned()))
+            }
+            NonAscii::Replace | NonAscii::PassThrough => Ok(None),
+        }
+    }
+    /// Checks that every item has a keyword the document type defines, if
+    /// unknown keywords are rejected
+    pub(crate) fn check_keywords<F>(&self, items: &[Item], known: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.unknown_keywords == UnknownKeywords::Ignore {
+            return Ok(());
+        }
+        match items.iter().find(|item| !known(item.keyword())) {
+            Some(item) => Err(Error::UnexpectedItem(item.keyword().to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether a byte can appear anywhere in a document without checking the
+/// line it is on
+fn is_plain(byte: &u8) -> bool {
+    (b' '..=b'~').contains(byte) || *byte == b'\n' || *byte == b'\t'
+}
+
+/// An error for the argument of a line that holds the byte at `position`,
+/// or for the argument that follows it if the byte is whitespace
+fn invalid_argument<R: Into<String>>(
+    line: &[u8],
+    line_start: usize,
+    position: usize,
+    reason: R,
+) -> Error {
+    let is_space = |byte: &u8| *byte == b' ' || *byte == b'\t';
+    let before = &line[..position];
+    let words = before
+        .split(is_space)
+        .filter(|word| !word.is_empty())
+        .count();
+    let starts_word = before.last().is_none_or(is_space) || line.get(position).is_none_or(is_space);
+    Error::InvalidArgument {
+        keyword: String::from_utf8_lossy(keyword_of(line)).into_owned(),
+        index: words.saturating_sub(if starts_word { 1 } else { 2 }),
+        offset: line_start + position,
+        reason: reason.into(),
+    }
+}
+
+/// The keyword at the start of a line, for naming it in errors
+fn keyword_of(line: &[u8]) -> &[u8] {
+    line.split(|byte| *byte == b' ' || *byte == b'\t')
+        .next()
+        .unwrap_or_default()
+}
+
+/// Whether any line starts with an "opt" prefix
+fn has_opt(input: &[u8]) -> bool {
+    input
+        .split(|byte| *byte == b'\n')
+        .any(|line| opt_item(line).is_some())
+}
+
+/// The item after an "opt" prefix, which dir-spec says to ignore
+fn opt_item(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"opt")?;
+    let skipped = rest
+        .iter()
+        .take_while(|byte| **byte == b' ' || **byte == b'\t')
+        .count();
+    if skipped == 0 || skipped == rest.len() {
+        None
+    } else {
+        Some(&rest[skipped..])
+    }
+}
+
+/// Test
+#[test]
+fn test_parse_options() {
+    use consensus::ConsensusDocument;
+    use descriptor::ServerDescriptor;
+    use document::parse_complete_with;
+    let consensus = &include_bytes!("../test/barebones.consensus")[..];
+    let strict = ParseOptions::strict();
+    assert!(ConsensusDocument::parse_with(consensus, &strict).is_ok());
+    assert!(ConsensusDocument::parse_with(include_bytes!("../test/consensus"), &strict).is_ok());
+    let tabbed =
+        String::from_utf8_lossy(consensus).replace("voting-delay 300 300", "voting-delay\t300 300");
+    assert!(ConsensusDocument::parse(tabbed.as_bytes()).is_ok());
+    assert!(ConsensusDocument::parse_with(tabbed.as_bytes(), &strict).is_err());
+    let unknown = String::from_utf8_lossy(consensus).replace("params ", "wombat 1\nparams ");
+    assert!(ConsensusDocument::parse(unknown.as_bytes()).is_ok());
+    assert_eq!(
+        ConsensusDocument::parse_with(unknown.as_bytes(), &strict).err(),
+        Some(Error::UnexpectedItem("wombat".to_string()))
+    );
+    let long = ParseOptions {
+        max_line_length: Some(100),
+        ..ParseOptions::default()
+    };
+    assert!(ConsensusDocument::parse_with(consensus, &long).is_err());
+    // Descriptors stored with CRLF line endings keep their digests
+    let descriptors = &include_bytes!("../test/server-descriptors")[..];
+    let first = ServerDescriptor::parse_all(descriptors).unwrap().remove(0);
+    let crlf = String::from_utf8_lossy(descriptors).replace('\n', "\r\n");
+    assert!(ServerDescriptor::parse_all(crlf.as_bytes()).is_err());
+    let lenient = ParseOptions::lenient();
+    let parsed = ServerDescriptor::parse_all_with(crlf.as_bytes(), &lenient).unwrap();
+    assert_eq!(parsed[0].digest, first.digest);
+    // Items behind an "opt" prefix are read as the item itself
+    let opt = String::from_utf8_lossy(descriptors).replace("\nproto ", "\nopt proto ");
+    assert!(ServerDescriptor::parse_all(opt.as_bytes()).is_err());
+    let parsed = ServerDescriptor::parse_all_with(opt.as_bytes(), &lenient).unwrap();
+    assert_eq!(parsed[0].proto, first.proto);
+    // UTF-8 contact lines are accepted unless only ASCII is allowed
+    let utf8 = String::from_utf8_lossy(descriptors).replace("contact seele", "contact Zoë");
+    let parsed = ServerDescriptor::parse_all(utf8.as_bytes()).unwrap();
+    assert_eq!(
+        parsed[0].contact.as_deref(),
+        Some("Zoë <seele AT example dot com>")
+    );
+    // Offsets count from the start of the input
+    let offset = utf8.find('ë').unwrap();
+    assert_eq!(
+        ServerDescriptor::parse_all_with(utf8.as_bytes(), &strict).err(),
+        Some(Error::InvalidArgument {
+            keyword: "contact".to_string(),
+            index: 0,
+            offset,
+            reason: "invalid character 0xC3".to_string(),
+        })
+    );
+    // Invalid UTF-8 is replaced or kept as it is
+    let latin1 = parse_complete_with(b"contact Zo\xEB\n", &ParseOptions::default()).unwrap();
+    assert_eq!(latin1.items()[0].arguments(), "Zo\u{FFFD}");
+    assert!(latin1.items()[0].arguments_utf8().is_some());
+    let latin1 = parse_complete_with(b"contact Zo\xEB\n", &lenient).unwrap();
+    assert_eq!(latin1.items()[0].argument_bytes(), b"Zo\xEB");
+    assert!(latin1.items()[0].arguments_utf8().is_none());
+    assert_eq!(
+        parse_complete_with(b"platform a\x01b\n", &lenient).err(),
+        Some(Error::InvalidArgument {
+            keyword: "platform".to_string(),
+            index: 0,
+            offset: 10,
+            reason: "invalid character 0x01".to_string(),
+        })
+    );
+    // Layout errors name the argument that follows the whitespace
+    let error = ConsensusDocument::parse_with(tabbed.as_bytes(), &strict).err();
+    assert_eq!(
+        error,
+        Some(Error::InvalidArgument {
+            keyword: "voting-delay".to_string(),
+            index: 0,
+            offset: tabbed.find('\t').unwrap(),
+            reason: "arguments must be separated by a single space".to_string(),
+        })
+    );
+}
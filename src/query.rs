@@ -0,0 +1,537 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{ConsensusDocument, Router};
+use error::Error;
+use identity::{Ed25519Identity, RsaIdentity};
+use std::cmp::Ordering;
+use std::net::IpAddr;
+use std::str::FromStr;
+use version::TorVersion;
+
+/// A filter over the router status entries of a consensus, such as
+/// "Exit AND Fast AND bandwidth > 5000 AND allows port 443 AND version >= 0.3.2".
+///
+/// ```text
+/// Query      = Or
+/// Or         = And *("OR" And)
+/// And        = Not *("AND" Not)
+/// Not        = "NOT" Not / "(" Or ")" / Condition
+/// Condition  = Flag / "flag" Flag
+///            / "nickname" ["="] Glob
+///            / "fingerprint" ["="] Identity
+///            / "address" ["=" / "in"] IP ["/" Bits]
+///            / ["allows"] "port" Port
+///            / "bandwidth" Comparison Weight
+///            / "version" Comparison Version
+///            / "supports" Protocol ["=" Version]
+/// Comparison = "=" / "==" / "!=" / "<" / "<=" / ">" / ">="
+/// ```
+///
+/// AND, OR and NOT may be written in any case, and AND binds tighter than
+/// OR. Any other word that isn't a field name is a flag, and flags and
+/// nicknames match regardless of case. Nickname globs use "*" and "?".
+/// Identities may be RSA fingerprints in hex or base64, or ed25519 ones.
+/// Bandwidth is the consensus weight, so relays without one never match.
+/// A version without a patch level compares only its series, so
+/// "version = 0.3.2" matches every 0.3.2.x release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// The relay has the flag
+    Flag(String),
+    /// The nickname matches the glob
+    Nickname(String),
+    /// The relay has the RSA identity
+    RsaIdentity(RsaIdentity),
+    /// The relay has the ed25519 identity, which only votes list
+    Ed25519Identity(Ed25519Identity),
+    /// One of the relay's addresses is in the network
+    Address(IpAddr, u8),
+    /// The exit policy summary allows the port
+    AllowsPort(u16),
+    /// The consensus weight compares to the value as given
+    Bandwidth(Comparison, u32),
+    /// The Tor version compares to the version as given
+    Version(Comparison, TorVersion),
+    /// The relay supports the protocol, at the version if one is given
+    Supports(String, Option<u32>),
+    /// The query doesn't match
+    Not(Box<Query>),
+    /// Every query matches
+    And(Vec<Query>),
+    /// Any query matches
+    Or(Vec<Query>),
+}
+/// How a relay's value must compare to the one in the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+/// A word or symbol of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Word(&'a str),
+    Symbol(&'static str),
+}
+/// A recursive descent parser over the tokens of a query
+struct Parser<'a> {
+    tokens: Vec<(usize, Token<'a>)>,
+    position: usize,
+    /// The length of the query, where errors about its end are reported
+    end: usize,
+}
+
+/// Symbols, longest first so that "<=" isn't read as "<" and "="
+const SYMBOLS: &[&str] = &["==", "!=", "<=", ">=", "=", "<", ">", "(", ")"];
+/// Words that start a condition rather than name a flag
+const FIELDS: &[&str] = &[
+    "flag",
+    "nickname",
+    "fingerprint",
+    "address",
+    "allows",
+    "port",
+    "bandwidth",
+    "version",
+    "supports",
+];
+
+impl Query {
+    /// Parses a query, reporting where it went wrong if it can't
+    pub fn parse(query: &str) -> Result<Self, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            position: 0,
+            end: query.len(),
+        };
+        let parsed = parser.or()?;
+        match parser.peek() {
+            None => Ok(parsed),
+            Some((offset, Token::Symbol(")"))) => Err(invalid(offset, "unmatched \")\"")),
+            Some((offset, _)) => Err(invalid(offset, "expected AND, OR or the end of the query")),
+        }
+    }
+    /// Whether the relay matches the query
+    pub fn matches(&self, router: &Router) -> bool {
+        match *self {
+            Query::Flag(ref flag) => router
+                .flags
+                .iter()
+                .any(|other| other.eq_ignore_ascii_case(flag)),
+            Query::Nickname(ref glob) => glob_matches(
+                glob.to_lowercase().as_bytes(),
+                router.nickname.to_lowercase().as_bytes(),
+            ),
+            Query::RsaIdentity(ref identity) => router.rsa_identity().as_ref() == Some(identity),
+            Query::Ed25519Identity(ref identity) => {
+                router.ed25519_identity().as_ref() == Some(identity)
+            }
+            Query::Address(network, bits) => ::std::iter::once(IpAddr::V4(router.ip))
+                .chain(router.addresses.iter().map(|address| address.ip()))
+                .any(|address| in_network(address, network, bits)),
+            Query::AllowsPort(port) => router.allows_port(port),
+            Query::Bandwidth(comparison, weight) => router
+                .bandwidth
+                .as_ref()
+                .is_some_and(|bandwidth| comparison.holds(bandwidth.bandwidth.cmp(&weight))),
+            Query::Version(comparison, ref version) => {
+                router.tor_version().is_some_and(|running| {
+                    let ordering = if version.patchlevel.is_none() && version.status_tag.is_none() {
+                        (running.major, running.minor, running.micro).cmp(&(
+                            version.major,
+                            version.minor,
+                            version.micro,
+                        ))
+                    } else {
+                        running.cmp(version)
+                    };
+                    comparison.holds(ordering)
+                })
+            }
+            Query::Supports(ref protocol, version) => router
+                .protocols
+                .iter()
+                .filter(|entry| entry.name == *protocol)
+                .any(|entry| {
+                    version.is_none_or(|version| {
+                        entry
+                            .versions
+                            .iter()
+                            .any(|&(low, high)| low <= version && version <= high)
+                    })
+                }),
+            Query::Not(ref query) => !query.matches(router),
+            Query::And(ref queries) => queries.iter().all(|query| query.matches(router)),
+            Query::Or(ref queries) => queries.iter().any(|query| query.matches(router)),
+        }
+    }
+}
+impl FromStr for Query {
+    type Err = Error;
+    fn from_str(query: &str) -> Result<Self, Error> {
+        Query::parse(query)
+    }
+}
+impl Comparison {
+    /// Whether a value that orders this way against the query's value
+    /// satisfies the comparison
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+impl ConsensusDocument {
+    /// The router status entries that match the query, in consensus order
+    pub fn select(&self, query: &Query) -> Vec<&Router> {
+        self.routers
+            .iter()
+            .filter(|router| query.matches(router))
+            .collect()
+    }
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(usize, Token<'a>)> {
+        self.tokens.get(self.position).cloned()
+    }
+    fn next(&mut self) -> Option<(usize, Token<'a>)> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+    /// Consumes the next token if it is the word, in any case
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some((_, Token::Word(word))) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Consumes the next token if it is the symbol
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some((_, Token::Symbol(other))) if other == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Consumes a word, naming what was expected if there is none
+    fn word(&mut self, expected: &str) -> Result<(usize, &'a str), Error> {
+        match self.next() {
+            Some((offset, Token::Word(word))) => Ok((offset, word)),
+            Some((offset, Token::Symbol(symbol))) => Err(invalid(
+                offset,
+                format!("expected {}, found \"{}\"", expected, symbol),
+            )),
+            None => Err(invalid(
+                self.end,
+                format!("expected {}, found the end of the query", expected),
+            )),
+        }
+    }
+    /// Consumes a word and parses it
+    fn value<T: FromStr>(&mut self, expected: &str) -> Result<T, Error> {
+        let (offset, word) = self.word(expected)?;
+        word.parse()
+            .map_err(|_| invalid(offset, format!("expected {}, found \"{}\"", expected, word)))
+    }
+    fn or(&mut self) -> Result<Query, Error> {
+        let mut queries = vec![self.and()?];
+        while self.keyword("or") {
+            queries.push(self.and()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::Or(queries),
+        })
+    }
+    fn and(&mut self) -> Result<Query, Error> {
+        let mut queries = vec![self.not()?];
+        while self.keyword("and") {
+            queries.push(self.not()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        })
+    }
+    fn not(&mut self) -> Result<Query, Error> {
+        if self.keyword("not") {
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        if let Some((offset, Token::Symbol("("))) = self.peek() {
+            self.position += 1;
+            let query = self.or()?;
+            if !self.symbol(")") {
+                return Err(invalid(offset, "unclosed \"(\""));
+            }
+            return Ok(query);
+        }
+        self.condition()
+    }
+    fn condition(&mut self) -> Result<Query, Error> {
+        let (offset, field) = self.word("a flag, a field or \"(\"")?;
+        if ["and", "or"]
+            .iter()
+            .any(|word| field.eq_ignore_ascii_case(word))
+        {
+            return Err(invalid(
+                offset,
+                format!("expected a flag, a field or \"(\" before {}", field),
+            ));
+        }
+        if !FIELDS.contains(&field) {
+            return Ok(Query::Flag(field.to_string()));
+        }
+        Ok(match field {
+            "flag" => Query::Flag(self.word("a flag")?.1.to_string()),
+            "nickname" => {
+                self.symbol("=");
+                Query::Nickname(self.word("a nickname")?.1.to_string())
+            }
+            "fingerprint" => {
+                self.symbol("=");
+                let (offset, identity) = self.word("a fingerprint")?;
+                match RsaIdentity::parse(identity) {
+                    Some(identity) => Query::RsaIdentity(identity),
+                    None => Query::Ed25519Identity(Ed25519Identity::parse(identity).ok_or_else(
+                        || invalid(offset, format!("\"{}\" is not a fingerprint", identity)),
+                    )?),
+                }
+            }
+            "address" => {
+                if !self.symbol("=") {
+                    self.keyword("in");
+                }
+                let (offset, network) = self.word("an address")?;
+                parse_network(network)
+                    .ok_or_else(|| invalid(offset, format!("\"{}\" is not an address", network)))?
+            }
+            "allows" | "port" => {
+                if field == "allows" && !self.keyword("port") {
+                    let offset = self.peek().map_or(self.end, |token| token.0);
+                    return Err(invalid(offset, "expected \"port\" after \"allows\""));
+                }
+                Query::AllowsPort(self.value("a port")?)
+            }
+            "bandwidth" => {
+                let comparison = self.comparison()?;
+                Query::Bandwidth(comparison, self.value("a consensus weight")?)
+            }
+            "version" => {
+                let comparison = self.comparison()?;
+                let (offset, version) = self.word("a Tor version")?;
+                Query::Version(
+                    comparison,
+                    TorVersion::parse(version).ok_or_else(|| {
+                        invalid(offset, format!("\"{}\" is not a Tor version", version))
+                    })?,
+                )
+            }
+            "supports" => {
+                let protocol = self.word("a protocol")?.1.to_string();
+                let version = if self.symbol("=") {
+                    Some(self.value("a protocol version")?)
+                } else {
+                    None
+                };
+                Query::Supports(protocol, version)
+            }
+            _ => unreachable!(),
+        })
+    }
+    fn comparison(&mut self) -> Result<Comparison, Error> {
+        let (offset, token) = match self.next() {
+            Some(token) => token,
+            None => return Err(invalid(self.end, "expected a comparison")),
+        };
+        Ok(match token {
+            Token::Symbol("=") | Token::Symbol("==") => Comparison::Equal,
+            Token::Symbol("!=") => Comparison::NotEqual,
+            Token::Symbol("<") => Comparison::Less,
+            Token::Symbol("<=") => Comparison::LessOrEqual,
+            Token::Symbol(">") => Comparison::Greater,
+            Token::Symbol(">=") => Comparison::GreaterOrEqual,
+            _ => return Err(invalid(offset, "expected a comparison")),
+        })
+    }
+}
+
+/// Splits a query into words and symbols
+fn tokenize(query: &str) -> Result<Vec<(usize, Token<'_>)>, Error> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while position < query.len() {
+        let rest = &query[position..];
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push((position, Token::Symbol(symbol)));
+            position += symbol.len();
+            continue;
+        }
+        let character = rest.chars().next().unwrap_or(' ');
+        if character.is_whitespace() {
+            position += character.len_utf8();
+            continue;
+        }
+        if character == '!' {
+            return Err(invalid(position, "expected \"!=\""));
+        }
+        let length = rest
+            .find(|character: char| character.is_whitespace() || "()<>=!".contains(character))
+            .unwrap_or(rest.len());
+        tokens.push((position, Token::Word(&rest[..length])));
+        position += length;
+    }
+    Ok(tokens)
+}
+/// Parses an address, or a network in CIDR notation, as a condition
+fn parse_network(network: &str) -> Option<Query> {
+    let (address, bits) = match network.find('/') {
+        Some(slash) => (&network[..slash], Some(&network[slash + 1..])),
+        None => (network, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    let maximum = if address.is_ipv4() { 32 } else { 128 };
+    let bits = match bits {
+        Some(bits) => bits.parse().ok().filter(|bits| *bits <= maximum)?,
+        None => maximum,
+    };
+    Some(Query::Address(address, bits))
+}
+/// Whether the address is in the network with the given prefix length
+fn in_network(address: IpAddr, network: IpAddr, bits: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(bits)).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(bits)).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+/// Matches a glob where "*" stands for any run of bytes and "?" for any
+/// one byte
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        Some((&byte, rest)) => match text.split_first() {
+            Some((&other, text)) if byte == b'?' || byte == other => glob_matches(rest, text),
+            _ => false,
+        },
+    }
+}
+/// Shorthand for building an `InvalidQuery`
+fn invalid<R: Into<String>>(offset: usize, reason: R) -> Error {
+    Error::InvalidQuery {
+        offset,
+        reason: reason.into(),
+    }
+}
+
+/// Test
+#[test]
+fn test_query() {
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/barebones.consensus"))
+        .expect("failed to parse consensus");
+    let nicknames = |query: &str| -> Vec<String> {
+        consensus
+            .select(&Query::parse(query).expect("failed to parse query"))
+            .iter()
+            .map(|router| router.nickname.clone())
+            .collect()
+    };
+    assert_eq!(
+        nicknames("Exit AND Fast AND bandwidth > 5000 AND allows port 443 AND version >= 0.3.2"),
+        ["CalyxInstitute14"]
+    );
+    assert_eq!(nicknames("fast"), ["Unnamed", "CalyxInstitute14"]);
+    assert_eq!(nicknames("NOT Fast or Exit"), ["seele", "CalyxInstitute14"]);
+    assert_eq!(nicknames("not (Fast or Exit)"), ["seele"]);
+    assert_eq!(nicknames("nickname = calyx*"), ["CalyxInstitute14"]);
+    assert_eq!(nicknames("nickname s??le"), ["seele"]);
+    assert_eq!(
+        nicknames("version = 0.3.2"),
+        ["Unnamed", "CalyxInstitute14"]
+    );
+    assert_eq!(nicknames("version < 0.3.2.1"), ["seele"]);
+    assert_eq!(nicknames("bandwidth <= 997"), ["seele", "Unnamed"]);
+    assert_eq!(
+        nicknames("address in 162.247.72.0/24"),
+        ["CalyxInstitute14"]
+    );
+    assert_eq!(nicknames("address = 67.161.31.147"), ["seele"]);
+    assert!(nicknames("address 2001:db8::/32").is_empty());
+    assert_eq!(
+        nicknames("fingerprint $0011BD2485AD45D984EC4159C88FC066E5E3300E"),
+        ["CalyxInstitute14"]
+    );
+    assert_eq!(
+        nicknames("supports LinkAuth=3 AND port 443"),
+        ["CalyxInstitute14"]
+    );
+    assert!(nicknames("supports LinkAuth=2 OR supports Padding").is_empty());
+    assert_eq!(nicknames("flag Guard"), ["CalyxInstitute14"]);
+    let error = |query: &str| match Query::parse(query) {
+        Err(Error::InvalidQuery { offset, reason }) => (offset, reason),
+        other => panic!("expected an error, found {:?}", other),
+    };
+    assert_eq!(
+        error("Exit AND bandwidth five"),
+        (19, "expected a comparison".to_string())
+    );
+    assert_eq!(
+        error("Exit AND (Fast OR Guard"),
+        (9, "unclosed \"(\"".to_string())
+    );
+    assert_eq!(
+        error("Exit Fast"),
+        (5, "expected AND, OR or the end of the query".to_string())
+    );
+    assert_eq!(
+        error("version >= 0.3"),
+        (11, "\"0.3\" is not a Tor version".to_string())
+    );
+    assert_eq!(
+        error("Exit AND"),
+        (
+            8,
+            "expected a flag, a field or \"(\", found the end of the query".to_string()
+        )
+    );
+    assert_eq!(
+        error("allows port 70000"),
+        (12, "expected a port, found \"70000\"".to_string())
+    );
+    assert_eq!(error("Exit)").0, 4);
+}
@@ -0,0 +1,944 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use document::{Document, Item};
+use error::Error;
+use std::fmt;
+
+/// The maximum of a rule for items that may appear any number of times
+pub const ANY: usize = usize::MAX;
+
+/// Where an item may appear within its section or group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Anywhere,
+    /// "[At start]": the first item
+    AtStart,
+    /// "[At end]": no other kind of item may follow
+    AtEnd,
+    /// Immediately after an item with the given keyword
+    After(&'static str),
+}
+/// Which kind of status document an item may appear in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applicability {
+    Both,
+    /// "[does not occur in consensuses]"
+    VoteOnly,
+    /// "[does not occur in votes]"
+    ConsensusOnly,
+}
+/// What the specification says about one keyword
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub keyword: &'static str,
+    pub minimum: usize,
+    /// `ANY` for no limit
+    pub maximum: usize,
+    pub position: Position,
+    /// The minimum and maximum only apply to this kind of document, and the
+    /// item may not appear in the other
+    pub applies: Applicability,
+    /// The keyword of an object the item must carry
+    pub object: Option<&'static str>,
+    /// The minimum and maximum in votes, when they differ from consensuses
+    pub vote_counts: Option<(usize, usize)>,
+}
+/// A run of items with its own rules, such as the authority section of a
+/// consensus
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    pub name: &'static str,
+    /// Keywords that begin the section. The first section needs none.
+    pub starts: &'static [&'static str],
+    /// A keyword that begins each group of a repeated section, such as "r"
+    /// for router status entries. The rules apply to each group.
+    pub group: Option<&'static str>,
+    pub rules: &'static [Rule],
+}
+/// The rule table of a document type, as sections in the order they appear
+#[derive(Debug, Clone, Copy)]
+pub struct DocumentRules {
+    pub name: &'static str,
+    pub sections: &'static [Section],
+}
+/// A rule that a document breaks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The section the item is in
+    pub section: &'static str,
+    pub keyword: String,
+    /// The index of the offending item in the document, if it is present
+    pub index: Option<usize>,
+    pub kind: ViolationKind,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// Fewer than the minimum number of items
+    Missing { minimum: usize, found: usize },
+    /// More than the maximum number of items, reported at the first extra
+    TooMany { maximum: usize, found: usize },
+    /// The item is not where its position rule allows
+    Misplaced(Position),
+    /// A vote-only item in a consensus
+    VoteOnly,
+    /// A consensus-only item in a vote
+    ConsensusOnly,
+    /// The item lacks the object it must carry
+    MissingObject(&'static str),
+    /// The item belongs to another section
+    WrongSection(&'static str),
+}
+
+impl Rule {
+    /// A rule that the keyword appears between `minimum` and `maximum`
+    /// times, anywhere, in any kind of document and without an object
+    pub const fn new(keyword: &'static str, minimum: usize, maximum: usize) -> Self {
+        Rule {
+            keyword,
+            minimum,
+            maximum,
+            position: Position::Anywhere,
+            applies: Applicability::Both,
+            object: None,
+            vote_counts: None,
+        }
+    }
+    pub const fn at_start(self) -> Self {
+        self.at(Position::AtStart)
+    }
+    pub const fn at_end(self) -> Self {
+        self.at(Position::AtEnd)
+    }
+    pub const fn at(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+    pub const fn vote_only(mut self) -> Self {
+        self.applies = Applicability::VoteOnly;
+        self
+    }
+    pub const fn consensus_only(mut self) -> Self {
+        self.applies = Applicability::ConsensusOnly;
+        self
+    }
+    pub const fn object(mut self, keyword: &'static str) -> Self {
+        self.object = Some(keyword);
+        self
+    }
+    pub const fn in_votes(mut self, minimum: usize, maximum: usize) -> Self {
+        self.vote_counts = Some((minimum, maximum));
+        self
+    }
+    /// The minimum and maximum for a vote or any other document
+    pub fn counts(&self, is_vote: bool) -> (usize, usize) {
+        match self.vote_counts {
+            Some(counts) if is_vote => counts,
+            _ => (self.minimum, self.maximum),
+        }
+    }
+}
+
+impl Section {
+    /// Checks that every item the section's rules apply to appears an
+    /// allowed number of times, returning the first problem as the typed
+    /// parsers report it. Rules in `overrides` replace those for the same
+    /// keyword.
+    pub(crate) fn check_counts(
+        &self,
+        items: &[Item],
+        is_vote: bool,
+        overrides: &[Rule],
+    ) -> Result<(), Error> {
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| overrides.iter().all(|other| other.keyword != rule.keyword))
+            .chain(overrides);
+        for rule in rules {
+            let applies = match rule.applies {
+                Applicability::Both => true,
+                Applicability::VoteOnly => is_vote,
+                Applicability::ConsensusOnly => !is_vote,
+            };
+            if !applies {
+                continue;
+            }
+            let (minimum, maximum) = rule.counts(is_vote);
+            let count = items
+                .iter()
+                .filter(|item| item.keyword() == rule.keyword)
+                .count();
+            if count < minimum {
+                return Err(Error::MissingItem(rule.keyword));
+            }
+            if count > maximum {
+                return Err(Error::DuplicateItem(rule.keyword.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DocumentRules {
+    /// Checks the counts of a document with a single section, as
+    /// `Section::check_counts` does
+    pub(crate) fn check_counts(&self, items: &[Item], overrides: &[Rule]) -> Result<(), Error> {
+        self.sections[0].check_counts(items, false, overrides)
+    }
+    /// Whether any section has a rule for the keyword
+    pub fn knows(&self, keyword: &str) -> bool {
+        self.sections
+            .iter()
+            .any(|section| section.rules.iter().any(|rule| rule.keyword == keyword))
+    }
+    /// Checks a document against every rule, returning all violations in
+    /// document order, with missing items last
+    pub fn validate(&self, document: &Document) -> Vec<Violation> {
+        let items = document.items();
+        let is_vote = items
+            .iter()
+            .any(|item| item.keyword() == "vote-status" && item.arguments().trim() == "vote");
+        // Split the items into sections, which only go forward
+        let mut spans: Vec<(usize, usize, usize)> = Vec::new();
+        let mut current = 0;
+        let mut start = 0;
+        for (index, item) in items.iter().enumerate() {
+            let next = (current + 1..self.sections.len())
+                .find(|&section| self.sections[section].starts.contains(&item.keyword()));
+            if let Some(next) = next {
+                spans.push((current, start, index));
+                current = next;
+                start = index;
+            }
+        }
+        spans.push((current, start, items.len()));
+        let mut violations = Vec::new();
+        let mut missing = Vec::new();
+        for (index, section) in self.sections.iter().enumerate() {
+            let present: Vec<_> = spans
+                .iter()
+                .filter(|span| span.0 == index && (span.1 < span.2 || index == 0))
+                .collect();
+            // A missing repeated section is empty, while a missing single
+            // section still has its required items
+            if present.is_empty() && section.group.is_none() {
+                self.check_group(section, items, 0, 0, is_vote, &mut violations, &mut missing);
+            }
+            for &&(_, start, end) in &present {
+                let mut group_start = start;
+                for position in start..=end {
+                    let at_group = position < end
+                        && position > group_start
+                        && section.group == Some(items[position].keyword());
+                    if position == end || at_group {
+                        self.check_group(
+                            section,
+                            items,
+                            group_start,
+                            position,
+                            is_vote,
+                            &mut violations,
+                            &mut missing,
+                        );
+                        group_start = position;
+                    }
+                }
+            }
+        }
+        violations.sort_by_key(|violation| violation.index);
+        violations.extend(missing);
+        violations
+    }
+    /// Checks the items from `start` up to `end` against a section's rules
+    #[allow(clippy::too_many_arguments)]
+    fn check_group(
+        &self,
+        section: &Section,
+        items: &[Item],
+        start: usize,
+        end: usize,
+        is_vote: bool,
+        violations: &mut Vec<Violation>,
+        missing: &mut Vec<Violation>,
+    ) {
+        let violation = |keyword: &str, index: Option<usize>, kind| Violation {
+            section: section.name,
+            keyword: keyword.to_string(),
+            index,
+            kind,
+        };
+        for rule in section.rules {
+            let found: Vec<usize> = (start..end)
+                .filter(|&index| items[index].keyword() == rule.keyword)
+                .collect();
+            let applies = match rule.applies {
+                Applicability::Both => true,
+                Applicability::VoteOnly => is_vote,
+                Applicability::ConsensusOnly => !is_vote,
+            };
+            if !applies {
+                let kind = match rule.applies {
+                    Applicability::VoteOnly => ViolationKind::VoteOnly,
+                    _ => ViolationKind::ConsensusOnly,
+                };
+                for &index in &found {
+                    violations.push(violation(rule.keyword, Some(index), kind.clone()));
+                }
+                continue;
+            }
+            let (minimum, maximum) = rule.counts(is_vote);
+            if found.len() < minimum {
+                missing.push(violation(
+                    rule.keyword,
+                    None,
+                    ViolationKind::Missing {
+                        minimum,
+                        found: found.len(),
+                    },
+                ));
+            }
+            if found.len() > maximum {
+                violations.push(violation(
+                    rule.keyword,
+                    Some(found[maximum]),
+                    ViolationKind::TooMany {
+                        maximum,
+                        found: found.len(),
+                    },
+                ));
+            }
+            for &index in &found {
+                let placed = match rule.position {
+                    Position::Anywhere => true,
+                    Position::AtStart => index == start,
+                    Position::AtEnd => items[index + 1..end]
+                        .iter()
+                        .all(|item| item.keyword() == rule.keyword),
+                    Position::After(keyword) => {
+                        index > start && items[index - 1].keyword() == keyword
+                    }
+                };
+                if !placed {
+                    violations.push(violation(
+                        rule.keyword,
+                        Some(index),
+                        ViolationKind::Misplaced(rule.position),
+                    ));
+                }
+                if let Some(object) = rule.object {
+                    if items[index].objects().first().map(|found| found.keyword()) != Some(object) {
+                        violations.push(violation(
+                            rule.keyword,
+                            Some(index),
+                            ViolationKind::MissingObject(object),
+                        ));
+                    }
+                }
+            }
+        }
+        // Items with a rule in another section only
+        for (index, item) in items.iter().enumerate().take(end).skip(start) {
+            if section
+                .rules
+                .iter()
+                .any(|rule| rule.keyword == item.keyword())
+            {
+                continue;
+            }
+            let elsewhere = self.sections.iter().find(|other| {
+                other
+                    .rules
+                    .iter()
+                    .any(|rule| rule.keyword == item.keyword())
+            });
+            if let Some(other) = elsewhere {
+                violations.push(violation(
+                    item.keyword(),
+                    Some(index),
+                    ViolationKind::WrongSection(other.name),
+                ));
+            }
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Position::Anywhere => write!(f, "anywhere"),
+            Position::AtStart => write!(f, "at start"),
+            Position::AtEnd => write!(f, "at end"),
+            Position::After(keyword) => write!(f, "right after \"{}\"", keyword),
+        }
+    }
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(index) = self.index {
+            write!(f, "item {}: ", index + 1)?;
+        }
+        write!(f, "{} \"{}\" ", self.section, self.keyword)?;
+        match self.kind {
+            ViolationKind::Missing { minimum, found } => {
+                write!(
+                    f,
+                    "appears {} times but must appear at least {}",
+                    found, minimum
+                )
+            }
+            ViolationKind::TooMany { maximum, found } => {
+                write!(
+                    f,
+                    "appears {} times but may appear at most {}",
+                    found, maximum
+                )
+            }
+            ViolationKind::Misplaced(position) => write!(f, "must appear {}", position),
+            ViolationKind::VoteOnly => write!(f, "may only appear in votes"),
+            ViolationKind::ConsensusOnly => write!(f, "may only appear in consensuses"),
+            ViolationKind::MissingObject(object) => write!(f, "lacks a {} object", object),
+            ViolationKind::WrongSection(section) => write!(f, "belongs in the {}", section),
+        }
+    }
+}
+
+/// Count overrides for server descriptors and extra-info documents
+/// sanitized by CollecTor, which removes keys and signatures and adds the
+/// digests of the original document
+pub const SANITIZED: &[Rule] = &[
+    Rule::new("identity-ed25519", 0, 0),
+    Rule::new("onion-key", 0, 1),
+    Rule::new("signing-key", 0, 1),
+    Rule::new("router-sig-ed25519", 0, 0),
+    Rule::new("router-signature", 0, 0),
+    Rule::new("router-digest", 1, 1),
+    Rule::new("router-digest-sha256", 0, 1),
+];
+
+/// Votes and consensuses, from section 3.4.1 of dir-spec.txt
+pub const NETWORK_STATUS: DocumentRules = DocumentRules {
+    name: "network status document",
+    sections: &[
+        NETWORK_STATUS_PREAMBLE,
+        NETWORK_STATUS_AUTHORITY,
+        NETWORK_STATUS_ROUTER,
+        NETWORK_STATUS_FOOTER,
+    ],
+};
+
+/// The preamble of votes and consensuses
+pub const NETWORK_STATUS_PREAMBLE: Section = Section {
+    name: "preamble",
+    starts: &[],
+    group: None,
+    rules: &[
+        Rule::new("network-status-version", 1, 1).at_start(),
+        Rule::new("vote-status", 1, 1),
+        Rule::new("consensus-methods", 0, 1).vote_only(),
+        Rule::new("consensus-method", 0, 1).consensus_only(),
+        Rule::new("published", 1, 1).vote_only(),
+        Rule::new("valid-after", 1, 1),
+        Rule::new("fresh-until", 1, 1),
+        Rule::new("valid-until", 1, 1),
+        Rule::new("voting-delay", 1, 1),
+        Rule::new("client-versions", 0, 1),
+        Rule::new("server-versions", 0, 1),
+        Rule::new("package", 0, ANY),
+        Rule::new("known-flags", 1, 1),
+        Rule::new("flag-thresholds", 0, 1).vote_only(),
+        Rule::new("recommended-client-protocols", 0, 1),
+        Rule::new("recommended-relay-protocols", 0, 1),
+        Rule::new("required-client-protocols", 0, 1),
+        Rule::new("required-relay-protocols", 0, 1),
+        Rule::new("params", 0, 1),
+        Rule::new("shared-rand-previous-value", 0, 1),
+        Rule::new("shared-rand-current-value", 0, 1),
+    ],
+};
+
+/// One authority of the authority section of votes and consensuses
+pub const NETWORK_STATUS_AUTHORITY: Section = Section {
+    name: "authority section",
+    starts: &["dir-source"],
+    group: Some("dir-source"),
+    rules: &[
+        Rule::new("dir-source", 1, 1).at_start(),
+        // The "-legacy" entries of a consensus have no contact
+        Rule::new("contact", 0, 1),
+        Rule::new("legacy-dir-key", 0, 1).vote_only(),
+        Rule::new("shared-rand-participate", 0, 1).vote_only(),
+        Rule::new("shared-rand-commit", 0, ANY).vote_only(),
+        Rule::new("shared-rand-previous-value", 0, 1).vote_only(),
+        Rule::new("shared-rand-current-value", 0, 1).vote_only(),
+        Rule::new("vote-digest", 1, 1).consensus_only(),
+        // The authority's key certificate follows in a vote
+        Rule::new("dir-key-certificate-version", 0, 1).vote_only(),
+        Rule::new("dir-address", 0, 1).vote_only(),
+        Rule::new("fingerprint", 0, 1).vote_only(),
+        Rule::new("dir-key-published", 0, 1).vote_only(),
+        Rule::new("dir-key-expires", 0, 1).vote_only(),
+        Rule::new("dir-identity-key", 0, 1)
+            .vote_only()
+            .object("RSA PUBLIC KEY"),
+        Rule::new("dir-signing-key", 0, 1)
+            .vote_only()
+            .object("RSA PUBLIC KEY"),
+        Rule::new("dir-key-crosscert", 0, 1).vote_only(),
+        Rule::new("dir-key-certification", 0, 1)
+            .vote_only()
+            .object("SIGNATURE"),
+    ],
+};
+
+/// One router status entry of votes and consensuses
+pub const NETWORK_STATUS_ROUTER: Section = Section {
+    name: "router status entry",
+    starts: &["r"],
+    group: Some("r"),
+    rules: &[
+        Rule::new("r", 1, 1).at_start(),
+        Rule::new("a", 0, ANY),
+        Rule::new("s", 1, 1),
+        Rule::new("v", 0, 1),
+        Rule::new("pr", 0, 1),
+        Rule::new("w", 0, 1),
+        Rule::new("p", 0, 1),
+        Rule::new("m", 0, ANY),
+        Rule::new("id", 0, 1).vote_only(),
+    ],
+};
+
+/// The footer of votes and consensuses
+pub const NETWORK_STATUS_FOOTER: Section = Section {
+    name: "footer",
+    starts: &[
+        "directory-footer",
+        "bandwidth-weights",
+        "directory-signature",
+    ],
+    group: None,
+    rules: &[
+        Rule::new("directory-footer", 0, 1).at_start(),
+        Rule::new("bandwidth-weights", 0, 1).consensus_only(),
+        // A vote carries only the signature of its authority
+        Rule::new("directory-signature", 1, ANY)
+            .in_votes(1, 1)
+            .at_end()
+            .object("SIGNATURE"),
+    ],
+};
+
+/// Server descriptors, from section 2.1.1 of dir-spec.txt
+pub const SERVER_DESCRIPTOR: DocumentRules = DocumentRules {
+    name: "server descriptor",
+    sections: &[Section {
+        name: "server descriptor",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("router", 1, 1).at_start(),
+            Rule::new("identity-ed25519", 0, 1)
+                .at(Position::After("router"))
+                .object("ED25519 CERT"),
+            Rule::new("master-key-ed25519", 0, 1),
+            Rule::new("bandwidth", 1, 1),
+            Rule::new("platform", 0, 1),
+            Rule::new("published", 1, 1),
+            Rule::new("fingerprint", 0, 1),
+            Rule::new("hibernating", 0, 1),
+            Rule::new("uptime", 0, 1),
+            Rule::new("onion-key", 1, 1).object("RSA PUBLIC KEY"),
+            Rule::new("onion-key-crosscert", 0, 1).object("CROSSCERT"),
+            Rule::new("ntor-onion-key", 0, 1),
+            Rule::new("ntor-onion-key-crosscert", 0, 1).object("ED25519 CERT"),
+            Rule::new("signing-key", 1, 1).object("RSA PUBLIC KEY"),
+            Rule::new("accept", 0, ANY),
+            Rule::new("reject", 0, ANY),
+            Rule::new("ipv6-policy", 0, 1),
+            Rule::new("router-sig-ed25519", 0, 1),
+            Rule::new("router-signature", 1, 1)
+                .at_end()
+                .object("SIGNATURE"),
+            Rule::new("contact", 0, 1),
+            Rule::new("bridge-distribution-request", 0, 1),
+            Rule::new("family", 0, 1),
+            Rule::new("read-history", 0, 1),
+            Rule::new("write-history", 0, 1),
+            Rule::new("eventdns", 0, 1),
+            Rule::new("caches-extra-info", 0, 1),
+            Rule::new("extra-info-digest", 0, 1),
+            Rule::new("hidden-service-dir", 0, 1),
+            Rule::new("protocols", 0, 1),
+            Rule::new("allow-single-hop-exits", 0, 1),
+            Rule::new("or-address", 0, ANY),
+            Rule::new("tunnelled-dir-server", 0, 1),
+            Rule::new("proto", 0, 1),
+        ],
+    }],
+};
+
+/// Count overrides for server descriptors from relays new enough that
+/// "proto" is required, as it is since 0.2.9
+pub const REQUIRED_PROTO: &[Rule] = &[Rule::new("proto", 1, 1)];
+
+/// Extra-info documents, from section 2.1.2 of dir-spec.txt
+pub const EXTRA_INFO: DocumentRules = DocumentRules {
+    name: "extra-info document",
+    sections: &[Section {
+        name: "extra-info document",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("extra-info", 1, 1).at_start(),
+            Rule::new("identity-ed25519", 0, 1)
+                .at(Position::After("extra-info"))
+                .object("ED25519 CERT"),
+            Rule::new("published", 1, 1),
+            Rule::new("read-history", 0, 1),
+            Rule::new("write-history", 0, 1),
+            Rule::new("ipv6-read-history", 0, 1),
+            Rule::new("ipv6-write-history", 0, 1),
+            Rule::new("dirreq-read-history", 0, 1),
+            Rule::new("dirreq-write-history", 0, 1),
+            Rule::new("geoip-db-digest", 0, 1),
+            Rule::new("geoip6-db-digest", 0, 1),
+            Rule::new("dirreq-stats-end", 0, 1),
+            Rule::new("dirreq-v3-ips", 0, 1),
+            Rule::new("dirreq-v3-reqs", 0, 1),
+            Rule::new("dirreq-v3-resp", 0, 1),
+            Rule::new("dirreq-v3-direct-dl", 0, 1),
+            Rule::new("dirreq-v3-tunneled-dl", 0, 1),
+            Rule::new("entry-stats-end", 0, 1),
+            Rule::new("entry-ips", 0, 1),
+            Rule::new("cell-stats-end", 0, 1),
+            Rule::new("cell-processed-cells", 0, 1),
+            Rule::new("cell-queued-cells", 0, 1),
+            Rule::new("cell-time-in-queue", 0, 1),
+            Rule::new("cell-circuits-per-decile", 0, 1),
+            Rule::new("exit-stats-end", 0, 1),
+            Rule::new("exit-kibibytes-written", 0, 1),
+            Rule::new("exit-kibibytes-read", 0, 1),
+            Rule::new("exit-streams-opened", 0, 1),
+            Rule::new("conn-bi-direct", 0, 1),
+            Rule::new("hidserv-stats-end", 0, 1),
+            Rule::new("hidserv-rend-relayed-cells", 0, 1),
+            Rule::new("hidserv-dir-onions-seen", 0, 1),
+            Rule::new("padding-counts", 0, 1),
+            Rule::new("bridge-stats-end", 0, 1),
+            Rule::new("bridge-ips", 0, 1),
+            Rule::new("bridge-ip-versions", 0, 1),
+            Rule::new("bridge-ip-transports", 0, 1),
+            Rule::new("transport", 0, ANY),
+            Rule::new("router-sig-ed25519", 0, 1),
+            Rule::new("router-signature", 1, 1)
+                .at_end()
+                .object("SIGNATURE"),
+        ],
+    }],
+};
+
+/// Microdescriptors, from section 3.3 of dir-spec.txt
+pub const MICRODESCRIPTOR: DocumentRules = DocumentRules {
+    name: "microdescriptor",
+    sections: &[Section {
+        name: "microdescriptor",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("onion-key", 1, 1)
+                .at_start()
+                .object("RSA PUBLIC KEY"),
+            Rule::new("ntor-onion-key", 1, 1),
+            Rule::new("a", 0, ANY),
+            Rule::new("family", 0, 1),
+            Rule::new("p", 0, 1),
+            Rule::new("p6", 0, 1),
+            Rule::new("id", 0, 2),
+        ],
+    }],
+};
+
+/// Authority key certificates, from section 3.1 of dir-spec.txt
+pub const KEY_CERTIFICATE: DocumentRules = DocumentRules {
+    name: "key certificate",
+    sections: &[Section {
+        name: "key certificate",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("dir-key-certificate-version", 1, 1).at_start(),
+            Rule::new("dir-address", 0, 1),
+            Rule::new("fingerprint", 1, 1),
+            Rule::new("dir-key-published", 1, 1),
+            Rule::new("dir-key-expires", 1, 1),
+            Rule::new("dir-identity-key", 1, 1).object("RSA PUBLIC KEY"),
+            Rule::new("dir-signing-key", 1, 1).object("RSA PUBLIC KEY"),
+            Rule::new("dir-key-crosscert", 1, 1),
+            Rule::new("dir-key-certification", 1, 1)
+                .at_end()
+                .object("SIGNATURE"),
+        ],
+    }],
+};
+
+/// Detached signature documents, from section 3.10 of dir-spec.txt
+pub const DETACHED_SIGNATURES: DocumentRules = DocumentRules {
+    name: "detached signature document",
+    sections: &[Section {
+        name: "detached signature document",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("consensus-digest", 0, 1).at_start(),
+            Rule::new("valid-after", 1, 1),
+            Rule::new("fresh-until", 1, 1),
+            Rule::new("valid-until", 1, 1),
+            Rule::new("additional-digest", 0, ANY),
+            Rule::new("additional-signature", 0, ANY).object("SIGNATURE"),
+            Rule::new("directory-signature", 0, ANY).object("SIGNATURE"),
+        ],
+    }],
+};
+
+/// The outer layer of version 3 onion service descriptors, from section
+/// 2.4 of rend-spec-v3.txt
+pub const HS_DESCRIPTOR: DocumentRules = DocumentRules {
+    name: "onion service descriptor",
+    sections: &[Section {
+        name: "outer layer",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("hs-descriptor", 1, 1).at_start(),
+            Rule::new("descriptor-lifetime", 1, 1),
+            Rule::new("descriptor-signing-key-cert", 1, 1).object("ED25519 CERT"),
+            Rule::new("revision-counter", 1, 1),
+            Rule::new("superencrypted", 1, 1).object("MESSAGE"),
+            Rule::new("signature", 1, 1).at_end(),
+        ],
+    }],
+};
+
+/// The middle layer of onion service descriptors, from section 2.5.1.2 of
+/// rend-spec-v3.txt
+pub const HS_SUPERENCRYPTED: DocumentRules = DocumentRules {
+    name: "onion service descriptor middle layer",
+    sections: &[Section {
+        name: "middle layer",
+        starts: &[],
+        group: None,
+        rules: &[
+            Rule::new("desc-auth-type", 1, 1),
+            Rule::new("desc-auth-ephemeral-key", 1, 1),
+            // Services without client authorization add fake clients
+            Rule::new("auth-client", 1, ANY),
+            Rule::new("encrypted", 1, 1).object("MESSAGE"),
+        ],
+    }],
+};
+
+/// Each introduction point of the inner layer of onion service descriptors
+pub const HS_INTRODUCTION_POINT: Section = Section {
+    name: "introduction point",
+    starts: &["introduction-point"],
+    group: Some("introduction-point"),
+    rules: &[
+        Rule::new("introduction-point", 1, 1).at_start(),
+        Rule::new("onion-key", 1, 1),
+        Rule::new("auth-key", 1, 1).object("ED25519 CERT"),
+        Rule::new("enc-key", 1, 1),
+        Rule::new("enc-key-cert", 1, 1).object("ED25519 CERT"),
+        Rule::new("legacy-key", 0, 1).object("RSA PUBLIC KEY"),
+        Rule::new("legacy-key-cert", 0, 1).object("CROSSCERT"),
+    ],
+};
+
+/// The inner layer of onion service descriptors, from section 2.5.2.2 of
+/// rend-spec-v3.txt
+pub const HS_ENCRYPTED: DocumentRules = DocumentRules {
+    name: "onion service descriptor inner layer",
+    sections: &[
+        Section {
+            name: "inner layer",
+            starts: &[],
+            group: None,
+            rules: &[
+                Rule::new("create2-formats", 1, 1).at_start(),
+                Rule::new("intro-auth-required", 0, 1),
+                Rule::new("single-onion-service", 0, 1),
+            ],
+        },
+        HS_INTRODUCTION_POINT,
+    ],
+};
+
+/// Test
+#[test]
+fn test_validate_rules() {
+    use document::parse_complete;
+    let validate =
+        |rules: &DocumentRules, input: &[u8]| rules.validate(&parse_complete(input).unwrap());
+    assert_eq!(
+        validate(
+            &NETWORK_STATUS,
+            include_bytes!("../test/barebones.consensus")
+        ),
+        vec![]
+    );
+    assert_eq!(
+        validate(&NETWORK_STATUS, include_bytes!("../test/barebones.vote")),
+        vec![]
+    );
+    assert_eq!(
+        validate(&NETWORK_STATUS, include_bytes!("../test/consensus")),
+        vec![]
+    );
+    assert_eq!(
+        validate(
+            &NETWORK_STATUS,
+            include_bytes!("../test/barebones.microdesc")
+        ),
+        vec![]
+    );
+    let broken = String::from_utf8_lossy(include_bytes!("../test/barebones.consensus"))
+        .replace("known-flags", "published 2018-02-08 07:00:00\nflags")
+        .replace(
+            "voting-delay 300 300\n",
+            "voting-delay 300 300\nvoting-delay 300 300\n",
+        )
+        .replacen(
+            "s Running Stable V2Dir Valid\n",
+            "s Running Stable V2Dir Valid\ncontact x\n",
+            1,
+        );
+    let violations = validate(&NETWORK_STATUS, broken.as_bytes());
+    let kinds: Vec<_> = violations
+        .iter()
+        .map(|violation| (violation.keyword.as_str(), violation.kind.clone()))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (
+                "voting-delay",
+                ViolationKind::TooMany {
+                    maximum: 1,
+                    found: 2,
+                },
+            ),
+            ("published", ViolationKind::VoteOnly),
+            ("contact", ViolationKind::WrongSection("authority section")),
+            (
+                "known-flags",
+                ViolationKind::Missing {
+                    minimum: 1,
+                    found: 0,
+                },
+            ),
+        ]
+    );
+    assert_eq!(
+        violations[1].to_string(),
+        "item 11: preamble \"published\" may only appear in votes"
+    );
+
+    // A vote has exactly one signature, which the parsers check as well
+    let vote = String::from_utf8_lossy(include_bytes!("../test/barebones.vote"));
+    let signature = &vote[vote.find("directory-signature ").unwrap()..];
+    let signed_twice = format!("{}{}", vote, signature);
+    let violations = validate(&NETWORK_STATUS, signed_twice.as_bytes());
+    assert_eq!(violations.len(), 1);
+    assert_eq!(
+        violations[0].kind,
+        ViolationKind::TooMany {
+            maximum: 1,
+            found: 2,
+        }
+    );
+    let document = parse_complete(signed_twice.as_bytes()).unwrap();
+    let footer = document
+        .items()
+        .iter()
+        .position(|item| item.keyword() == "directory-footer")
+        .unwrap();
+    let footer = &document.items()[footer..];
+    assert_eq!(
+        NETWORK_STATUS_FOOTER.check_counts(footer, true, &[]),
+        Err(Error::DuplicateItem("directory-signature".to_string()))
+    );
+    assert_eq!(
+        NETWORK_STATUS_FOOTER.check_counts(footer, false, &[]),
+        Ok(())
+    );
+
+    // Overrides replace the rules for their keywords
+    let bridge = include_bytes!("../test/bridge-descriptors");
+    let start = bridge.iter().position(|byte| *byte == b'\n').unwrap() + 1;
+    let descriptor = parse_complete(&bridge[start..]).unwrap();
+    assert_eq!(
+        SERVER_DESCRIPTOR.check_counts(descriptor.items(), &[]),
+        Err(Error::MissingItem("onion-key"))
+    );
+    assert_eq!(
+        SERVER_DESCRIPTOR.check_counts(descriptor.items(), SANITIZED),
+        Ok(())
+    );
+
+    // The parsers reject every duplicate the rules forbid
+    use certificate::KeyCertificate;
+    use consensus::ConsensusDocument;
+    use microdescriptor::Microdescriptor;
+    let duplicate = |input: &[u8], line: &str| {
+        String::from_utf8_lossy(input).replacen(line, &format!("{}{}", line, line), 1)
+    };
+    let consensus = &include_bytes!("../test/barebones.consensus")[..];
+    let twice = duplicate(consensus, "valid-after 2018-02-08 07:00:00\n").replacen(
+        "valid-after 2018-02-08",
+        "valid-after 2019-02-09",
+        1,
+    );
+    assert_eq!(
+        ConsensusDocument::parse(twice.as_bytes()).err(),
+        Some(Error::DuplicateItem("valid-after".to_string()))
+    );
+    let twice = duplicate(consensus, "vote-status consensus\n");
+    assert_eq!(
+        ConsensusDocument::parse(twice.as_bytes()).err(),
+        Some(Error::DuplicateItem("vote-status".to_string()))
+    );
+    let twice = duplicate(consensus, "\ns Running Stable V2Dir Valid");
+    assert_eq!(
+        ConsensusDocument::parse(twice.as_bytes()).err(),
+        Some(Error::DuplicateItem("s".to_string()))
+    );
+    let twice = duplicate(
+        include_bytes!("../test/key-certificate"),
+        "fingerprint FE8BE7DAE28F5C8E59E7B948DC10F4CC4C8BB639\n",
+    );
+    assert_eq!(
+        KeyCertificate::parse_all(twice.as_bytes()).err(),
+        Some(Error::DuplicateItem("fingerprint".to_string()))
+    );
+    let twice = duplicate(
+        include_bytes!("../test/microdescs"),
+        "ntor-onion-key S3c7PPYIA29or41AcCPU2ayFgesApfx0XaxcFThnMo4=\n",
+    );
+    assert_eq!(
+        Microdescriptor::parse_all(twice.as_bytes()).err(),
+        Some(Error::DuplicateItem("ntor-onion-key".to_string()))
+    );
+}
@@ -0,0 +1,311 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+//! Serde support, enabled by the `serde` feature.
+//!
+//! Parsed documents serialize to JSON shapes that are kept stable between
+//! releases. Field names follow the Rust field names, except where Onionoo
+//! has a name for the same thing:
+//!
+//! * RSA identities are uppercase hex strings named "fingerprint", even
+//!   where the document itself writes them in base64.
+//! * The "w" line bandwidth of a router is named "consensus_weight".
+//! * Port policy summaries are named "exit_policy_summary" and written as
+//!   `{"accept": ["80", "443-444"]}` or `{"reject": [...]}`.
+//! * Times are "YYYY-MM-DD HH:MM:SS" strings in UTC.
+//!
+//! Tor versions are strings such as "0.3.2.9-alpha". Keys, signatures and
+//! other binary data are unpadded base64 strings. Votes and consensuses
+//! have a "vote_status" object whose "type" is "vote" or "consensus".
+//! Metaformat items are `{"keyword": "r", "arguments": "...", "objects":
+//! [{"keyword": "SIGNATURE", "data": "..."}]}`, with "arguments" null when
+//! the line has none.
+//!
+//! Every shape deserializes back into the type it came from.
+use consensus::{parse_range_list, PortPolicy};
+use identity::{Ed25519Identity, RsaIdentity};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::collections::BTreeMap;
+use time::Timestamp;
+use version::TorVersion;
+
+/// Implements Serialize and Deserialize through the `Display` form of a type
+/// and the function that parses it
+macro_rules! string_form {
+    ($type:ty, $parse:path, $expected:expr) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let string = String::deserialize(deserializer)?;
+                $parse(&string).ok_or_else(|| {
+                    de::Error::invalid_value(de::Unexpected::Str(&string), &$expected)
+                })
+            }
+        }
+    };
+}
+string_form!(
+    Timestamp,
+    Timestamp::parse,
+    "a \"YYYY-MM-DD HH:MM:SS\" time"
+);
+string_form!(TorVersion, TorVersion::parse, "a Tor version");
+string_form!(
+    RsaIdentity,
+    RsaIdentity::parse,
+    "an RSA identity fingerprint"
+);
+string_form!(
+    Ed25519Identity,
+    Ed25519Identity::parse,
+    "an Ed25519 identity"
+);
+
+impl Serialize for PortPolicy {
+    /// Writes the policy as an Onionoo exit policy summary
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ports: Vec<String> = self
+            .ports
+            .iter()
+            .map(|&(low, high)| {
+                if low == high {
+                    low.to_string()
+                } else {
+                    format!("{}-{}", low, high)
+                }
+            })
+            .collect();
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(if self.accept { "accept" } else { "reject" }, &ports)?;
+        map.end()
+    }
+}
+impl<'de> Deserialize<'de> for PortPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let summary = BTreeMap::<String, Vec<String>>::deserialize(deserializer)?;
+        let mut entries = summary.into_iter();
+        let (accept, ranges) = match (entries.next(), entries.next()) {
+            (Some((action, ranges)), None) => match action.as_str() {
+                "accept" => (true, ranges),
+                "reject" => (false, ranges),
+                _ => return Err(de::Error::unknown_field(&action, &["accept", "reject"])),
+            },
+            _ => return Err(de::Error::invalid_length(2, &"one of accept or reject")),
+        };
+        let ports = parse_range_list(&ranges.join(",")).map_err(|range| {
+            de::Error::invalid_value(de::Unexpected::Str(range), &"a port or port range")
+        })?;
+        Ok(PortPolicy { accept, ports })
+    }
+}
+
+/// Binary data as unpadded base64
+pub(crate) mod base64_bytes {
+    use document::{decode_base64, encode_base64};
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_base64(bytes))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        decode_base64(&string)
+            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(&string), &"base64"))
+    }
+}
+
+/// Optional binary data as unpadded base64 or null
+pub(crate) mod base64_option {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    /// Wraps the data so it can go through `base64_bytes`
+    #[derive(Serialize, Deserialize)]
+    struct Bytes(#[serde(with = "super::base64_bytes")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match *bytes {
+            Some(ref bytes) => serializer.serialize_some(&Bytes(bytes.clone())),
+            None => serializer.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<Bytes>::deserialize(deserializer)?.map(|bytes| bytes.0))
+    }
+}
+
+/// A base64 RSA identity, as on "r" lines, written as an uppercase hex
+/// fingerprint. Malformed identities are written as they are.
+pub(crate) mod fingerprint {
+    use identity::RsaIdentity;
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(identity: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        match RsaIdentity::from_base64(identity) {
+            Some(identity) => serializer.serialize_str(&identity.to_hex()),
+            None => serializer.serialize_str(identity),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        Ok(RsaIdentity::from_hex(&string)
+            .map(|identity| identity.to_base64())
+            .unwrap_or(string))
+    }
+}
+
+/// A shared random value as `{"num_reveals": 9, "value": "base64"}` or null
+pub(crate) mod shared_rand {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    #[derive(Serialize, Deserialize)]
+    struct SharedRandValue {
+        num_reveals: i32,
+        #[serde(with = "super::base64_bytes")]
+        value: Vec<u8>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<(i32, Vec<u8>)>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match *value {
+            Some((num_reveals, ref value)) => serializer.serialize_some(&SharedRandValue {
+                num_reveals,
+                value: value.clone(),
+            }),
+            None => serializer.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<(i32, Vec<u8>)>, D::Error> {
+        Ok(Option::<SharedRandValue>::deserialize(deserializer)?
+            .map(|shared_rand| (shared_rand.num_reveals, shared_rand.value)))
+    }
+}
+
+/// Object data as a single string
+pub(crate) mod chars {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(data: &[char], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&data.iter().collect::<String>())
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<char>, D::Error> {
+        Ok(String::deserialize(deserializer)?.chars().collect())
+    }
+}
+
+/// Keyword line arguments as a string, with bytes that aren't valid UTF-8
+/// replaced with U+FFFD
+pub(crate) mod arguments {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(
+        arguments: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match *arguments {
+            Some(ref arguments) => serializer.serialize_some(&String::from_utf8_lossy(arguments)),
+            None => serializer.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<String>::deserialize(deserializer)?.map(String::into_bytes))
+    }
+}
+
+/// Reads a field that is left out when it is `None` and null when it is
+/// `Some(None)`, such as the "id ed25519" line of a vote
+pub(crate) fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Test
+#[test]
+fn test_serialization() {
+    use certificate::KeyCertificate;
+    use consensus::ConsensusDocument;
+    use document::{parse_complete, Document};
+    use serde_json;
+    let input = &include_bytes!("../test/barebones.consensus")[..];
+    let consensus = ConsensusDocument::parse(input).unwrap();
+    let json = serde_json::to_value(&consensus).unwrap();
+    assert_eq!(json["valid_after"], "2018-02-08 07:00:00");
+    assert_eq!(json["vote_status"]["type"], "consensus");
+    assert_eq!(json["flavor"], "ns");
+    let router = &json["routers"][0];
+    assert_eq!(router["nickname"], "seele");
+    assert_eq!(router["publication"], "2018-02-07 20:43:31");
+    assert_eq!(
+        router["fingerprint"],
+        "000A10D43011EA4928A35F610405F92B4433B4DC"
+    );
+    assert_eq!(router["exit_policy_summary"]["reject"][0], "1-65535");
+    assert!(router["bandwidth"]["consensus_weight"].is_u64());
+    assert!(router.get("ed25519_id").is_none());
+    assert_eq!(
+        json["authorities"][0]["fingerprint"],
+        "0232AF901C31A04EE9848595AF9BB7620D4C5B2E"
+    );
+    let parsed: ConsensusDocument = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed.routers[0].identity, consensus.routers[0].identity);
+    assert_eq!(parsed.valid_after, consensus.valid_after);
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+    let certificate = KeyCertificate::parse_all(include_bytes!("../test/key-certificate")).unwrap();
+    let json = serde_json::to_value(&certificate[0]).unwrap();
+    assert_eq!(json["published"], "2018-01-01 00:00:00");
+    assert_eq!(json["expires"], "2019-01-01 00:00:00");
+    let parsed: KeyCertificate = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.expires, certificate[0].expires);
+    // Metaformat documents keep their keywords, arguments and objects
+    let document = parse_complete(input).unwrap();
+    let json = serde_json::to_value(&document).unwrap();
+    assert_eq!(json["items"][0]["keyword"], "network-status-version");
+    assert_eq!(json["items"][0]["arguments"], "3");
+    let parsed: Document = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed.items().len(), document.items().len());
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+    assert!(json["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|item| item["objects"]
+            .as_array()
+            .is_some_and(|objects| !objects.is_empty())));
+}
@@ -0,0 +1,245 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use certificate::KeyCertificate;
+use consensus::ConsensusDocument;
+use document::encode_hex;
+use error::Error;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use time::Timestamp;
+
+/// Checks an RSA signature the way tor makes them, with PKCS#1 v1.5
+/// padding around the bare digest rather than a DigestInfo structure
+/// # Parameters
+/// * `key` - DER-encoded PKCS#1 public key, as in "RSA PUBLIC KEY" objects
+/// * `digest` - the digest that was signed
+/// * `signature` - the signature
+pub fn verify_rsa(key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+    RsaPublicKey::from_pkcs1_der(key)
+        .map(|key| {
+            key.verify(Pkcs1v15Sign::new_unprefixed(), digest, signature)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+impl KeyCertificate {
+    /// Uppercase hex SHA-1 digest of the signing key, as on
+    /// "directory-signature" lines
+    pub fn signing_key_digest(&self) -> String {
+        encode_hex(&Sha1::digest(&self.signing_key))
+    }
+    /// Checks that the fingerprint is the digest of the identity key, that
+    /// the signing key signed the identity key digest and that the identity
+    /// key signed the certificate. Only certificates parsed from their
+    /// bytes, on their own or in a vote, can pass the last check.
+    pub fn verify(&self) -> Result<(), Error> {
+        let identity_digest = Sha1::digest(&self.identity_key);
+        if !self
+            .fingerprint
+            .eq_ignore_ascii_case(&encode_hex(&identity_digest))
+        {
+            return Err(Error::invalid(
+                "fingerprint",
+                "not the digest of the identity key",
+            ));
+        }
+        if !verify_rsa(&self.signing_key, &identity_digest, &self.crosscert) {
+            return Err(Error::invalid(
+                "dir-key-crosscert",
+                "signature doesn't match the signing key",
+            ));
+        }
+        let digest = self.digest.ok_or_else(|| {
+            Error::invalid("dir-key-certification", "the certified bytes are unknown")
+        })?;
+        if !verify_rsa(&self.identity_key, &digest, &self.certification) {
+            return Err(Error::invalid(
+                "dir-key-certification",
+                "signature doesn't match the identity key",
+            ));
+        }
+        Ok(())
+    }
+    /// Whether the certificate is no longer valid at `now`
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires < now
+    }
+}
+
+/// The outcome of checking one "directory-signature"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// The signature was made with a certified signing key
+    Valid,
+    /// The certificate was found but the signature doesn't match
+    Invalid,
+    /// None of the certificates has the identity and signing key of the
+    /// signature
+    MissingCertificate,
+    /// The certificate had expired when the consensus became valid
+    ExpiredCertificate,
+    /// The certificate's own signatures don't check out
+    BadCertificate,
+    /// The digest algorithm is neither sha1 nor sha256
+    UnsupportedAlgorithm,
+}
+/// A signature of a consensus along with whether it checked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureCheck {
+    /// Hex fingerprint of the signing authority's identity key
+    pub identity: String,
+    /// Hex digest of the signing key
+    pub signing_key_digest: String,
+    /// The digest algorithm
+    pub algorithm: String,
+    pub status: SignatureStatus,
+}
+impl fmt::Display for SignatureCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            SignatureStatus::Valid => "valid",
+            SignatureStatus::Invalid => "INVALID",
+            SignatureStatus::MissingCertificate => "no certificate",
+            SignatureStatus::ExpiredCertificate => "certificate expired",
+            SignatureStatus::BadCertificate => "certificate invalid",
+            SignatureStatus::UnsupportedAlgorithm => "unsupported algorithm",
+        };
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.identity, self.signing_key_digest, self.algorithm, status
+        )
+    }
+}
+
+impl ConsensusDocument {
+    /// Checks every "directory-signature" against the given key
+    /// certificates. Only documents parsed from their bytes can be checked.
+    pub fn check_signatures(
+        &self,
+        certificates: &[KeyCertificate],
+    ) -> Result<Vec<SignatureCheck>, Error> {
+        let (sha1, sha256) = match (self.sha1_digest, self.sha256_digest) {
+            (Some(sha1), Some(sha256)) => (sha1, sha256),
+            _ => {
+                return Err(Error::invalid(
+                    "directory-signature",
+                    "the signed bytes are unknown",
+                ))
+            }
+        };
+        Ok(self
+            .footer
+            .signatures
+            .iter()
+            .map(|signature| {
+                let certificate = certificates.iter().find(|certificate| {
+                    certificate
+                        .fingerprint
+                        .eq_ignore_ascii_case(&signature.identity)
+                        && certificate
+                            .signing_key_digest()
+                            .eq_ignore_ascii_case(&signature.signing_key_digest)
+                });
+                let digest: &[u8] = match signature.algorithm.as_str() {
+                    "sha1" => &sha1,
+                    "sha256" => &sha256,
+                    _ => &[],
+                };
+                let status = match certificate {
+                    _ if digest.is_empty() => SignatureStatus::UnsupportedAlgorithm,
+                    None => SignatureStatus::MissingCertificate,
+                    Some(certificate) if certificate.verify().is_err() => {
+                        SignatureStatus::BadCertificate
+                    }
+                    Some(certificate) if certificate.is_expired(self.valid_after) => {
+                        SignatureStatus::ExpiredCertificate
+                    }
+                    Some(certificate)
+                        if verify_rsa(&certificate.signing_key, digest, &signature.signature) =>
+                    {
+                        SignatureStatus::Valid
+                    }
+                    Some(_) => SignatureStatus::Invalid,
+                };
+                SignatureCheck {
+                    identity: signature.identity.clone(),
+                    signing_key_digest: signature.signing_key_digest.clone(),
+                    algorithm: signature.algorithm.clone(),
+                    status,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Test
+#[test]
+fn test_check_signatures() {
+    let certificates =
+        KeyCertificate::parse_all(include_bytes!("../test/key-certificate")).unwrap();
+    assert_eq!(certificates.len(), 1);
+    let certificate = &certificates[0];
+    assert!(certificate.verify().is_ok());
+    let time = |timestamp| Timestamp::parse(timestamp).unwrap();
+    assert!(certificate.published < certificate.expires);
+    assert!(!certificate.is_expired(time("2019-01-01 00:00:00")));
+    assert!(certificate.is_expired(time("2019-01-01 00:00:01")));
+    let input = &include_bytes!("../test/signed.consensus")[..];
+    let consensus = ConsensusDocument::parse(input).unwrap();
+    let checks = consensus.check_signatures(&certificates).unwrap();
+    let statuses: Vec<SignatureStatus> = checks.iter().map(|check| check.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            SignatureStatus::MissingCertificate,
+            SignatureStatus::MissingCertificate,
+            SignatureStatus::Valid
+        ]
+    );
+    assert_eq!(
+        checks[2].signing_key_digest,
+        certificate.signing_key_digest()
+    );
+    // Changing what was signed breaks the signature
+    let tampered = String::from_utf8_lossy(input).replace("Bandwidth=46", "Bandwidth=4600");
+    let checks = ConsensusDocument::parse(tampered.as_bytes())
+        .unwrap()
+        .check_signatures(&certificates)
+        .unwrap();
+    assert_eq!(checks[2].status, SignatureStatus::Invalid);
+    // Documents whose bytes aren't known can't be checked at all
+    let mut unknown = consensus.clone();
+    unknown.sha256_digest = None;
+    assert!(unknown.check_signatures(&certificates).is_err());
+    // So does changing the certificate
+    let mut forged = certificate.clone();
+    forged.digest = Some([0; 20]);
+    assert!(forged.verify().is_err());
+    let checks = consensus.check_signatures(&[forged]).unwrap();
+    assert_eq!(checks[2].status, SignatureStatus::BadCertificate);
+    // Certificates whose bytes aren't known, like ones built by hand, can't
+    // be trusted
+    let mut unknown = certificate.clone();
+    unknown.digest = None;
+    assert!(unknown.verify().is_err());
+    let checks = consensus.check_signatures(&[unknown]).unwrap();
+    assert_eq!(checks[2].status, SignatureStatus::BadCertificate);
+}
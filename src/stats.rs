@@ -0,0 +1,234 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use consensus::{ConsensusDocument, Router};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Ports whose exit coverage `NetworkStats` reports: the ones behind
+/// ssh, mail, DNS, the web, IRC and XMPP
+pub const WELL_KNOWN_PORTS: &[u16] = &[
+    22, 25, 53, 80, 110, 143, 194, 443, 465, 587, 993, 995, 5222, 6667, 8080, 8443,
+];
+/// The version key for relays that don't run a recognizable Tor version
+pub const OTHER_VERSION: &str = "other";
+
+/// Aggregate figures for one consensus, the kind that is charted hour by
+/// hour to watch the network.
+///
+/// Weights are consensus weights from the "w" lines, and shares are
+/// fractions of the total weight between 0 and 1.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NetworkStats {
+    /// Number of relays listed
+    pub relays: usize,
+    /// Number of relays with each flag, including known flags that no relay
+    /// has
+    pub flags: BTreeMap<String, usize>,
+    /// Sum of the consensus weights of all relays
+    pub total_weight: u64,
+    /// Weight available in each path position
+    pub positions: PositionWeights,
+    /// Share of the total weight by Tor release series, such as "0.3.2",
+    /// with relays running anything else under `OTHER_VERSION`
+    pub version_share: BTreeMap<String, f64>,
+    /// Number of relays with an IPv6 ORPort
+    pub ipv6_relays: usize,
+    /// Number of relays on each IPv4 ORPort
+    pub or_ports: BTreeMap<u16, usize>,
+    /// For each of the `WELL_KNOWN_PORTS`, the share of exit weight whose
+    /// policy summary allows it. Exits are relays with the Exit flag but
+    /// not the BadExit flag.
+    pub exit_port_coverage: BTreeMap<u16, f64>,
+    /// Number of authorities, without the "-legacy" entries of authorities
+    /// with an old identity key
+    pub authorities: usize,
+    /// Number of authorities that signed the consensus
+    pub signing_authorities: usize,
+}
+/// Consensus weight after applying the "bandwidth-weights" for a position,
+/// counting the relays clients pick from for it: Guard-flagged relays for
+/// guards, every relay for middles and exits for exits. The three add up to
+/// the total weight when the weights are balanced.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PositionWeights {
+    pub guard: f64,
+    pub middle: f64,
+    pub exit: f64,
+}
+
+impl NetworkStats {
+    /// Computes the figures for a consensus
+    pub fn new(consensus: &ConsensusDocument) -> Self {
+        let routers = &consensus.routers;
+        let mut flags: BTreeMap<String, usize> = consensus
+            .known_flags
+            .iter()
+            .map(|flag| (flag.clone(), 0))
+            .collect();
+        let mut version_weight: BTreeMap<String, u64> = BTreeMap::new();
+        let mut or_ports = BTreeMap::new();
+        for router in routers {
+            for flag in &router.flags {
+                *flags.entry(flag.clone()).or_insert(0) += 1;
+            }
+            let series = match router.tor_version() {
+                Some(version) => format!("{}.{}.{}", version.major, version.minor, version.micro),
+                None => OTHER_VERSION.to_string(),
+            };
+            *version_weight.entry(series).or_insert(0) += weight(router);
+            *or_ports.entry(router.or_port).or_insert(0) += 1;
+        }
+        let total_weight: u64 = routers.iter().map(weight).sum();
+        let exits: Vec<&Router> = routers.iter().filter(|router| is_exit(router)).collect();
+        let exit_weight: u64 = exits.iter().map(|router| weight(router)).sum();
+        let signers: BTreeSet<&str> = consensus
+            .footer
+            .signatures
+            .iter()
+            .map(|signature| signature.identity.as_str())
+            .collect();
+        NetworkStats {
+            relays: routers.len(),
+            flags,
+            total_weight,
+            positions: PositionWeights::new(consensus),
+            version_share: version_weight
+                .into_iter()
+                .map(|(series, weight)| (series, share(weight, total_weight)))
+                .collect(),
+            ipv6_relays: routers
+                .iter()
+                .filter(|router| router.addresses.iter().any(|address| address.is_ipv6()))
+                .count(),
+            or_ports,
+            exit_port_coverage: WELL_KNOWN_PORTS
+                .iter()
+                .map(|&port| {
+                    let allowed = exits
+                        .iter()
+                        .filter(|router| router.allows_port(port))
+                        .map(|router| weight(router))
+                        .sum();
+                    (port, share(allowed, exit_weight))
+                })
+                .collect(),
+            authorities: consensus
+                .authorities
+                .iter()
+                .filter(|authority| !authority.nickname.ends_with("-legacy"))
+                .count(),
+            signing_authorities: signers.len(),
+        }
+    }
+}
+impl PositionWeights {
+    /// Applies the consensus's "bandwidth-weights" the way tor does when
+    /// picking relays for a path. Consensuses without them, such as votes,
+    /// have no weight in any position.
+    pub fn new(consensus: &ConsensusDocument) -> Self {
+        let weights = &consensus.footer.bandwidth_weights;
+        let scale = f64::from(consensus.param("bwweightscale").unwrap_or(10000));
+        let factor = |key: &str| {
+            weights
+                .iter()
+                .find(|param| param.key == key)
+                .map_or(0.0, |param| f64::from(param.value) / scale)
+        };
+        // Relays are weighted by whether they are guards ("g"), exits ("e"),
+        // both ("d") or neither ("m")
+        let position = |prefix: char, eligible: &dyn Fn(&Router) -> bool| {
+            consensus
+                .routers
+                .iter()
+                .filter(|router| eligible(router))
+                .map(|router| {
+                    let class = match (router.has_flag("Guard"), is_exit(router)) {
+                        (true, true) => 'd',
+                        (true, false) => 'g',
+                        (false, true) => 'e',
+                        (false, false) => 'm',
+                    };
+                    weight(router) as f64 * factor(&format!("W{}{}", prefix, class))
+                })
+                .sum()
+        };
+        PositionWeights {
+            guard: position('g', &|router| router.has_flag("Guard")),
+            middle: position('m', &|_| true),
+            exit: position('e', &is_exit),
+        }
+    }
+}
+impl ConsensusDocument {
+    /// Computes aggregate figures for the consensus
+    pub fn stats(&self) -> NetworkStats {
+        NetworkStats::new(self)
+    }
+}
+
+/// The relay's consensus weight, or 0 without a "w" line
+fn weight(router: &Router) -> u64 {
+    router
+        .bandwidth
+        .as_ref()
+        .map_or(0, |bandwidth| u64::from(bandwidth.bandwidth))
+}
+/// Whether clients would use the relay as an exit
+fn is_exit(router: &Router) -> bool {
+    router.has_flag("Exit") && !router.has_flag("BadExit")
+}
+/// The part as a fraction of the whole, or 0 for nothing
+fn share(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+/// Test
+#[test]
+fn test_stats() {
+    let consensus = ConsensusDocument::parse(include_bytes!("../test/consensus"))
+        .expect("failed to parse consensus");
+    let stats = consensus.stats();
+    let close = |actual: f64, expected: f64| (actual - expected).abs() < 1e-6;
+    assert_eq!(stats.relays, 6218);
+    assert_eq!(stats.flags["Running"], 6218);
+    assert_eq!(stats.flags["Exit"], 831);
+    assert_eq!(stats.flags["BadExit"], 56);
+    assert_eq!(stats.flags["Guard"], 1731);
+    assert_eq!(stats.flags["NoEdConsensus"], 0);
+    assert_eq!(stats.total_weight, 40773788);
+    assert!(close(stats.positions.guard, 15171621.7536));
+    assert!(close(stats.positions.middle, 15170490.2464));
+    assert!(close(stats.positions.exit, 10431676.0));
+    assert!(close(
+        stats.positions.guard + stats.positions.middle + stats.positions.exit,
+        stats.total_weight as f64
+    ));
+    assert!(close(stats.version_share["0.3.2"], 0.4326852584802766));
+    assert!(close(stats.version_share.values().sum(), 1.0));
+    assert_eq!(stats.ipv6_relays, 648);
+    assert_eq!(stats.or_ports[&9001], 2863);
+    assert_eq!(stats.or_ports[&443], 1756);
+    assert!(close(stats.exit_port_coverage[&443], 0.9992923476534356));
+    assert!(close(stats.exit_port_coverage[&25], 0.026044520554511088));
+    assert_eq!(stats.authorities, 9);
+    assert_eq!(stats.signing_authorities, 9);
+}
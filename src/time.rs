@@ -0,0 +1,150 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::fmt;
+use std::ops::{Add, Sub};
+
+/// A point in time in UTC with one-second resolution, as written in
+/// directory documents in "YYYY-MM-DD HH:MM:SS" form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+impl Timestamp {
+    /// Constructor
+    /// # Parameters
+    /// * `seconds` - seconds since the Unix epoch
+    pub fn from_unix(seconds: u64) -> Self {
+        Timestamp(seconds)
+    }
+    /// Seconds since the Unix epoch
+    pub fn unix(self) -> u64 {
+        self.0
+    }
+    /// Parses "YYYY-MM-DD HH:MM:SS"
+    pub fn parse(timestamp: &str) -> Option<Self> {
+        let bytes = timestamp.as_bytes();
+        if bytes.len() != 19
+            || bytes[4] != b'-'
+            || bytes[7] != b'-'
+            || bytes[10] != b' '
+            || bytes[13] != b':'
+            || bytes[16] != b':'
+        {
+            return None;
+        }
+        let number = |start: usize, end: usize| -> Option<u64> {
+            let digits = &timestamp[start..end];
+            if digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                digits.parse().ok()
+            } else {
+                None
+            }
+        };
+        let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+        let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+        if year < 1970
+            || !(1..=12).contains(&month)
+            || day < 1
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+        Some(Timestamp(
+            days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second,
+        ))
+    }
+}
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0 / 86400);
+        let seconds = self.0 % 86400;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+impl Add<u64> for Timestamp {
+    type Output = Timestamp;
+    fn add(self, seconds: u64) -> Timestamp {
+        Timestamp(self.0 + seconds)
+    }
+}
+impl Sub<u64> for Timestamp {
+    type Output = Timestamp;
+    fn sub(self, seconds: u64) -> Timestamp {
+        Timestamp(self.0.saturating_sub(seconds))
+    }
+}
+
+fn is_leap_year(year: u64) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+/// Days since the epoch for a date in the proleptic Gregorian calendar
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let days_before_year = (1970..year)
+        .map(|year| if is_leap_year(year) { 366 } else { 365 })
+        .sum::<u64>();
+    let days_before_month = (1..month)
+        .map(|month| days_in_month(year, month))
+        .sum::<u64>();
+    days_before_year + days_before_month + day - 1
+}
+/// The date for a number of days since the epoch
+fn civil_from_days(mut days: u64) -> (u64, u64, u64) {
+    let mut year = 1970;
+    loop {
+        let length = if is_leap_year(year) { 366 } else { 365 };
+        if days < length {
+            break;
+        }
+        days -= length;
+        year += 1;
+    }
+    let mut month = 1;
+    while days >= days_in_month(year, month) {
+        days -= days_in_month(year, month);
+        month += 1;
+    }
+    (year, month, days + 1)
+}
+
+/// Test
+#[test]
+fn test_timestamp() {
+    let timestamp = Timestamp::parse("2018-02-08 07:00:00").unwrap();
+    assert_eq!(timestamp.unix(), 1518073200);
+    assert_eq!(timestamp.to_string(), "2018-02-08 07:00:00");
+    assert_eq!((timestamp + 86400 * 21).to_string(), "2018-03-01 07:00:00");
+    assert!(Timestamp::parse("2018-02-29 07:00:00").is_none());
+    assert!(Timestamp::parse("2018-2-08 07:00:00").is_none());
+}
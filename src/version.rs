@@ -0,0 +1,225 @@
+// Copyright 2018 witchof0x20
+/*  This file is part of onyan.
+
+    onyan is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    onyan is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with onyan.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A Tor version as described in version-spec.txt:
+/// MAJOR "." MINOR "." MICRO ["." PATCHLEVEL] ["-" STATUS_TAG] [" (" EXTRA_INFO ")"]*
+///
+/// Versions are ordered the way tor orders them: by number, then by status
+/// tag as a string, then by git tag. A version without a patch level sorts
+/// like patch level 0.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TorVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+    pub patchlevel: Option<u32>,
+    /// Such as "alpha" or "rc", or "alpha-dev" for development builds
+    pub status_tag: Option<String>,
+    /// The lowercase hex commit from a "(git-XXXX)" extra info
+    pub git_tag: Option<String>,
+}
+/// How a version compares to the versions the authorities recommend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    /// The version is listed
+    Recommended,
+    /// The version is older than every recommended version, but its series
+    /// still has recommended versions to upgrade to
+    Old,
+    /// The version is newer than every recommended version
+    New,
+    /// The version is newer than every recommended version in its series,
+    /// though other series have newer ones
+    NewInSeries,
+    /// The version is older than every recommended version and its whole
+    /// series is no longer recommended; tor reports these as old too
+    Obsolete,
+    /// The version falls between recommended versions without being one
+    Unrecommended,
+}
+
+impl TorVersion {
+    /// Parses a version, with or without a "Tor " prefix as in "v" lines
+    /// and "platform" items; trailing words outside parentheses, like the
+    /// " on Linux" of a platform, are skipped
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.strip_prefix("Tor ").unwrap_or(version);
+        let (version, extra) = match version.find(' ') {
+            Some(space) => (&version[..space], &version[space + 1..]),
+            None => (version, ""),
+        };
+        let (numbers, status_tag) = match version.find('-') {
+            Some(dash) => (&version[..dash], Some(&version[dash + 1..])),
+            None => (version, None),
+        };
+        if status_tag == Some("") {
+            return None;
+        }
+        let numbers = numbers
+            .split('.')
+            .map(|number| {
+                if !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()) {
+                    number.parse().ok()
+                } else {
+                    None
+                }
+            })
+            .collect::<Option<Vec<u32>>>()?;
+        if numbers.len() != 3 && numbers.len() != 4 {
+            return None;
+        }
+        let mut git_tag = None;
+        for info in extra.split(' ').filter(|info| !info.is_empty()) {
+            let info = match info
+                .strip_prefix('(')
+                .and_then(|info| info.strip_suffix(')'))
+            {
+                Some(info) => info,
+                None => continue,
+            };
+            if let Some(tag) = info.strip_prefix("git-") {
+                if tag.is_empty()
+                    || tag.len() > 40
+                    || !tag.bytes().all(|byte| byte.is_ascii_hexdigit())
+                {
+                    return None;
+                }
+                git_tag = Some(tag.to_lowercase());
+            }
+        }
+        Some(TorVersion {
+            major: numbers[0],
+            minor: numbers[1],
+            micro: numbers[2],
+            patchlevel: numbers.get(3).cloned(),
+            status_tag: status_tag.map(|tag| tag.to_string()),
+            git_tag,
+        })
+    }
+    /// Whether both versions are in the same MAJOR.MINOR.MICRO series
+    pub fn same_series(&self, other: &TorVersion) -> bool {
+        (self.major, self.minor, self.micro) == (other.major, other.minor, other.micro)
+    }
+    /// How this version compares to a list of recommended versions,
+    /// following tor_version_is_obsolete except that old versions whose
+    /// series has no recommended version are `Obsolete` rather than `Old`,
+    /// and an empty list makes every version `Unrecommended` where tor
+    /// reports it as empty
+    pub fn status(&self, recommended: &[TorVersion]) -> VersionStatus {
+        let (mut found_older, mut found_newer) = (false, false);
+        let (mut found_in_series, mut found_newer_in_series) = (false, false);
+        for other in recommended {
+            let same_series = self.same_series(other);
+            found_in_series |= same_series;
+            match self.cmp(other) {
+                Ordering::Equal => return VersionStatus::Recommended,
+                Ordering::Less => {
+                    found_newer = true;
+                    found_newer_in_series |= same_series;
+                }
+                Ordering::Greater => found_older = true,
+            }
+        }
+        if found_in_series && !found_newer_in_series && found_newer {
+            VersionStatus::NewInSeries
+        } else if found_newer && !found_older && found_in_series {
+            VersionStatus::Old
+        } else if found_newer && !found_older {
+            VersionStatus::Obsolete
+        } else if found_older && !found_newer {
+            VersionStatus::New
+        } else {
+            VersionStatus::Unrecommended
+        }
+    }
+}
+impl Ord for TorVersion {
+    fn cmp(&self, other: &TorVersion) -> Ordering {
+        let key = |version: &TorVersion| {
+            (
+                version.major,
+                version.minor,
+                version.micro,
+                version.patchlevel.unwrap_or(0),
+            )
+        };
+        key(self)
+            .cmp(&key(other))
+            .then_with(|| {
+                let tag = |version: &TorVersion| version.status_tag.clone().unwrap_or_default();
+                tag(self).cmp(&tag(other))
+            })
+            .then_with(|| self.git_tag.cmp(&other.git_tag))
+            .then_with(|| self.patchlevel.is_some().cmp(&other.patchlevel.is_some()))
+    }
+}
+impl PartialOrd for TorVersion {
+    fn partial_cmp(&self, other: &TorVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl fmt::Display for TorVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)?;
+        if let Some(patchlevel) = self.patchlevel {
+            write!(f, ".{}", patchlevel)?;
+        }
+        if let Some(ref status_tag) = self.status_tag {
+            write!(f, "-{}", status_tag)?;
+        }
+        if let Some(ref git_tag) = self.git_tag {
+            write!(f, " (git-{})", git_tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Test
+#[test]
+fn test_tor_version() {
+    let version = |version| TorVersion::parse(version).unwrap();
+    let parsed = version("Tor 0.3.3.1-alpha-dev (git-7f2a0f7B1c5d1d3e)");
+    assert_eq!(parsed.patchlevel, Some(1));
+    assert_eq!(parsed.status_tag.as_deref(), Some("alpha-dev"));
+    assert_eq!(
+        parsed.to_string(),
+        "0.3.3.1-alpha-dev (git-7f2a0f7b1c5d1d3e)"
+    );
+    assert!(version("0.2.9.14") < version("0.2.9.15"));
+    assert!(version("0.3.2.9") < version("0.3.2.10"));
+    assert!(version("0.3.3.1") < version("0.3.3.1-alpha"));
+    assert!(TorVersion::parse("0.3.2").is_some());
+    assert!(TorVersion::parse("0.3.2.9-").is_none());
+    assert!(TorVersion::parse("0.3.x.9").is_none());
+    assert_eq!(version("Tor 0.3.1.9 on Linux"), version("0.3.1.9"));
+    let platform = version("Tor 0.3.2.9 (git-abcd) on Linux");
+    assert_eq!(platform.git_tag.as_deref(), Some("abcd"));
+    let recommended: Vec<TorVersion> = ["0.2.9.14", "0.2.9.15", "0.3.1.9", "0.3.2.9"]
+        .iter()
+        .map(|v| version(v))
+        .collect();
+    let status = |v| version(v).status(&recommended);
+    assert_eq!(status("0.3.1.9"), VersionStatus::Recommended);
+    assert_eq!(status("0.2.9.10"), VersionStatus::Old);
+    assert_eq!(status("0.2.5.16"), VersionStatus::Obsolete);
+    assert_eq!(status("0.3.4.1-alpha"), VersionStatus::New);
+    assert_eq!(status("0.3.1.10"), VersionStatus::NewInSeries);
+    assert_eq!(status("0.3.0.13"), VersionStatus::Unrecommended);
+    assert_eq!(version("0.3.1.9").status(&[]), VersionStatus::Unrecommended);
+}